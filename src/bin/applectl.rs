@@ -1,6 +1,7 @@
 use anyhow::{bail, Result};
+use apple::protocol::{call, parse_switch, Request, PROTOCOL_VERSION};
 use clap::{Parser, Subcommand};
-use serde::Deserialize;
+use std::path::PathBuf;
 
 fn is_insecure_http(s: &str) -> bool {
     s.starts_with("http://")
@@ -15,7 +16,7 @@ fn insecure_allowed() -> bool {
 #[derive(Parser)]
 #[command(name = "applectl")]
 struct Cli {
-    /// Daemon socket (overrides APPLE_DAEMON_SOCKET)
+    /// Daemon socket (overrides APPLE_DAEMON_SOCKET and the default path)
     #[arg(long)]
     socket: Option<String>,
 
@@ -29,22 +30,125 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Play { uri: String },
+    /// Show the daemon's protocol version and capabilities
+    Hello,
+    Play {
+        uri: String,
+    },
     Pause,
-    Enqueue { uri: String },
+    Enqueue {
+        uri: String,
+    },
+    /// Search the daemon's adapter (the local library for mpv), e.g. `artist:radiohead year:>2000`
+    Search {
+        query: Vec<String>,
+    },
+    /// Queue library tracks by the ids printed by `search`
+    EnqueueId {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    /// Play library tracks now, queueing all but the first next
+    PlayIds {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
     Next,
+    /// Replay the previously played item
+    Prev,
     Status,
     List,
-    ArtistInfo { artist_id: String },
-    ArtistDiscography { artist_id: String },
+    /// Show recently played items, most recent first
+    History,
+    /// Set the queue repeat mode: off, one or all
+    Repeat {
+        mode: String,
+    },
+    /// Turn shuffle on or off
+    Shuffle {
+        /// on or off
+        state: String,
+        /// Seed for a reproducible shuffle order
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Remove the queue item at POSITION (1-based, as printed by `list`)
+    Remove {
+        position: usize,
+    },
+    /// Move the queue item at FROM to position TO (1-based)
+    Move {
+        from: usize,
+        to: usize,
+    },
+    Clear,
+    /// Queue an item to play right after the current one
+    InsertNext {
+        uri: String,
+    },
+    /// Skip ahead and play the queue item at POSITION (1-based)
+    PlayIndex {
+        position: usize,
+    },
+    /// Append the entries of an M3U/M3U8, PLS or XSPF playlist to the queue
+    Load {
+        file: PathBuf,
+    },
+    /// Write the queue to a playlist file; the format follows the extension
+    Save {
+        file: PathBuf,
+    },
+    /// Manage named playlists stored by the daemon
+    Playlist {
+        #[command(subcommand)]
+        action: PlaylistAction,
+    },
+    ArtistInfo {
+        artist_id: String,
+    },
+    ArtistDiscography {
+        artist_id: String,
+    },
 }
 
-#[derive(Deserialize)]
-struct Resp {
-    #[allow(dead_code)]
-    ok: bool,
-    msg: String,
-    items: Option<Vec<String>>,
+#[derive(Subcommand)]
+enum PlaylistAction {
+    List,
+    /// Print the items of a playlist
+    Show {
+        name: String,
+    },
+    Create {
+        name: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Delete {
+        name: String,
+    },
+    /// Append URI, or the currently playing track, to a playlist
+    Add {
+        name: String,
+        uri: Option<String>,
+    },
+    /// Replace a playlist with the current queue (creating it if needed)
+    Save {
+        name: String,
+    },
+    /// Append a playlist to the queue
+    Load {
+        name: String,
+    },
+}
+
+// The daemon protocol uses zero-based indices; the command line uses list positions.
+fn queue_index(position: usize) -> Result<usize> {
+    match position.checked_sub(1) {
+        Some(i) => Ok(i),
+        None => bail!("queue positions start at 1"),
+    }
 }
 
 #[tokio::main]
@@ -52,13 +156,31 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     let socket = cli
         .socket
-        .or_else(|| std::env::var("APPLE_DAEMON_SOCKET").ok())
+        .or_else(apple::protocol::daemon_socket)
         .expect("daemon socket required (set APPLE_DAEMON_SOCKET or --socket)");
     let token = cli
         .token
         .or_else(|| std::env::var("APPLE_DAEMON_TOKEN").ok());
 
     match cli.cmd {
+        Commands::Hello => {
+            let request = Request::Hello {
+                version: PROTOCOL_VERSION,
+                client: Some(format!("applectl {}", env!("CARGO_PKG_VERSION"))),
+            };
+            let r = call(&socket, token.as_deref(), request)
+                .await?
+                .into_result()?;
+            match r.hello {
+                Some(h) => {
+                    println!("{} (protocol v{})", h.server, h.version);
+                    println!("auth required: {}", h.auth_required);
+                    println!("capabilities: {}", h.capabilities.join(", "));
+                    println!("commands: {}", h.commands.join(", "));
+                }
+                None => println!("{}", r.msg),
+            }
+        }
         Commands::Play { uri } => {
             if is_insecure_http(&uri) && !insecure_allowed() {
                 bail!(
@@ -66,11 +188,11 @@ async fn main() -> Result<()> {
                      APPLE_ALLOW_INSECURE=1 to allow insecure URLs"
                 );
             }
-            let r = send(&socket, token.as_deref(), "play", Some(&uri)).await?;
+            let r = call(&socket, token.as_deref(), Request::Play { uri }).await?;
             println!("{}", r.msg);
         }
         Commands::Pause => {
-            let r = send(&socket, token.as_deref(), "pause", None).await?;
+            let r = call(&socket, token.as_deref(), Request::Pause).await?;
             println!("{}", r.msg);
         }
         Commands::Enqueue { uri } => {
@@ -80,29 +202,160 @@ async fn main() -> Result<()> {
                      APPLE_ALLOW_INSECURE=1 to allow insecure URLs"
                 );
             }
-            let r = send(&socket, token.as_deref(), "enqueue", Some(&uri)).await?;
+            let r = call(&socket, token.as_deref(), Request::Enqueue { uri }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Search { query } => {
+            let query = query.join(" ");
+            let r = call(&socket, token.as_deref(), Request::Search { query }).await?;
+            match r.tracks {
+                Some(tracks) if !tracks.is_empty() => {
+                    for t in tracks {
+                        println!("{}  {}", t.id, t);
+                    }
+                }
+                _ => println!("{}", r.msg),
+            }
+        }
+        Commands::EnqueueId { ids } => {
+            let r = call(&socket, token.as_deref(), Request::EnqueueId { ids }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::PlayIds { ids } => {
+            let r = call(&socket, token.as_deref(), Request::PlayIds { ids }).await?;
             println!("{}", r.msg);
         }
         Commands::Next => {
-            let r = send(&socket, token.as_deref(), "next", None).await?;
+            let r = call(&socket, token.as_deref(), Request::Next).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Prev => {
+            let r = call(&socket, token.as_deref(), Request::Prev).await?;
+            println!("{}", r.msg);
+        }
+        Commands::History => {
+            let r = call(&socket, token.as_deref(), Request::History).await?;
+            match r.tracks {
+                Some(tracks) if !tracks.is_empty() => {
+                    for (i, t) in tracks.iter().enumerate() {
+                        println!("{}: {}", i + 1, t);
+                    }
+                }
+                _ => println!("no history"),
+            }
+        }
+        Commands::Repeat { mode } => {
+            let mode = mode.parse()?;
+            let r = call(&socket, token.as_deref(), Request::Repeat { mode }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Shuffle { state, seed } => {
+            let Some(on) = parse_switch(&state) else {
+                bail!("expected on or off, got '{}'", state);
+            };
+            let r = call(&socket, token.as_deref(), Request::Shuffle { on, seed }).await?;
             println!("{}", r.msg);
         }
         Commands::Status => {
-            let r = send(&socket, token.as_deref(), "status", None).await?;
+            let r = call(&socket, token.as_deref(), Request::Status).await?;
             println!("{}", r.msg);
         }
         Commands::List => {
-            let r = send(&socket, token.as_deref(), "list", None).await?;
-            if let Some(items) = r.items {
-                for it in items {
-                    println!("- {}", it);
+            let r = call(&socket, token.as_deref(), Request::List).await?;
+            if let Some(tracks) = r.tracks {
+                for (i, t) in tracks.iter().enumerate() {
+                    println!("{}: {}", i + 1, t);
+                }
+            } else if let Some(items) = r.items {
+                for (i, it) in items.iter().enumerate() {
+                    println!("{}: {}", i + 1, it);
                 }
             } else {
                 println!("no items");
             }
         }
+        Commands::Remove { position } => {
+            let index = queue_index(position)?;
+            let r = call(&socket, token.as_deref(), Request::Remove { index }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Move { from, to } => {
+            let (from, to) = (queue_index(from)?, queue_index(to)?);
+            let r = call(&socket, token.as_deref(), Request::Move { from, to }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Clear => {
+            let r = call(&socket, token.as_deref(), Request::Clear).await?;
+            println!("{}", r.msg);
+        }
+        Commands::InsertNext { uri } => {
+            if is_insecure_http(&uri) && !insecure_allowed() {
+                bail!(
+                    "Refusing insecure http URL. Use https:// or set \
+                     APPLE_ALLOW_INSECURE=1 to allow insecure URLs"
+                );
+            }
+            let r = call(&socket, token.as_deref(), Request::InsertNext { uri }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::PlayIndex { position } => {
+            let index = queue_index(position)?;
+            let r = call(&socket, token.as_deref(), Request::PlayIndex { index }).await?;
+            println!("{}", r.msg);
+        }
+        // Playlist files are read and written here, with the caller's permissions; the
+        // daemon only sees the tracks.
+        Commands::Load { file } => {
+            let tracks = apple::playlist::load_playlist(&file, "")?;
+            let r = call(&socket, token.as_deref(), Request::EnqueueTracks { tracks }).await?;
+            println!("{}", r.msg);
+        }
+        Commands::Save { file } => {
+            let r = call(&socket, token.as_deref(), Request::List)
+                .await?
+                .into_result()?;
+            let tracks = r.tracks.unwrap_or_default();
+            apple::playlist::save_playlist(&file, &tracks)?;
+            println!("Saved {} item(s) to {}", tracks.len(), file.display());
+        }
+        Commands::Playlist { action } => {
+            let request = match action {
+                PlaylistAction::List => Request::PlaylistList,
+                PlaylistAction::Show { name } => Request::PlaylistShow { name },
+                PlaylistAction::Create { name } => Request::PlaylistCreate { name },
+                PlaylistAction::Rename { from, to } => Request::PlaylistRename { from, to },
+                PlaylistAction::Delete { name } => Request::PlaylistDelete { name },
+                PlaylistAction::Add { name, uri } => {
+                    if let Some(uri) = &uri {
+                        if is_insecure_http(uri) && !insecure_allowed() {
+                            bail!(
+                                "Refusing insecure http URL. Use https:// or set \
+                                 APPLE_ALLOW_INSECURE=1 to allow insecure URLs"
+                            );
+                        }
+                    }
+                    Request::PlaylistAdd { name, uri }
+                }
+                PlaylistAction::Save { name } => Request::PlaylistSave { name },
+                PlaylistAction::Load { name } => Request::PlaylistLoad { name },
+            };
+            let r = call(&socket, token.as_deref(), request).await?;
+            match (r.tracks, r.items) {
+                (Some(tracks), _) if !tracks.is_empty() => {
+                    for (i, t) in tracks.iter().enumerate() {
+                        println!("{}: {}", i + 1, t);
+                    }
+                }
+                (None, Some(items)) if !items.is_empty() => {
+                    for it in items {
+                        println!("{}", it);
+                    }
+                }
+                _ => println!("{}", r.msg),
+            }
+        }
         Commands::ArtistInfo { artist_id } => {
-            let r = send(&socket, token.as_deref(), "artist_info", Some(&artist_id)).await?;
+            let r = call(&socket, token.as_deref(), Request::ArtistInfo { artist_id }).await?;
             if let Some(items) = r.items {
                 for it in items {
                     println!("{}", it);
@@ -112,11 +365,10 @@ async fn main() -> Result<()> {
             }
         }
         Commands::ArtistDiscography { artist_id } => {
-            let r = send(
+            let r = call(
                 &socket,
                 token.as_deref(),
-                "artist_discography",
-                Some(&artist_id),
+                Request::ArtistDiscography { artist_id },
             )
             .await?;
             if let Some(items) = r.items {
@@ -131,36 +383,3 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
-
-async fn send(socket: &str, token: Option<&str>, cmd: &str, arg: Option<&str>) -> Result<Resp> {
-    #[cfg(unix)]
-    {
-        use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
-        use tokio::net::UnixStream;
-        let stream = UnixStream::connect(socket).await?;
-        let (r, mut w) = split(stream);
-        let mut reader = BufReader::new(r);
-        let payload = serde_json::json!({"cmd": cmd, "arg": arg, "token": token});
-        let msg = payload.to_string() + "\n";
-        w.write_all(msg.as_bytes()).await?;
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
-        let resp: Resp = serde_json::from_str(&line)?;
-        Ok(resp)
-    }
-    #[cfg(not(unix))]
-    {
-        use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
-        use tokio::net::TcpStream;
-        let stream = TcpStream::connect(socket).await?;
-        let (r, mut w) = split(stream);
-        let mut reader = BufReader::new(r);
-        let payload = serde_json::json!({"cmd": cmd, "arg": arg, "token": token});
-        let msg = payload.to_string() + "\n";
-        w.write_all(msg.as_bytes()).await?;
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
-        let resp: Resp = serde_json::from_str(&line)?;
-        Ok(resp)
-    }
-}
@@ -1,10 +1,19 @@
 // Full-featured TUI for apple
 // - Shows status and queue
-// - Supports local (in-process) control or remote control via daemon socket (APPLE_DAEMON_SOCKET)
-// - Keybindings: q=quit, p=pause, SPACE=toggle pause (pause only), n=play next queued item, s=refresh status
-//   a=play immediately (enter input), e=enqueue (enter input), Up/Down navigate queue
+// - Supports local (in-process) control or remote control via daemon socket (APPLE_DAEMON_SOCKET);
+//   remote state is pushed by the daemon (`subscribe`) and only polled while that is unavailable
+// - Keybindings: q=quit, p=pause, SPACE=toggle pause (pause only), n=play next queued item, b=play previous, s=refresh status
+//   r=cycle repeat (off/all/one), z=toggle shuffle
+//   a=play immediately (enter input), e=enqueue (enter input), E=play next (enter input), Up/Down navigate queue
+//   Enter=play selected row, x/Del=remove selected, J/K=move selected down/up, C=clear queue
+//   Tab=switch between queue and playlists; in playlists: Enter=load into queue, N=new, R=rename,
+//   x/Del=delete, w=save queue into it; A (queue)=add selected row to the highlighted playlist
+//   L=library browser (artists or genres -> albums -> tracks): Left/Right=column, Enter=play the
+//   selected artist/album/track, e=enqueue it, g=switch between artists and genres, Esc=close
+//   /=search as you type: Down=move into the results, Enter=play the selected result, e (in the
+//   results)=enqueue it, / or Backspace=back to the query, Esc=close
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crossterm::event::{self, Event as CEvent, KeyCode, KeyEvent};
 use crossterm::execute;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
@@ -16,36 +25,304 @@ use ratatui::{
     widgets::{Block, Borders, Gauge, List, ListItem, Paragraph},
     Terminal,
 };
-use serde::{Deserialize, Serialize};
 use std::io;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use apple::config::{load_config, save_config};
-use apple::player::Player;
+use apple::events::{Event, LiveState};
+use apple::library::{Album, Library};
+use apple::player::{Player, RepeatMode};
+use apple::playlist_store::PlaylistStore;
+use apple::protocol::{call, subscribe, Request};
+use apple::track::Track;
 
-#[derive(Deserialize, Serialize, Debug)]
-struct DaemonResp {
-    ok: bool,
-    msg: String,
-    items: Option<Vec<String>>,
+// Operations on a named playlist; see the daemon's playlist requests.
+enum PlaylistOp {
+    Create,
+    Rename(String),
+    Delete,
+    /// Replace with the queue
+    Save,
+    /// Append to the queue
+    Load,
+    /// Append a uri
+    Add(String),
+}
+
+impl PlaylistOp {
+    fn request(self, name: &str) -> Request {
+        let name = name.to_string();
+        match self {
+            PlaylistOp::Create => Request::PlaylistCreate { name },
+            PlaylistOp::Rename(to) => Request::PlaylistRename { from: name, to },
+            PlaylistOp::Delete => Request::PlaylistDelete { name },
+            PlaylistOp::Save => Request::PlaylistSave { name },
+            PlaylistOp::Load => Request::PlaylistLoad { name },
+            PlaylistOp::Add(uri) => Request::PlaylistAdd {
+                name,
+                uri: Some(uri),
+            },
+        }
+    }
+}
+
+// Library browser over the local index: artists (or genres) -> albums -> tracks.
+#[derive(Default)]
+struct LibraryBrowser {
+    open: bool,
+    by_genre: bool,
+    /// Focused column: 0 artists/genres, 1 albums, 2 tracks
+    column: usize,
+    selected: [usize; 3],
+}
+
+// The rows of the three columns for the current selection.
+struct BrowserView {
+    groups: Vec<String>,
+    /// Label and entry indices; the first row holds every track of the group
+    albums: Vec<(String, Vec<usize>)>,
+    tracks: Vec<usize>,
+}
+
+impl BrowserView {
+    fn len(&self, column: usize) -> usize {
+        match column {
+            0 => self.groups.len(),
+            1 => self.albums.len(),
+            _ => self.tracks.len(),
+        }
+    }
+}
+
+impl LibraryBrowser {
+    fn view(&mut self, lib: &Library) -> BrowserView {
+        let groups = if self.by_genre {
+            lib.genres()
+        } else {
+            lib.artists()
+        };
+        self.selected[0] = self.selected[0].min(groups.len().saturating_sub(1));
+        let mut albums = Vec::new();
+        if let Some(group) = groups.get(self.selected[0]) {
+            let all = if self.by_genre {
+                lib.by_genre(group)
+            } else {
+                lib.by_artist(group)
+            };
+            albums = lib
+                .albums_of(all.iter().copied())
+                .into_iter()
+                .map(|a| (self.album_label(&a), a.tracks))
+                .collect();
+            albums.insert(0, (format!("All tracks ({})", all.len()), all));
+        }
+        self.selected[1] = self.selected[1].min(albums.len().saturating_sub(1));
+        let tracks = albums
+            .get(self.selected[1])
+            .map(|(_, t)| t.clone())
+            .unwrap_or_default();
+        self.selected[2] = self.selected[2].min(tracks.len().saturating_sub(1));
+        BrowserView {
+            groups,
+            albums,
+            tracks,
+        }
+    }
+
+    fn album_label(&self, album: &Album) -> String {
+        let mut label = match album.year {
+            Some(y) => format!("{} ({})", album.title, y),
+            None => album.title.clone(),
+        };
+        // a genre mixes artists
+        if let (true, Some(artist)) = (self.by_genre, &album.artist) {
+            label = format!("{} - {}", label, artist);
+        }
+        label
+    }
+
+    fn up(&mut self) {
+        if self.selected[self.column] > 0 {
+            self.selected[self.column] -= 1;
+            self.reset_below();
+        }
+    }
+
+    fn down(&mut self, view: &BrowserView) {
+        if self.selected[self.column] + 1 < view.len(self.column) {
+            self.selected[self.column] += 1;
+            self.reset_below();
+        }
+    }
+
+    // A new artist or album starts the columns right of it from the top.
+    fn reset_below(&mut self) {
+        for s in &mut self.selected[self.column + 1..] {
+            *s = 0;
+        }
+    }
+
+    /// Entries Enter/e act on: the whole artist or genre, the album, or a single track,
+    /// depending on the focused column.
+    fn chosen(&self, view: &BrowserView) -> Vec<usize> {
+        match self.column {
+            0 => view.albums.first().map(|(_, t)| t.clone()),
+            1 => view.albums.get(self.selected[1]).map(|(_, t)| t.clone()),
+            _ => view.tracks.get(self.selected[2]).map(|i| vec![*i]),
+        }
+        .unwrap_or_default()
+    }
+}
+
+// How long typing must pause before the query is sent.
+const SEARCH_DEBOUNCE: Duration = Duration::from_millis(250);
+
+// Search-as-you-type panel.
+#[derive(Default)]
+struct SearchPanel {
+    open: bool,
+    /// Keys edit the query; otherwise they act on the results
+    typing: bool,
+    query: String,
+    results: Vec<Track>,
+    selected: usize,
+    error: Option<String>,
+    /// When the query last changed without being searched yet
+    edited: Option<Instant>,
+}
+
+impl SearchPanel {
+    fn open(&mut self) {
+        *self = SearchPanel {
+            open: true,
+            typing: true,
+            ..SearchPanel::default()
+        };
+    }
+
+    fn edit(&mut self) {
+        self.edited = Some(Instant::now());
+    }
+
+    // True once typing has paused; the caller then searches and hands back the result.
+    fn due(&self) -> bool {
+        self.edited.is_some_and(|t| t.elapsed() >= SEARCH_DEBOUNCE)
+    }
+
+    fn set_results(&mut self, res: Result<Vec<Track>>) {
+        self.edited = None;
+        self.selected = 0;
+        match res {
+            Ok(tracks) => {
+                self.results = tracks;
+                self.error = None;
+            }
+            Err(e) => {
+                self.results.clear();
+                self.error = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    fn chosen(&self) -> Option<Track> {
+        self.results.get(self.selected).cloned()
+    }
+}
+
+fn browser_track_label(lib: &Library, index: usize) -> String {
+    let e = &lib.entries[index];
+    let title = e.track.title.clone().unwrap_or_else(|| e.track.to_string());
+    match e.track_number {
+        Some(n) => format!("{:>2}. {}", n, title),
+        None => title,
+    }
+}
+
+// Rate of position updates from the daemon.
+const FEED_TICK_MS: u64 = 500;
+// How often to retry `subscribe` while polling instead.
+const FEED_RETRY: Duration = Duration::from_secs(2);
+
+/// Daemon state kept up to date by pushed events.
+struct Feed {
+    events: tokio::sync::mpsc::UnboundedReceiver<Event>,
+    state: LiveState,
+}
+
+impl Feed {
+    async fn start(socket: &str, token: Option<&str>) -> Result<Self> {
+        let mut feed = Feed {
+            events: subscribe(socket, token, FEED_TICK_MS).await?,
+            state: LiveState::default(),
+        };
+        // the full state comes first and ends with the position
+        while let Ok(Some(ev)) =
+            tokio::time::timeout(Duration::from_secs(1), feed.events.recv()).await
+        {
+            let last = matches!(ev, Event::Position { .. });
+            feed.state.apply(ev);
+            if last {
+                break;
+            }
+        }
+        Ok(feed)
+    }
+
+    /// Apply what arrived since the last frame; false once the connection is gone.
+    fn drain(&mut self) -> bool {
+        loop {
+            match self.events.try_recv() {
+                Ok(ev) => self.state.apply(ev),
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => return true,
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
 }
 
 enum Controller {
     Local {
-        player: Player,
+        player: Box<Player>,
     },
     Remote {
         socket: String,
         token: Option<String>,
+        feed: Option<Box<Feed>>,
+        retry_at: Instant,
     },
 }
 
 impl Controller {
+    // Catch up with the daemon's pushed state, subscribing (again) if needed.
+    async fn sync(&mut self) {
+        let Controller::Remote {
+            socket,
+            token,
+            feed,
+            retry_at,
+        } = self
+        else {
+            return;
+        };
+        if feed.is_none() && Instant::now() >= *retry_at {
+            *retry_at = Instant::now() + FEED_RETRY;
+            *feed = Feed::start(socket, token.as_deref())
+                .await
+                .ok()
+                .map(Box::new);
+        }
+        if feed.as_mut().is_some_and(|f| !f.drain()) {
+            *feed = None;
+        }
+    }
+
     async fn status(&mut self) -> Result<String> {
         match self {
-            Controller::Local { player } => player.adapter_mut().status().await,
-            Controller::Remote { socket, token } => {
-                let resp = send_daemon_cmd(socket, token.as_deref(), "status", None).await?;
+            Controller::Local { player } => player.status().await.map(|s| s.to_string()),
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.status.to_string()),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::Status).await?;
                 Ok(resp.msg)
             }
         }
@@ -54,9 +331,10 @@ impl Controller {
     async fn get_position(&mut self) -> Result<u64> {
         match self {
             Controller::Local { player } => player.adapter_mut().get_position().await,
-            Controller::Remote { socket, token } => {
-                let resp = send_daemon_cmd(socket, token.as_deref(), "position", None).await?;
-                resp.msg.parse().unwrap_or(Ok(0))
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.status.position.unwrap_or(0)),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::Position).await?;
+                Ok(resp.msg.parse().unwrap_or(0))
             }
         }
     }
@@ -64,9 +342,10 @@ impl Controller {
     async fn get_duration(&mut self) -> Result<u64> {
         match self {
             Controller::Local { player } => player.adapter_mut().get_duration().await,
-            Controller::Remote { socket, token } => {
-                let resp = send_daemon_cmd(socket, token.as_deref(), "duration", None).await?;
-                resp.msg.parse().unwrap_or(Ok(0))
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.status.duration.unwrap_or(0)),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::Duration).await?;
+                Ok(resp.msg.parse().unwrap_or(0))
             }
         }
     }
@@ -74,8 +353,8 @@ impl Controller {
     async fn volume_up(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => player.adapter_mut().volume_up().await,
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "volume_up", None).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::VolumeUp).await?;
                 Ok(())
             }
         }
@@ -84,8 +363,8 @@ impl Controller {
     async fn volume_down(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => player.adapter_mut().volume_down().await,
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "volume_down", None).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::VolumeDown).await?;
                 Ok(())
             }
         }
@@ -94,9 +373,13 @@ impl Controller {
     async fn seek_forward(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => player.adapter_mut().seek_forward(10).await,
-            Controller::Remote { socket, token } => {
-                let _ =
-                    send_daemon_cmd(socket, token.as_deref(), "seek_forward", Some("10")).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(
+                    socket,
+                    token.as_deref(),
+                    Request::SeekForward { seconds: 10 },
+                )
+                .await?;
                 Ok(())
             }
         }
@@ -105,9 +388,13 @@ impl Controller {
     async fn seek_backward(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => player.adapter_mut().seek_backward(10).await,
-            Controller::Remote { socket, token } => {
-                let _ =
-                    send_daemon_cmd(socket, token.as_deref(), "seek_backward", Some("10")).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(
+                    socket,
+                    token.as_deref(),
+                    Request::SeekBackward { seconds: 10 },
+                )
+                .await?;
                 Ok(())
             }
         }
@@ -116,8 +403,8 @@ impl Controller {
     async fn pause(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => player.adapter_mut().pause().await,
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "pause", None).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Pause).await?;
                 Ok(())
             }
         }
@@ -126,8 +413,8 @@ impl Controller {
     async fn play_item(&mut self, item: &str) -> Result<()> {
         match self {
             Controller::Local { player } => player.play_item(item).await,
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "play", Some(item)).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Play { uri: item.into() }).await?;
                 Ok(())
             }
         }
@@ -136,47 +423,318 @@ impl Controller {
     async fn enqueue(&mut self, item: &str) -> Result<()> {
         match self {
             Controller::Local { player } => {
-                player.enqueue(item.to_string());
+                let track = player.track_for(item);
+                player.enqueue(track);
                 Ok(())
             }
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "enqueue", Some(item)).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(
+                    socket,
+                    token.as_deref(),
+                    Request::Enqueue { uri: item.into() },
+                )
+                .await?;
                 Ok(())
             }
         }
     }
 
+    // Library tracks are sent to the daemon by id so it keeps their tags.
+    async fn enqueue_tracks(&mut self, tracks: Vec<Track>) -> Result<String> {
+        match self {
+            Controller::Local { player } => {
+                let n = tracks.len();
+                for t in tracks {
+                    player.enqueue(t);
+                }
+                Ok(format!("queued {} track(s)", n))
+            }
+            Controller::Remote { socket, token, .. } => {
+                let ids = tracks.into_iter().map(|t| t.id).collect();
+                let resp = call(socket, token.as_deref(), Request::EnqueueId { ids }).await?;
+                Ok(resp.msg)
+            }
+        }
+    }
+
+    // Play the first track now with the rest queued right after it.
+    async fn play_tracks(&mut self, tracks: Vec<Track>) -> Result<String> {
+        match self {
+            Controller::Local { player } => {
+                let n = tracks.len();
+                player.play_tracks(tracks).await?;
+                Ok(format!("playing {} track(s)", n))
+            }
+            Controller::Remote { socket, token, .. } => {
+                let ids = tracks.into_iter().map(|t| t.id).collect();
+                let resp = call(socket, token.as_deref(), Request::PlayIds { ids }).await?;
+                Ok(resp.msg)
+            }
+        }
+    }
+
+    async fn search(&mut self, query: &str) -> Result<Vec<Track>> {
+        match self {
+            Controller::Local { player } => player.adapter_mut().search_tracks(query).await,
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(
+                    socket,
+                    token.as_deref(),
+                    Request::Search {
+                        query: query.into(),
+                    },
+                )
+                .await?;
+                // adapters without structured search only answer with text
+                resp.tracks.ok_or_else(|| anyhow!(resp.msg))
+            }
+        }
+    }
+
     async fn next_and_play(&mut self) -> Result<()> {
         match self {
             Controller::Local { player } => {
                 if let Some(it) = player.next_item() {
-                    player.play_item(&it).await?;
+                    player.play_track(it.clone()).await?;
+                }
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Next).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn insert_next(&mut self, item: &str) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                let track = player.track_for(item);
+                player.insert_next(track);
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(
+                    socket,
+                    token.as_deref(),
+                    Request::InsertNext { uri: item.into() },
+                )
+                .await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn remove(&mut self, index: usize) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                player.remove(index);
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Remove { index }).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn move_item(&mut self, from: usize, to: usize) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                player.move_item(from, to);
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Move { from, to }).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn clear_queue(&mut self) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                player.clear();
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Clear).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn play_index(&mut self, index: usize) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                if let Some(it) = player.jump_to(index) {
+                    player.play_track(it).await?;
                 }
                 Ok(())
             }
-            Controller::Remote { socket, token } => {
-                let _ = send_daemon_cmd(socket, token.as_deref(), "next", None).await?;
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::PlayIndex { index }).await?;
                 Ok(())
             }
         }
     }
 
-    async fn list_queue(&mut self) -> Result<Vec<String>> {
+    async fn set_repeat(&mut self, mode: RepeatMode) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                player.set_repeat(mode);
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Repeat { mode }).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn set_shuffle(&mut self, on: bool) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                player.set_shuffle(on, None);
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let request = Request::Shuffle { on, seed: None };
+                let _ = call(socket, token.as_deref(), request).await?;
+                Ok(())
+            }
+        }
+    }
+
+    // Current repeat/shuffle settings, for the indicator
+    async fn modes(&mut self) -> Result<(RepeatMode, bool)> {
+        match self {
+            Controller::Local { player } => Ok((player.repeat(), player.shuffle())),
+            Controller::Remote { feed: Some(f), .. } => {
+                Ok((f.state.status.repeat, f.state.status.shuffle))
+            }
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::Status).await?;
+                Ok(resp
+                    .status
+                    .map(|s| (s.repeat, s.shuffle))
+                    .unwrap_or_default())
+            }
+        }
+    }
+
+    async fn prev(&mut self) -> Result<()> {
+        match self {
+            Controller::Local { player } => {
+                if player.previous().await?.is_none() {
+                    player.adapter_mut().prev().await?;
+                }
+                Ok(())
+            }
+            Controller::Remote { socket, token, .. } => {
+                let _ = call(socket, token.as_deref(), Request::Prev).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn history(&mut self) -> Result<Vec<Track>> {
+        match self {
+            Controller::Local { player } => Ok(player.history()),
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.history.clone()),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::History).await?;
+                Ok(resp.tracks.unwrap_or_default())
+            }
+        }
+    }
+
+    async fn list_queue(&mut self) -> Result<Vec<Track>> {
         match self {
             Controller::Local { player } => Ok(player.list()),
-            Controller::Remote { socket, token } => {
-                let resp = send_daemon_cmd(socket, token.as_deref(), "list", None).await?;
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.queue.clone()),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::List).await?;
+                // older daemons only send plain uris
+                Ok(resp.tracks.unwrap_or_else(|| {
+                    resp.items
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|uri| Track::from_uri(uri, "daemon"))
+                        .collect()
+                }))
+            }
+        }
+    }
+
+    async fn playlists(&mut self) -> Result<Vec<String>> {
+        match self {
+            Controller::Local { .. } => PlaylistStore::open_default().names(),
+            Controller::Remote { feed: Some(f), .. } => Ok(f.state.playlists.clone()),
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), Request::PlaylistList).await?;
                 Ok(resp.items.unwrap_or_default())
             }
         }
     }
 
+    // Returns the message for the status line.
+    async fn playlist(&mut self, name: &str, op: PlaylistOp) -> Result<String> {
+        match self {
+            Controller::Local { player } => {
+                let store = PlaylistStore::open_default();
+                let msg = match op {
+                    PlaylistOp::Create => {
+                        store.create(name)?;
+                        format!("created {}", name)
+                    }
+                    PlaylistOp::Rename(to) => {
+                        store.rename(name, &to)?;
+                        format!("renamed {} to {}", name, to)
+                    }
+                    PlaylistOp::Delete => {
+                        store.delete(name)?;
+                        format!("deleted {}", name)
+                    }
+                    PlaylistOp::Save => {
+                        let tracks = player.list();
+                        store.save(name, &tracks)?;
+                        format!("saved {} item(s) to {}", tracks.len(), name)
+                    }
+                    PlaylistOp::Load => {
+                        let tracks = store.tracks(name)?;
+                        let n = tracks.len();
+                        for t in tracks {
+                            player.enqueue(t);
+                        }
+                        format!("queued {} item(s) from {}", n, name)
+                    }
+                    PlaylistOp::Add(uri) => {
+                        let n = store.append(name, &[player.track_for(&uri)])?;
+                        format!("added to {} ({} items)", name, n)
+                    }
+                };
+                Ok(msg)
+            }
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(socket, token.as_deref(), op.request(name)).await?;
+                Ok(resp.into_result()?.msg)
+            }
+        }
+    }
+
     async fn artist_info(&mut self, id: &str) -> Result<String> {
         match self {
             Controller::Local { player } => player.adapter_mut().artist_info(id).await,
-            Controller::Remote { socket, token } => {
-                let resp =
-                    send_daemon_cmd(socket, token.as_deref(), "artist_info", Some(id)).await?;
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(
+                    socket,
+                    token.as_deref(),
+                    Request::ArtistInfo {
+                        artist_id: id.into(),
+                    },
+                )
+                .await?;
                 if let Some(items) = resp.items {
                     Ok(items.join("\n"))
                 } else {
@@ -189,10 +747,15 @@ impl Controller {
     async fn artist_discography(&mut self, id: &str) -> Result<String> {
         match self {
             Controller::Local { player } => player.adapter_mut().artist_discography(id).await,
-            Controller::Remote { socket, token } => {
-                let resp =
-                    send_daemon_cmd(socket, token.as_deref(), "artist_discography", Some(id))
-                        .await?;
+            Controller::Remote { socket, token, .. } => {
+                let resp = call(
+                    socket,
+                    token.as_deref(),
+                    Request::ArtistDiscography {
+                        artist_id: id.into(),
+                    },
+                )
+                .await?;
                 if let Some(items) = resp.items {
                     Ok(items.join("\n"))
                 } else {
@@ -203,44 +766,6 @@ impl Controller {
     }
 }
 
-async fn send_daemon_cmd(
-    socket: &str,
-    token: Option<&str>,
-    cmd: &str,
-    arg: Option<&str>,
-) -> Result<DaemonResp> {
-    #[cfg(unix)]
-    {
-        use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
-        use tokio::net::UnixStream;
-        let stream = UnixStream::connect(socket).await?;
-        let (r, mut w) = split(stream);
-        let mut reader = BufReader::new(r);
-        let payload = serde_json::json!({"cmd": cmd, "arg": arg, "token": token});
-        let msg = payload.to_string() + "\n";
-        w.write_all(msg.as_bytes()).await?;
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
-        let resp: DaemonResp = serde_json::from_str(&line)?;
-        Ok(resp)
-    }
-    #[cfg(not(unix))]
-    {
-        use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
-        use tokio::net::TcpStream;
-        let stream = TcpStream::connect(socket).await?;
-        let (r, mut w) = split(stream);
-        let mut reader = BufReader::new(r);
-        let payload = serde_json::json!({"cmd": cmd, "arg": arg, "token": token});
-        let msg = payload.to_string() + "\n";
-        w.write_all(msg.as_bytes()).await?;
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
-        let resp: DaemonResp = serde_json::from_str(&line)?;
-        Ok(resp)
-    }
-}
-
 fn is_insecure_http(s: &str) -> bool {
     s.starts_with("http://")
 }
@@ -311,6 +836,101 @@ impl Theme {
     }
 }
 
+// The browser's three columns, or why the index could not be loaded.
+fn render_browser<B: ratatui::backend::Backend>(
+    f: &mut ratatui::Frame<B>,
+    area: ratatui::layout::Rect,
+    browser: &LibraryBrowser,
+    browsed: &Result<(Arc<Library>, BrowserView)>,
+    theme: Theme,
+) {
+    let (lib, view) = match browsed {
+        Ok((lib, view)) => (lib, view),
+        Err(e) => {
+            let p = Paragraph::new(format!("{:#}", e))
+                .block(Block::default().borders(Borders::ALL).title("Library"));
+            f.render_widget(p, area);
+            return;
+        }
+    };
+    let panes = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage(25),
+            Constraint::Percentage(35),
+            Constraint::Percentage(40),
+        ])
+        .split(area);
+    let groups_title = if browser.by_genre {
+        "Genres (g: artists)"
+    } else {
+        "Artists (g: genres)"
+    };
+    let columns = [
+        (groups_title, view.groups.clone()),
+        (
+            "Albums",
+            view.albums.iter().map(|(l, _)| l.clone()).collect(),
+        ),
+        (
+            "Tracks",
+            view.tracks
+                .iter()
+                .map(|i| browser_track_label(lib, *i))
+                .collect(),
+        ),
+    ];
+    for (col, (title, rows)) in columns.into_iter().enumerate() {
+        let mut state = ratatui::widgets::ListState::default();
+        state.select((!rows.is_empty()).then_some(browser.selected[col]));
+        let highlight = if col == browser.column {
+            theme.list_highlight()
+        } else {
+            Style::default().add_modifier(Modifier::BOLD)
+        };
+        let rows: Vec<ListItem> = rows.into_iter().map(ListItem::new).collect();
+        let list = List::new(rows)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(highlight);
+        f.render_stateful_widget(list, panes[col], &mut state);
+    }
+}
+
+fn render_search_results<B: ratatui::backend::Backend>(
+    f: &mut ratatui::Frame<B>,
+    area: ratatui::layout::Rect,
+    search: &SearchPanel,
+    state: &mut ratatui::widgets::ListState,
+    theme: Theme,
+) {
+    if let Some(err) = &search.error {
+        let p = Paragraph::new(err.as_str())
+            .block(Block::default().borders(Borders::ALL).title("Search"));
+        f.render_widget(p, area);
+        return;
+    }
+    let rows: Vec<ListItem> = search
+        .results
+        .iter()
+        .map(|t| match &t.album {
+            Some(album) => ListItem::new(format!("{}  [{}]", t, album)),
+            None => ListItem::new(t.to_string()),
+        })
+        .collect();
+    let title = if search.edited.is_some() {
+        "Search (searching...)".to_string()
+    } else {
+        format!("Search ({} results)", search.results.len())
+    };
+    let list = List::new(rows)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(theme.list_highlight());
+    f.render_stateful_widget(list, area, state);
+}
+
+// Number of entries in the preferences popup
+const PREFS_COUNT: usize = 1;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     enable_raw_mode()?;
@@ -325,24 +945,35 @@ async fn main() -> Result<()> {
         Controller::Remote {
             socket: sock,
             token,
+            feed: None,
+            retry_at: Instant::now(),
         }
     } else {
         let adapter = apple::playback::get_adapter().await?;
-        let player = Player::new(adapter);
+        let player = Box::new(Player::new(adapter));
         Controller::Local { player }
     };
 
     // UI state
+    controller.sync().await;
     let mut last_status = controller
         .status()
         .await
         .unwrap_or_else(|_| "unknown".into());
     let mut last_refresh = Instant::now();
+    let (mut repeat, mut shuffle) = controller.modes().await.unwrap_or_default();
     let mut selected: usize = 0;
     let mut mode_input = false;
     let mut input_buf = String::new();
     let mut input_enqueue = false;
+    let mut input_insert_next = false;
     let mut pending_artist_action: Option<&str> = None;
+    // "create" or "rename" while typing a playlist name
+    let mut pending_playlist_action: Option<&str> = None;
+    let mut playlists_focus = false;
+    let mut playlist_selected: usize = 0;
+    let mut browser = LibraryBrowser::default();
+    let mut search = SearchPanel::default();
 
     let mut modal_open = false;
     let mut modal_lines: Vec<String> = Vec::new();
@@ -362,16 +993,53 @@ async fn main() -> Result<()> {
     }
 
     let mut list_state = ratatui::widgets::ListState::default();
+    let mut playlist_state = ratatui::widgets::ListState::default();
     let tick_rate = Duration::from_millis(100);
 
     loop {
+        controller.sync().await;
         let queue = controller.list_queue().await.unwrap_or_default();
         if queue.is_empty() {
             selected = 0;
         } else if selected >= queue.len() {
             selected = queue.len() - 1;
         }
-        list_state.select(if queue.is_empty() { None } else { Some(selected) });
+        list_state.select(if queue.is_empty() {
+            None
+        } else {
+            Some(selected)
+        });
+
+        let history = controller.history().await.unwrap_or_default();
+
+        let playlists = controller.playlists().await.unwrap_or_default();
+        if playlist_selected >= playlists.len() {
+            playlist_selected = playlists.len().saturating_sub(1);
+        }
+        playlist_state.select(if playlists_focus && !playlists.is_empty() {
+            Some(playlist_selected)
+        } else {
+            None
+        });
+
+        // the browser reads the same index the daemon searches; reloaded only when it changes
+        let browsed = browser.open.then(|| {
+            apple::library::load_cached().map(|lib| {
+                let view = browser.view(&lib);
+                (lib, view)
+            })
+        });
+
+        if search.due() {
+            if search.query.trim().is_empty() {
+                search.set_results(Ok(Vec::new()));
+            } else {
+                let res = controller.search(&search.query).await;
+                search.set_results(res);
+            }
+        }
+        let mut search_state = ratatui::widgets::ListState::default();
+        search_state.select((!search.results.is_empty()).then_some(search.selected));
 
         // Get position and duration outside of draw to avoid async issues
         let position = controller.get_position().await.unwrap_or(0);
@@ -391,7 +1059,7 @@ async fn main() -> Result<()> {
                 .split(size);
 
             let header = Paragraph::new(format!(
-                "Apple TUI - q:quit p:pause SPACE:pause n:next s:status a:play e:enqueue t:theme +/-:volume ←/→:seek - last: {}",
+                "Apple TUI - q:quit p:pause SPACE:pause n:next b:prev r:repeat z:shuffle s:status a:play e:enqueue t:theme +/-:volume ←/→:seek - last: {}",
                 last_status
             ))
                 .style(theme.header_style())
@@ -404,33 +1072,93 @@ async fn main() -> Result<()> {
                 format_time(position),
                 if duration > 0 { format_time(duration) } else { "--:--".to_string() }
             );
-            let progress_gauge = ratatui::widgets::Gauge::default()
-                .block(Block::default().borders(Borders::ALL).title("Progress"))
+            let progress_gauge = Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title(format!(
+                    "Progress [repeat: {}]{}",
+                    repeat,
+                    if shuffle { " [shuffle]" } else { "" }
+                )))
                 .gauge_style(theme.list_highlight())
                 .percent((progress * 100.0) as u16)
                 .label(progress_text);
             f.render_widget(progress_gauge, chunks[1]);
 
-            let items: Vec<ListItem> = queue.iter().map(|it| ListItem::new(it.clone())).collect();
+            let items: Vec<ListItem> = queue
+                .iter()
+                .map(|t| match t.duration {
+                    Some(d) => ListItem::new(format!("{}  ({})", t, format_time(d))),
+                    None => ListItem::new(t.to_string()),
+                })
+                .collect();
             let list = List::new(items)
                 .block(Block::default().borders(Borders::ALL).title("Queue"))
                 .highlight_style(theme.list_highlight());
-            f.render_stateful_widget(list, chunks[2], &mut list_state);
+            let panes = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([
+                    Constraint::Percentage(50),
+                    Constraint::Percentage(25),
+                    Constraint::Percentage(25),
+                ])
+                .split(chunks[2]);
 
-            if mode_input {
-                let prompt = if input_enqueue { "Enqueue: " } else { "Play: " };
+            if let Some(browsed) = &browsed {
+                render_browser(f, chunks[2], &browser, browsed, theme);
+            } else if search.open {
+                render_search_results(f, chunks[2], &search, &mut search_state, theme);
+            } else {
+                f.render_stateful_widget(list, panes[0], &mut list_state);
+
+                let played: Vec<ListItem> = history.iter().map(|t| ListItem::new(t.to_string())).collect();
+                let played = List::new(played)
+                    .block(Block::default().borders(Borders::ALL).title("History (b: previous)"));
+                f.render_widget(played, panes[1]);
+
+                let names: Vec<ListItem> = playlists.iter().map(|n| ListItem::new(n.as_str())).collect();
+                let names = List::new(names)
+                    .block(Block::default().borders(Borders::ALL).title(if playlists_focus {
+                        "Playlists (Enter:load N:new R:rename x:delete w:save queue)"
+                    } else {
+                        "Playlists (Tab)"
+                    }))
+                    .highlight_style(theme.list_highlight());
+                f.render_stateful_widget(names, panes[2], &mut playlist_state);
+            }
+
+            if search.open {
+                let p = Paragraph::new(format!("Search: {}", search.query))
+                    .block(Block::default().borders(Borders::ALL).title(if search.typing {
+                        "Type to search (Down: results, Enter: play, Esc: close)"
+                    } else {
+                        "Results (Enter: play, e: enqueue, /: edit query, Esc: close)"
+                    }));
+                f.render_widget(p, chunks[3]);
+            } else if mode_input {
+                let prompt = if pending_playlist_action == Some("create") {
+                    "New playlist: "
+                } else if pending_playlist_action == Some("rename") {
+                    "Rename playlist to: "
+                } else if input_insert_next {
+                    "Play next: "
+                } else if input_enqueue {
+                    "Enqueue: "
+                } else {
+                    "Play: "
+                };
                 let p = Paragraph::new(format!("{}{}", prompt, input_buf))
                     .block(Block::default().borders(Borders::ALL).title(
                         "Input (Enter to submit, Esc to cancel)"
                     ));
-                f.render_widget(p, chunks[2]);
+                f.render_widget(p, chunks[3]);
             } else {
-                let help = Paragraph::new(
-                    "Navigation: Up/Down to move, e:enqueue, a:play, i:artist info, d:discography, T:preferences, t:theme toggle"
-                )
+                let help = Paragraph::new(if browser.open {
+                    "Up/Down:select ←/→:column Enter:play artist/album/track e:enqueue it g:artists/genres Esc/L:close"
+                } else {
+                    "Up/Down:select Enter:play selected x:remove J/K:move down/up C:clear e:enqueue E:play next a:play A:add to playlist Tab:playlists L:library /:search i:artist info d:discography T:preferences t:theme"
+                })
                     .style(theme.help_style())
                     .block(Block::default().borders(Borders::ALL).title("Help"));
-                f.render_widget(help, chunks[2]);
+                f.render_widget(help, chunks[3]);
             }
 
             if modal_open {
@@ -495,7 +1223,24 @@ async fn main() -> Result<()> {
                             input_buf.pop();
                         }
                         KeyCode::Enter => {
-                            if let Some(action) = pending_artist_action {
+                            if let Some(action) = pending_playlist_action {
+                                let res = if action == "create" {
+                                    controller.playlist(&input_buf, PlaylistOp::Create).await
+                                } else {
+                                    match playlists.get(playlist_selected) {
+                                        Some(name) => {
+                                            controller
+                                                .playlist(
+                                                    name,
+                                                    PlaylistOp::Rename(input_buf.clone()),
+                                                )
+                                                .await
+                                        }
+                                        None => Err(anyhow!("no playlist selected")),
+                                    }
+                                };
+                                last_status = res.unwrap_or_else(|e| e.to_string());
+                            } else if let Some(action) = pending_artist_action {
                                 if action == "info" {
                                     match controller.artist_info(&input_buf).await {
                                         Ok(info) => {
@@ -527,6 +1272,8 @@ async fn main() -> Result<()> {
                             } else if is_insecure_http(&input_buf) && !insecure_allowed() {
                                 last_status = "Refused insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow"
                                     .into();
+                            } else if input_insert_next {
+                                let _ = controller.insert_next(&input_buf).await;
                             } else if input_enqueue {
                                 let _ = controller.enqueue(&input_buf).await;
                             } else {
@@ -534,11 +1281,15 @@ async fn main() -> Result<()> {
                             }
                             input_buf.clear();
                             mode_input = false;
+                            input_insert_next = false;
                             pending_artist_action = None;
+                            pending_playlist_action = None;
                         }
                         KeyCode::Esc => {
                             input_buf.clear();
                             mode_input = false;
+                            input_insert_next = false;
+                            pending_playlist_action = None;
                         }
                         _ => {}
                     }
@@ -547,10 +1298,8 @@ async fn main() -> Result<()> {
                         KeyCode::Up => {
                             modal_scroll = modal_scroll.saturating_sub(1);
                         }
-                        KeyCode::Down => {
-                            if modal_scroll + 1 < modal_lines.len() {
-                                modal_scroll += 1;
-                            }
+                        KeyCode::Down if modal_scroll + 1 < modal_lines.len() => {
+                            modal_scroll += 1;
                         }
                         KeyCode::PageUp => {
                             modal_scroll = modal_scroll.saturating_sub(10);
@@ -572,8 +1321,8 @@ async fn main() -> Result<()> {
                         KeyCode::Up => {
                             prefs_selected = prefs_selected.saturating_sub(1);
                         }
-                        KeyCode::Down => {
-                            prefs_selected = prefs_selected.saturating_add(1).min(0);
+                        KeyCode::Down if prefs_selected + 1 < PREFS_COUNT => {
+                            prefs_selected += 1;
                         }
                         KeyCode::Enter => {
                             theme = theme.next();
@@ -589,8 +1338,143 @@ async fn main() -> Result<()> {
                         }
                         _ => {}
                     }
+                } else if search.open {
+                    let chosen = search.chosen();
+                    match code {
+                        KeyCode::Esc => search.open = false,
+                        KeyCode::Up => search.selected = search.selected.saturating_sub(1),
+                        KeyCode::Down => {
+                            search.typing = false;
+                            if search.selected + 1 < search.results.len() {
+                                search.selected += 1;
+                            }
+                        }
+                        KeyCode::Enter => {
+                            if let Some(t) = chosen {
+                                last_status = controller
+                                    .play_tracks(vec![t])
+                                    .await
+                                    .unwrap_or_else(|e| e.to_string());
+                                search.open = false;
+                            }
+                        }
+                        KeyCode::Char(c) if search.typing => {
+                            search.query.push(c);
+                            search.edit();
+                        }
+                        KeyCode::Backspace if search.typing => {
+                            search.query.pop();
+                            search.edit();
+                        }
+                        KeyCode::Char('e') => {
+                            if let Some(t) = chosen {
+                                last_status = controller
+                                    .enqueue_tracks(vec![t])
+                                    .await
+                                    .unwrap_or_else(|e| e.to_string());
+                            }
+                        }
+                        KeyCode::Char('/') | KeyCode::Backspace => search.typing = true,
+                        _ => {}
+                    }
+                } else if browser.open && code != KeyCode::Char('q') {
+                    let chosen: Vec<Track> = match &browsed {
+                        Some(Ok((lib, view))) => browser
+                            .chosen(view)
+                            .into_iter()
+                            .map(|i| lib.entries[i].track.clone())
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    match code {
+                        KeyCode::Up => browser.up(),
+                        KeyCode::Down => {
+                            if let Some(Ok((_, view))) = &browsed {
+                                browser.down(view);
+                            }
+                        }
+                        KeyCode::Left => browser.column = browser.column.saturating_sub(1),
+                        KeyCode::Right => browser.column = (browser.column + 1).min(2),
+                        KeyCode::Char('g') => {
+                            browser.by_genre = !browser.by_genre;
+                            browser.column = 0;
+                            browser.selected = [0; 3];
+                        }
+                        KeyCode::Enter if !chosen.is_empty() => {
+                            last_status = controller
+                                .play_tracks(chosen)
+                                .await
+                                .unwrap_or_else(|e| e.to_string());
+                        }
+                        KeyCode::Char('e') if !chosen.is_empty() => {
+                            last_status = controller
+                                .enqueue_tracks(chosen)
+                                .await
+                                .unwrap_or_else(|e| e.to_string());
+                        }
+                        KeyCode::Esc | KeyCode::Char('L') => browser.open = false,
+                        _ => {}
+                    }
+                } else if playlists_focus && !matches!(code, KeyCode::Tab | KeyCode::Char('q')) {
+                    let name = playlists.get(playlist_selected).cloned();
+                    let op = match code {
+                        KeyCode::Up => {
+                            playlist_selected = playlist_selected.saturating_sub(1);
+                            None
+                        }
+                        KeyCode::Down if playlist_selected + 1 < playlists.len() => {
+                            playlist_selected += 1;
+                            None
+                        }
+                        KeyCode::Char('N') => {
+                            mode_input = true;
+                            pending_playlist_action = Some("create");
+                            input_buf.clear();
+                            None
+                        }
+                        KeyCode::Char('R') if name.is_some() => {
+                            mode_input = true;
+                            pending_playlist_action = Some("rename");
+                            input_buf.clear();
+                            None
+                        }
+                        KeyCode::Enter => Some(PlaylistOp::Load),
+                        KeyCode::Char('x') | KeyCode::Delete => Some(PlaylistOp::Delete),
+                        KeyCode::Char('w') => Some(PlaylistOp::Save),
+                        KeyCode::Esc => {
+                            playlists_focus = false;
+                            None
+                        }
+                        _ => None,
+                    };
+                    if let (Some(op), Some(name)) = (op, name) {
+                        last_status = controller
+                            .playlist(&name, op)
+                            .await
+                            .unwrap_or_else(|e| e.to_string());
+                    }
                 } else {
                     match code {
+                        KeyCode::Tab => {
+                            playlists_focus = !playlists_focus;
+                        }
+                        KeyCode::Char('L') => {
+                            browser.open = true;
+                            playlists_focus = false;
+                        }
+                        KeyCode::Char('/') => {
+                            search.open();
+                            playlists_focus = false;
+                        }
+                        KeyCode::Char('A') if !queue.is_empty() => {
+                            last_status = match playlists.get(playlist_selected) {
+                                Some(name) => controller
+                                    .playlist(name, PlaylistOp::Add(queue[selected].uri.clone()))
+                                    .await
+                                    .unwrap_or_else(|e| e.to_string()),
+                                None => "no playlists; Tab then N to create one".into(),
+                            };
+                        }
                         KeyCode::Char('T') => {
                             prefs_open = true;
                             prefs_selected = 0;
@@ -612,6 +1496,23 @@ async fn main() -> Result<()> {
                                 .await
                                 .unwrap_or_else(|_| "unknown".into());
                         }
+                        KeyCode::Char('b') => {
+                            let _ = controller.prev().await;
+                            last_status = controller
+                                .status()
+                                .await
+                                .unwrap_or_else(|_| "unknown".into());
+                        }
+                        KeyCode::Char('r') => {
+                            let _ = controller.set_repeat(repeat.cycle()).await;
+                            (repeat, shuffle) = controller.modes().await.unwrap_or_default();
+                            last_status = format!("repeat {}", repeat);
+                        }
+                        KeyCode::Char('z') => {
+                            let _ = controller.set_shuffle(!shuffle).await;
+                            (repeat, shuffle) = controller.modes().await.unwrap_or_default();
+                            last_status = format!("shuffle {}", if shuffle { "on" } else { "off" });
+                        }
                         KeyCode::Char('s') => {
                             last_status = controller
                                 .status()
@@ -637,6 +1538,36 @@ async fn main() -> Result<()> {
                             pending_artist_action = None;
                             input_buf.clear();
                         }
+                        KeyCode::Char('E') => {
+                            mode_input = true;
+                            input_insert_next = true;
+                            pending_artist_action = None;
+                            input_buf.clear();
+                        }
+                        KeyCode::Char('x') | KeyCode::Delete if !queue.is_empty() => {
+                            let _ = controller.remove(selected).await;
+                            last_status = "removed from queue".into();
+                        }
+                        KeyCode::Char('J') if selected + 1 < queue.len() => {
+                            let _ = controller.move_item(selected, selected + 1).await;
+                            selected += 1;
+                        }
+                        KeyCode::Char('K') if selected > 0 && selected < queue.len() => {
+                            let _ = controller.move_item(selected, selected - 1).await;
+                            selected -= 1;
+                        }
+                        KeyCode::Char('C') => {
+                            let _ = controller.clear_queue().await;
+                            last_status = "queue cleared".into();
+                        }
+                        KeyCode::Enter if !queue.is_empty() => {
+                            let _ = controller.play_index(selected).await;
+                            selected = 0;
+                            last_status = controller
+                                .status()
+                                .await
+                                .unwrap_or_else(|_| "unknown".into());
+                        }
                         KeyCode::Char('i') => {
                             mode_input = true;
                             input_enqueue = false;
@@ -668,10 +1599,8 @@ async fn main() -> Result<()> {
                         KeyCode::Up => {
                             selected = selected.saturating_sub(1);
                         }
-                        KeyCode::Down => {
-                            if selected + 1 < queue.len() {
-                                selected += 1;
-                            }
+                        KeyCode::Down if selected + 1 < queue.len() => {
+                            selected += 1;
                         }
                         _ => {}
                     }
@@ -684,6 +1613,7 @@ async fn main() -> Result<()> {
                 .status()
                 .await
                 .unwrap_or_else(|_| "unknown".into());
+            (repeat, shuffle) = controller.modes().await.unwrap_or_default();
             last_refresh = Instant::now();
         }
     }
@@ -2,7 +2,7 @@ use anyhow::Context;
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
-use crate::player::Player;
+use crate::player::{ModeOverrides, Player, RepeatMode};
 
 #[derive(Parser)]
 #[command(name = "apple-music-cli")]
@@ -12,8 +12,61 @@ pub struct Cli {
     #[arg(long)]
     daemon: bool,
 
+    /// With --daemon: serve a single client on stdin/stdout instead of a socket, e.g. under a
+    /// supervisor; log output goes to stderr
+    #[arg(long, requires = "daemon")]
+    stdio: bool,
+
+    /// With --daemon: also listen on TCP at ADDR, e.g. 127.0.0.1:7700 (or set APPLE_DAEMON_TCP)
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "daemon",
+        conflicts_with = "stdio"
+    )]
+    tcp: Option<String>,
+
+    /// With --daemon: also serve the HTTP API on ADDR (host:port, or a port on localhost);
+    /// plain --http uses 127.0.0.1:7780. Or set APPLE_DAEMON_HTTP
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "daemon",
+        num_args = 0..=1,
+        default_missing_value = crate::http::DEFAULT_ADDR
+    )]
+    http: Option<String>,
+
+    /// With --daemon: also speak the MPD protocol on ADDR (host:port, or a port on localhost);
+    /// plain --mpd uses 127.0.0.1:6600. Or set APPLE_DAEMON_MPD
+    #[arg(
+        long,
+        value_name = "ADDR",
+        requires = "daemon",
+        num_args = 0..=1,
+        default_missing_value = crate::mpd::DEFAULT_ADDR
+    )]
+    mpd: Option<String>,
+
+    /// With --daemon: do not offer MPRIS on the session bus (or set APPLE_DAEMON_MPRIS=0)
+    #[arg(long, requires = "daemon")]
+    no_mpris: bool,
+
+    /// Queue repeat mode: off, one or all. Defaults to off, or with --daemon to the saved mode
+    #[arg(long)]
+    repeat: Option<RepeatMode>,
+
+    /// Play queued items in random order
+    #[arg(long)]
+    shuffle: bool,
+
+    /// Seed for --shuffle, to get a reproducible order
+    #[arg(long, requires = "shuffle")]
+    shuffle_seed: Option<u64>,
+
+    /// Required unless running as a daemon
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
@@ -46,13 +99,133 @@ pub enum Commands {
         #[command(subcommand)]
         action: QueueAction,
     },
+    /// Index and browse local music files
+    Library {
+        #[command(subcommand)]
+        action: LibraryAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum LibraryAction {
+    /// Update the index from DIRS, or from the configured music directories. Only new and
+    /// changed files are read unless --full is given.
+    Scan {
+        dirs: Vec<PathBuf>,
+        #[arg(long)]
+        full: bool,
+    },
+    Artists,
+    Albums,
 }
 
 #[derive(Subcommand)]
 pub enum QueueAction {
-    Add { item: String },
+    Add {
+        item: String,
+    },
     List,
     Next,
+    /// Remove the item at POSITION (1-based, as printed by `queue list`)
+    Remove {
+        position: usize,
+    },
+    /// Move the item at FROM to position TO (1-based)
+    Move {
+        from: usize,
+        to: usize,
+    },
+    Clear,
+    /// Queue an item to play right after the current one
+    InsertNext {
+        item: String,
+    },
+    /// Skip ahead and play the item at POSITION (1-based)
+    Play {
+        position: usize,
+    },
+    /// Append the entries of an M3U/M3U8, PLS or XSPF playlist to the daemon's queue
+    Load {
+        file: PathBuf,
+    },
+    /// Write the daemon's queue to a playlist file; the format follows the extension
+    Save {
+        file: PathBuf,
+    },
+}
+
+// Convert a 1-based queue position from the command line to an index.
+fn queue_index(position: usize) -> anyhow::Result<usize> {
+    position
+        .checked_sub(1)
+        .ok_or_else(|| anyhow::anyhow!("queue positions start at 1"))
+}
+
+// Send `request` to the daemon (APPLE_DAEMON_SOCKET or the default socket), for commands that
+// act on its queue.
+async fn daemon_call(
+    request: crate::protocol::Request,
+) -> anyhow::Result<crate::protocol::Response> {
+    let socket =
+        crate::protocol::daemon_socket().context("set APPLE_DAEMON_SOCKET to reach the daemon")?;
+    let token = std::env::var("APPLE_DAEMON_TOKEN").ok();
+    let r = crate::protocol::call(&socket, token.as_deref(), request).await?;
+    r.into_result()
+}
+
+fn run_library(action: LibraryAction) -> anyhow::Result<()> {
+    use crate::library::{library_path, music_dirs, Library};
+    match action {
+        LibraryAction::Scan { dirs, full } => {
+            let dirs = if dirs.is_empty() {
+                music_dirs(&crate::config::load_config())
+            } else {
+                dirs
+            };
+            let mut lib = if full {
+                Library::default()
+            } else {
+                Library::load(&library_path()).unwrap_or_default()
+            };
+            let (stats, warnings) = lib.rescan(&dirs);
+            for w in &warnings {
+                eprintln!("warning: {}", w);
+            }
+            lib.save(&library_path())?;
+            println!("Scan: {}", stats);
+            println!(
+                "Indexed {} tracks ({} albums, {} artists) from {}",
+                lib.entries.len(),
+                lib.albums().len(),
+                lib.artists().len(),
+                dirs.iter()
+                    .map(|d| d.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        LibraryAction::Artists => {
+            for a in Library::load(&library_path())?.artists() {
+                println!("{}", a);
+            }
+        }
+        LibraryAction::Albums => {
+            for a in Library::load(&library_path())?.albums() {
+                let artist = a.artist.as_deref().unwrap_or("Unknown artist");
+                match a.year {
+                    Some(y) => println!(
+                        "{} - {} ({}, {} tracks)",
+                        artist,
+                        a.title,
+                        y,
+                        a.tracks.len()
+                    ),
+                    None => println!("{} - {} ({} tracks)", artist, a.title, a.tracks.len()),
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Subcommand)]
@@ -73,18 +246,47 @@ pub enum SeekAction {
 
 pub async fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if !cli.daemon && cli.command.is_none() {
+        use clap::CommandFactory;
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required unless --daemon is given",
+            )
+            .exit();
+    }
+
+    // before the adapter exists: with --stdio nothing else may write to stdout
+    let transports = if cli.daemon {
+        crate::daemon::Transport::configured(
+            cli.stdio,
+            cli.tcp.clone(),
+            cli.http.clone(),
+            cli.mpd.clone(),
+            !cli.no_mpris,
+        )?
+    } else {
+        Vec::new()
+    };
 
     // Create adapter and player
     let adapter = crate::playback::get_adapter().await?;
     let mut player = Player::new(adapter);
+    let modes = ModeOverrides {
+        repeat: cli.repeat,
+        shuffle: cli.shuffle,
+        shuffle_seed: cli.shuffle_seed,
+    };
 
     if cli.daemon {
-        // run the simple daemon that listens for JSON commands
-        crate::daemon::run_daemon(player).await?;
+        // run the simple daemon that listens for JSON commands; the modes go on top of the
+        // saved state
+        crate::daemon::run_daemon(player, transports, modes).await?;
         return Ok(());
     }
+    player.apply_overrides(modes);
 
-    match cli.command {
+    match cli.command.expect("checked after parsing") {
         Commands::Search { query } => {
             let res = player
                 .adapter_mut()
@@ -118,16 +320,15 @@ pub async fn run() -> anyhow::Result<()> {
             player.adapter_mut().next().await.context("next failed")?;
             println!("Next");
         }
-        Commands::Prev => {
-            player.adapter_mut().prev().await.context("prev failed")?;
-            println!("Prev");
-        }
+        Commands::Prev => match player.previous().await.context("prev failed")? {
+            Some(it) => println!("Playing previous: {}", it),
+            None => {
+                player.adapter_mut().prev().await.context("prev failed")?;
+                println!("Prev");
+            }
+        },
         Commands::Status => {
-            let s = player
-                .adapter_mut()
-                .status()
-                .await
-                .context("status failed")?;
+            let s = player.status().await.context("status failed")?;
             println!("Status:\n{}", s);
         }
         Commands::Volume { action } => match action {
@@ -196,9 +397,11 @@ pub async fn run() -> anyhow::Result<()> {
                 println!("Seek to {} seconds", seconds);
             }
         },
+        Commands::Library { action } => run_library(action)?,
         Commands::Queue { action } => match action {
             QueueAction::Add { item } => {
-                player.enqueue(item);
+                let track = player.track_for(&item);
+                player.enqueue(track);
                 println!("Queued");
             }
             QueueAction::List => {
@@ -206,10 +409,53 @@ pub async fn run() -> anyhow::Result<()> {
                     println!("{}: {}", i + 1, it);
                 }
             }
+            QueueAction::Remove { position } => match player.remove(queue_index(position)?) {
+                Some(it) => println!("Removed: {}", it),
+                None => println!("No item at position {}", position),
+            },
+            QueueAction::Move { from, to } => {
+                if player.move_item(queue_index(from)?, queue_index(to)?) {
+                    println!("Moved {} to {}", from, to);
+                } else {
+                    println!("Position out of range");
+                }
+            }
+            QueueAction::Clear => {
+                player.clear();
+                println!("Queue cleared");
+            }
+            QueueAction::InsertNext { item } => {
+                let track = player.track_for(&item);
+                player.insert_next(track);
+                println!("Queued next");
+            }
+            QueueAction::Play { position } => {
+                if let Some(it) = player.jump_to(queue_index(position)?) {
+                    player
+                        .play_track(it.clone())
+                        .await
+                        .context("play queued item failed")?;
+                    println!("Playing queued item: {}", it);
+                } else {
+                    println!("No item at position {}", position);
+                }
+            }
+            QueueAction::Load { file } => {
+                // The file is read here, with the user's permissions, rather than by the daemon.
+                let tracks = crate::playlist::load_playlist(&file, player.source())?;
+                let r = daemon_call(crate::protocol::Request::EnqueueTracks { tracks }).await?;
+                println!("{}", r.msg);
+            }
+            QueueAction::Save { file } => {
+                let r = daemon_call(crate::protocol::Request::List).await?;
+                let tracks = r.tracks.unwrap_or_default();
+                crate::playlist::save_playlist(&file, &tracks)?;
+                println!("Saved {} item(s) to {}", tracks.len(), file.display());
+            }
             QueueAction::Next => {
                 if let Some(it) = player.next_item() {
                     player
-                        .play_item(&it)
+                        .play_track(it.clone())
                         .await
                         .context("play queued item failed")?;
                     println!("Playing queued item: {}", it);
@@ -232,7 +478,7 @@ mod tests {
     #[test]
     fn parse_play_file() {
         let cli = Cli::parse_from(["apple", "play-file", "song.mp3"]);
-        match cli.command {
+        match cli.command.unwrap() {
             Commands::PlayFile { path } => assert_eq!(path, PathBuf::from("song.mp3")),
             _ => panic!("expected PlayFile command"),
         }
@@ -241,7 +487,7 @@ mod tests {
     #[test]
     fn parse_queue_add() {
         let cli = Cli::parse_from(["apple", "queue", "add", "http://example.com/stream.mp3"]);
-        match cli.command {
+        match cli.command.unwrap() {
             Commands::Queue { action } => match action {
                 QueueAction::Add { item } => {
                     assert_eq!(item, "http://example.com/stream.mp3".to_string())
@@ -251,4 +497,56 @@ mod tests {
             _ => panic!("expected Queue command"),
         }
     }
+
+    #[test]
+    fn parse_queue_move() {
+        let cli = Cli::parse_from(["apple", "queue", "move", "3", "1"]);
+        match cli.command.unwrap() {
+            Commands::Queue {
+                action: QueueAction::Move { from, to },
+            } => {
+                assert_eq!((from, to), (3, 1));
+                assert_eq!(queue_index(from).unwrap(), 2);
+                assert!(queue_index(0).is_err());
+            }
+            _ => panic!("expected Queue Move"),
+        }
+    }
+
+    #[test]
+    fn parse_queue_mode_flags() {
+        let cli = Cli::parse_from([
+            "apple",
+            "--repeat",
+            "all",
+            "--shuffle",
+            "--shuffle-seed",
+            "7",
+            "queue",
+            "list",
+        ]);
+        assert_eq!(cli.repeat, Some(RepeatMode::All));
+        assert!(cli.shuffle);
+        assert_eq!(cli.shuffle_seed, Some(7));
+        assert!(Cli::try_parse_from(["apple", "--shuffle-seed", "7", "status"]).is_err());
+    }
+
+    #[test]
+    fn parse_daemon_transports() {
+        let cli = Cli::parse_from(["apple", "--daemon", "--tcp", "127.0.0.1:7700"]);
+        assert!(cli.daemon && cli.command.is_none());
+        assert_eq!(cli.tcp.as_deref(), Some("127.0.0.1:7700"));
+        assert!(Cli::try_parse_from(["apple", "--stdio"]).is_err());
+        assert!(Cli::try_parse_from(["apple", "--daemon", "--stdio", "--tcp", "x:1"]).is_err());
+        let cli = Cli::parse_from(["apple", "--daemon", "--http"]);
+        assert_eq!(cli.http.as_deref(), Some(crate::http::DEFAULT_ADDR));
+        let cli = Cli::parse_from(["apple", "--daemon", "--stdio", "--http", "8080"]);
+        assert_eq!(cli.http.as_deref(), Some("8080"));
+        assert!(Cli::try_parse_from(["apple", "--http", "status"]).is_err());
+        let cli = Cli::parse_from(["apple", "--daemon", "--mpd"]);
+        assert_eq!(cli.mpd.as_deref(), Some(crate::mpd::DEFAULT_ADDR));
+        assert!(Cli::try_parse_from(["apple", "--mpd", "6601", "status"]).is_err());
+        assert!(Cli::parse_from(["apple", "--daemon", "--no-mpris"]).no_mpris);
+        assert!(Cli::try_parse_from(["apple", "--no-mpris", "status"]).is_err());
+    }
 }
@@ -7,9 +7,15 @@ use std::path::PathBuf;
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Config {
     pub theme: Option<String>,
+    /// Directories indexed by `apple library scan`
+    #[serde(default)]
+    pub music_dirs: Vec<PathBuf>,
+    /// Let the daemon watch `music_dirs` and update the library index as files change
+    #[serde(default)]
+    pub watch_library: bool,
 }
 
-fn default_config_dir() -> PathBuf {
+pub(crate) fn default_config_dir() -> PathBuf {
     if let Ok(p) = env::var("APPLE_CONFIG_PATH") {
         return PathBuf::from(p);
     }
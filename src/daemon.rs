@@ -1,45 +1,475 @@
-use crate::player::Player;
-use anyhow::Result;
+use crate::events::{Event, LiveState};
+use crate::jsonrpc::{self, RpcResponse};
+use crate::playback::{EndReason, PlaybackEvent};
+use crate::player::{ModeOverrides, Player};
+use crate::playlist_store::PlaylistStore;
+use crate::protocol::{
+    parse_request, ErrorCode, EventLine, Failure, Hello, Request, Response, COMMANDS,
+    PROTOCOL_VERSION,
+};
+use crate::state::{load_state, save_state, state_path};
+use crate::track::Track;
+use anyhow::{Context, Result};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
 
-// A tiny JSON command protocol for local control. This is D1: a small daemon mode.
-// Commands are sent as a single-line JSON object. Example:
-// { "cmd": "play", "arg": "http://...", "token": "optional" }
+// Local control protocol: one JSON request per line, see `crate::protocol` for the format.
+// Every transport (unix socket, TCP, stdio) hands its connections to `handle_connection`; the
+// optional HTTP API (`crate::http`), MPD listener (`crate::mpd`) and MPRIS (`crate::mpris`) map
+// theirs onto the same `dispatch`.
 
-#[derive(Deserialize)]
-struct Cmd {
-    cmd: String,
-    arg: Option<String>,
-    token: Option<String>,
+/// Where the daemon takes requests.
+#[derive(Debug)]
+pub enum Transport {
+    /// Unix socket at this path, for the daemon's user and the peers in the allowlist. A
+    /// stale socket is replaced on start, and the file is removed on exit
+    #[cfg(unix)]
+    Unix(PathBuf, PeerAllowlist),
+    /// TCP address such as 127.0.0.1:7700 (port 0 picks a free one)
+    Tcp(String),
+    /// A single client on stdin and this stdout (see `claim_stdout`); the daemon stops when
+    /// stdin closes
+    Stdio(std::fs::File),
+    /// The HTTP API on this TCP address
+    Http(String),
+    /// The MPD protocol on this TCP address
+    Mpd(String),
+    /// MPRIS on the session bus; the daemon runs without it if there is no bus
+    #[cfg(all(unix, not(target_os = "macos")))]
+    Mpris,
+}
+
+impl Transport {
+    /// The transports to serve: only stdio with `stdio`, otherwise the unix socket from
+    /// APPLE_DAEMON_SOCKET (default: `protocol::default_socket_path`) and
+    /// APPLE_DAEMON_ALLOW_UIDS / APPLE_DAEMON_ALLOW_GIDS, plus TCP when `tcp` or
+    /// APPLE_DAEMON_TCP is set. Without unix sockets TCP is always used, on APPLE_DAEMON_SOCKET
+    /// or an ephemeral localhost port. The HTTP API is added to either when `http` or
+    /// APPLE_DAEMON_HTTP is set, the MPD listener when `mpd` or APPLE_DAEMON_MPD is, and MPRIS
+    /// on Linux and the BSDs with `mpris` unless APPLE_DAEMON_MPRIS=0.
+    pub fn configured(
+        stdio: bool,
+        tcp: Option<String>,
+        http: Option<String>,
+        mpd: Option<String>,
+        mpris: bool,
+    ) -> Result<Vec<Transport>> {
+        let addr = |flag: Option<String>, var: &str| {
+            flag.or_else(|| std::env::var(var).ok())
+                .filter(|a| !a.is_empty())
+                .map(|a| listen_addr(&a))
+        };
+        let http = addr(http, "APPLE_DAEMON_HTTP").map(Transport::Http);
+        let mpd = addr(mpd, "APPLE_DAEMON_MPD").map(Transport::Mpd);
+        let mpris = mpris
+            && !std::env::var("APPLE_DAEMON_MPRIS")
+                .is_ok_and(|v| v == "0" || v.eq_ignore_ascii_case("false"));
+        #[cfg(all(unix, not(target_os = "macos")))]
+        let mpris = mpris.then_some(Transport::Mpris);
+        #[cfg(not(all(unix, not(target_os = "macos"))))]
+        let mpris: Option<Transport> = {
+            let _ = mpris;
+            None
+        };
+        let extra = http.into_iter().chain(mpd).chain(mpris);
+        if stdio {
+            return Ok(std::iter::once(Transport::Stdio(claim_stdout()?))
+                .chain(extra)
+                .collect());
+        }
+        let socket_env = std::env::var("APPLE_DAEMON_SOCKET").ok();
+        let tcp = tcp.or_else(|| std::env::var("APPLE_DAEMON_TCP").ok());
+        #[cfg(unix)]
+        {
+            let sock = socket_env
+                .filter(|s| !s.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(crate::protocol::default_socket_path);
+            let peers = PeerAllowlist::parse(
+                std::env::var("APPLE_DAEMON_ALLOW_UIDS").ok().as_deref(),
+                std::env::var("APPLE_DAEMON_ALLOW_GIDS").ok().as_deref(),
+            )?;
+            Ok(std::iter::once(Transport::Unix(sock, peers))
+                .chain(tcp.map(Transport::Tcp))
+                .chain(extra)
+                .collect())
+        }
+        #[cfg(not(unix))]
+        {
+            let addr = tcp.or(socket_env).unwrap_or_else(|| "127.0.0.1:0".into());
+            Ok(std::iter::once(Transport::Tcp(addr)).chain(extra).collect())
+        }
+    }
+
+    async fn bind(self) -> Result<Listener> {
+        Ok(match self {
+            #[cfg(unix)]
+            Transport::Unix(path, peers) => {
+                use std::os::unix::fs::PermissionsExt;
+                clear_socket_path(&path)?;
+                let listener = bind_private(&path)?;
+                // other users can only connect when allowed in, and then their credentials are
+                // checked on every connection
+                let (mode, group) = peers.socket_access();
+                if let Some(gid) = group {
+                    nix::unistd::chown(&path, None, Some(nix::unistd::Gid::from_raw(gid)))
+                        .with_context(|| format!("giving {} to group {}", path.display(), gid))?;
+                }
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("setting the mode of {}", path.display()))?;
+                println!("daemon listening on {}", path.display());
+                Listener::Unix(listener, path, peers)
+            }
+            Transport::Tcp(addr) => {
+                let listener = tokio::net::TcpListener::bind(&addr)
+                    .await
+                    .with_context(|| format!("binding {}", addr))?;
+                println!("daemon listening on {}", listener.local_addr()?);
+                Listener::Tcp(listener)
+            }
+            Transport::Stdio(out) => {
+                println!("daemon serving stdin/stdout");
+                let out = tokio::fs::File::from_std(out);
+                Listener::Single(Box::new(tokio::io::join(tokio::io::stdin(), out)))
+            }
+            Transport::Http(addr) => {
+                let listener = tokio::net::TcpListener::bind(&addr)
+                    .await
+                    .with_context(|| format!("binding {}", addr))?;
+                println!("daemon HTTP API on http://{}", listener.local_addr()?);
+                Listener::Http(listener)
+            }
+            Transport::Mpd(addr) => {
+                let listener = tokio::net::TcpListener::bind(&addr)
+                    .await
+                    .with_context(|| format!("binding {}", addr))?;
+                println!("daemon MPD protocol on {}", listener.local_addr()?);
+                Listener::Mpd(listener)
+            }
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Transport::Mpris => Listener::Mpris(
+                crate::mpris::connect()
+                    .await
+                    .context("MPRIS: connecting to the session bus")?,
+            ),
+        })
+    }
+
+    /// Whether the daemon should start anyway when this transport cannot be set up.
+    fn optional(&self) -> bool {
+        #[cfg(all(unix, not(target_os = "macos")))]
+        if matches!(self, Transport::Mpris) {
+            return true;
+        }
+        false
+    }
+}
+
+/// Make way for the unix socket at `path`: a socket left behind by a daemon that is gone is
+/// removed, but a live one, or a file that is not a socket, is an error.
+#[cfg(unix)]
+fn clear_socket_path(path: &std::path::Path) -> Result<()> {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::FileTypeExt;
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("checking {}", path.display())),
+    };
+    if !meta.file_type().is_socket() {
+        anyhow::bail!("{} exists and is not a socket", path.display());
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => anyhow::bail!("another daemon is listening on {}", path.display()),
+        Err(e) if matches!(e.kind(), ErrorKind::ConnectionRefused | ErrorKind::NotFound) => {
+            std::fs::remove_file(path)
+                .or_else(|e| match e.kind() {
+                    ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })
+                .with_context(|| format!("removing stale socket {}", path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+}
+
+/// Bind a unix socket at `path` that only its owner can connect to from the start, instead of
+/// creating it with the process umask and narrowing it afterwards. The umask is process-wide,
+/// but it is only narrowed, and only for the bind.
+#[cfg(unix)]
+fn bind_private(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use nix::sys::stat::{umask, Mode};
+    let old = umask(Mode::from_bits_truncate(0o177));
+    let listener = tokio::net::UnixListener::bind(path);
+    umask(old);
+    listener.with_context(|| format!("binding {}", path.display()))
+}
+
+/// Unix socket peers that need no token: these users and members of these groups. The daemon's
+/// own user needs the token when one is set, like TCP clients; anyone else always needs it and
+/// is refused when none is set.
+#[cfg(unix)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerAllowlist {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+#[cfg(unix)]
+impl PeerAllowlist {
+    /// Comma-separated numeric ids, as in APPLE_DAEMON_ALLOW_UIDS and APPLE_DAEMON_ALLOW_GIDS.
+    pub fn parse(uids: Option<&str>, gids: Option<&str>) -> Result<Self> {
+        let ids = |list: Option<&str>, what: &str| {
+            list.unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| {
+                    id.parse::<u32>()
+                        .with_context(|| format!("invalid {} in the allowlist: {}", what, id))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            uids: ids(uids, "uid")?,
+            gids: ids(gids, "gid")?,
+        })
+    }
+
+    /// Whether no other user is let in.
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty()
+    }
+
+    /// Mode and group for the socket: 0600 for the daemon's user alone, 0660 with the group
+    /// when a single group is allowed, and otherwise 0666, which lets every local user connect
+    /// and leaves it to the credential check to refuse them.
+    fn socket_access(&self) -> (u32, Option<u32>) {
+        match (self.uids.as_slice(), self.gids.as_slice()) {
+            ([], []) => (0o600, None),
+            ([], [gid]) => (0o660, Some(*gid)),
+            _ => (0o666, None),
+        }
+    }
+
+    fn allows(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+/// Keep the process's stdout for the protocol and point fd 1 at stderr, so that log output
+/// from the daemon and the adapters cannot end up in the reply stream.
+#[cfg(unix)]
+pub fn claim_stdout() -> Result<std::fs::File> {
+    use std::io::Write;
+    std::io::stdout().flush()?;
+    let out = nix::unistd::dup(std::io::stdout()).context("duplicating stdout")?;
+    nix::unistd::dup2_stdout(std::io::stderr()).context("redirecting stdout")?;
+    Ok(std::fs::File::from(out))
+}
+
+#[cfg(not(unix))]
+pub fn claim_stdout() -> Result<std::fs::File> {
+    anyhow::bail!("the stdio transport needs a unix platform")
+}
+
+trait Duplex: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Duplex for T {}
+
+/// A bound transport.
+enum Listener {
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf, PeerAllowlist),
+    Tcp(tokio::net::TcpListener),
+    /// One connection with no idle timeout
+    Single(Box<dyn Duplex>),
+    Http(tokio::net::TcpListener),
+    Mpd(tokio::net::TcpListener),
+    #[cfg(all(unix, not(target_os = "macos")))]
+    Mpris(zbus::Connection),
+}
+
+impl Listener {
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix(..) => "unix socket",
+            Listener::Tcp(_) => "TCP",
+            Listener::Single(_) => "stdio",
+            Listener::Http(_) => "HTTP",
+            Listener::Mpd(_) => "MPD",
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Listener::Mpris(_) => "MPRIS",
+        }
+    }
+
+    /// Whether the daemon stops when this listener is done: only the stdio client ends it.
+    fn ends_daemon(&self) -> bool {
+        matches!(self, Listener::Single(_))
+    }
+
+    /// Accept connections until `stop` is set; a `Single` listener returns when its client
+    /// goes away.
+    async fn serve(self, shared: Shared, mut stop: watch::Receiver<bool>) {
+        match self {
+            #[cfg(unix)]
+            Listener::Unix(listener, path, peers) => {
+                let l = &listener;
+                let peers = &peers;
+                accept_loop(
+                    || async move { l.accept().await.map(|(s, _)| s) },
+                    |stream, shared, stop| unix_connection(stream, shared, stop, peers.clone()),
+                    &shared,
+                    &mut stop,
+                )
+                .await;
+                // cleanup socket on exit
+                let _ = std::fs::remove_file(&path);
+            }
+            Listener::Tcp(listener) => {
+                let l = &listener;
+                accept_loop(
+                    || async move { l.accept().await.map(|(s, _)| s) },
+                    protocol_connection,
+                    &shared,
+                    &mut stop,
+                )
+                .await;
+            }
+            Listener::Single(stream) => {
+                if let Err(e) = handle_connection(stream, shared, stop, None, Peer::Unchecked).await
+                {
+                    eprintln!("daemon connection error: {}", e);
+                }
+            }
+            Listener::Http(listener) => {
+                if let Err(e) = crate::http::serve(listener, shared, stop).await {
+                    eprintln!("daemon http error: {}", e);
+                }
+            }
+            Listener::Mpd(listener) => {
+                let l = &listener;
+                accept_loop(
+                    || async move { l.accept().await.map(|(s, _)| s) },
+                    crate::mpd::handle_connection,
+                    &shared,
+                    &mut stop,
+                )
+                .await;
+            }
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Listener::Mpris(conn) => {
+                if let Err(e) = crate::mpris::serve(conn, shared, stop).await {
+                    eprintln!("daemon: MPRIS stopped: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// What a listener task reports when it is done: the listener's name, and whether the daemon
+/// stops with it.
+type ListenerExit = (&'static str, bool);
+
+fn spawn_listener(
+    servers: &mut tokio::task::JoinSet<ListenerExit>,
+    listener: Listener,
+    shared: Shared,
+    stop: watch::Receiver<bool>,
+) {
+    let exit = (listener.name(), listener.ends_daemon());
+    let serve = listener.serve(shared, stop);
+    servers.spawn(async move {
+        serve.await;
+        exit
+    });
+}
+
+// Run until a signal, or until a listener that ends the daemon is done (the stdio client went
+// away). Any other listener that stops, such as MPRIS when the session bus goes away, is
+// logged and the others carry on.
+async fn wait_for_exit(
+    servers: &mut tokio::task::JoinSet<ListenerExit>,
+    stop: &mut watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = stopped(stop) => return,
+            Some(done) = servers.join_next() => match done {
+                Ok((_, true)) => return,
+                Ok((name, false)) => {
+                    eprintln!("daemon: the {} listener stopped; serving the others", name)
+                }
+                Err(e) => eprintln!("daemon: a listener failed: {}", e),
+            },
+        }
+    }
+}
+
+/// Hand every accepted connection to `handle` on its own task.
+async fn accept_loop<S, F, Fut, H, HFut>(
+    accept: F,
+    handle: H,
+    shared: &Shared,
+    stop: &mut watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<S>>,
+    H: Fn(S, Shared, watch::Receiver<bool>) -> HFut,
+    HFut: std::future::Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        tokio::select! {
+            _ = stopped(stop) => break,
+            accept = accept() => match accept {
+                Ok(stream) => {
+                    let conn = handle(stream, shared.clone(), stop.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = conn.await {
+                            eprintln!("daemon connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    eprintln!("daemon accept error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct Resp {
-    ok: bool,
-    msg: String,
-    items: Option<Vec<String>>,
+pub(crate) async fn stopped(stop: &mut watch::Receiver<bool>) {
+    let _ = stop.wait_for(|s| *s).await;
 }
 
-/// Run the daemon. Improvements:
-/// - Socket path configurable via APPLE_DAEMON_SOCKET
+/// Run the daemon on `transports` (see `Transport::configured`). Improvements:
 /// - Optional auth token via APPLE_DAEMON_TOKEN
 /// - Graceful shutdown on Ctrl-C / SIGTERM
-/// - Per-connection loop (multiple commands), per-request timeout
-pub async fn run_daemon(player: Player) -> Result<()> {
-    let socket_env = std::env::var("APPLE_DAEMON_SOCKET").ok();
+/// - Per-connection loop (multiple commands), idle timeout
+/// - Queue and playback state saved to `state.json` and restored on start; set
+///   APPLE_DAEMON_RESUME=1 to resume the saved track at its saved position
+pub async fn run_daemon(
+    player: Player,
+    transports: Vec<Transport>,
+    modes: ModeOverrides,
+) -> Result<()> {
     let token_env = std::env::var("APPLE_DAEMON_TOKEN").ok();
+    let resume = std::env::var("APPLE_DAEMON_RESUME")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
 
-    // Shared shutdown notifier
-    let shutdown = Arc::new(tokio::sync::Notify::new());
-    let shutdown_flag = Arc::new(AtomicBool::new(false));
-    // Spawn a task to watch for Ctrl-C (cross-platform) and notify shutdown
+    // Set once to shut down; every listener and connection watches it
+    let (stop_tx, mut stop) = watch::channel(false);
+    // Spawn a task to watch for Ctrl-C (cross-platform) and signal shutdown
     {
-        let s = shutdown.clone();
-        let flag = shutdown_flag.clone();
+        let stop_tx = stop_tx.clone();
         tokio::spawn(async move {
             // On unix we also try to listen for SIGTERM for CI/runners
             #[cfg(unix)]
@@ -59,810 +489,972 @@ pub async fn run_daemon(player: Player) -> Result<()> {
                 let _ = tokio::signal::ctrl_c().await;
             }
             println!("daemon: shutting down (signal received)");
-            flag.store(true, Ordering::SeqCst);
-            s.notify_waiters();
+            stop_tx.send_replace(true);
         });
     }
 
-    #[cfg(unix)]
-    {
-        use tokio::net::UnixListener;
-        let sock = socket_env.map(std::path::PathBuf::from).unwrap_or_else(|| {
-            std::env::temp_dir().join(format!("apple-daemon-{}.sock", std::process::id()))
-        });
-        // remove if exists
-        let _ = std::fs::remove_file(&sock);
-        let listener = UnixListener::bind(&sock)?;
-        println!("daemon listening on {}", sock.display());
+    let mut listeners = Vec::new();
+    for t in transports {
+        let optional = t.optional();
+        match t.bind().await {
+            Ok(l) => listeners.push(l),
+            Err(e) if optional => eprintln!("daemon: {:#}", e),
+            Err(e) => return Err(e),
+        }
+    }
 
-        // Share player state across tasks
-        let player = Arc::new(tokio::sync::Mutex::new(player));
-        loop {
-            tokio::select! {
-                _ = shutdown.notified() => break,
-                accept = listener.accept() => match accept {
-                    Ok((stream, _addr)) => {
-                        let player = player.clone();
-                        let shutdown = shutdown.clone();
-                        let token_env = token_env.clone();
-                        let shutdown_flag = shutdown_flag.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = handle_unix_connection(
-                                stream,
-                                player,
-                                shutdown,
-                                shutdown_flag,
-                                token_env,
-                            )
-                            .await
-                            {
-                                eprintln!("daemon connection error: {}", e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("daemon accept error: {}", e);
-                        break;
-                    }
-                }
-            }
+    // Share player state across tasks
+    let player = Arc::new(tokio::sync::Mutex::new(player));
+    let state_file = state_path();
+    restore_state(&player, &state_file, resume).await;
+    // explicit command-line modes win over the saved ones
+    player.lock().await.apply_overrides(modes);
+    let advance = spawn_auto_advance(player.clone()).await;
+    let changes = watch::channel(()).0;
+    let forwarder = spawn_change_forwarder(player.clone(), changes.clone()).await;
+    let saver = tokio::spawn(save_state_on_change(
+        player.clone(),
+        state_file.clone(),
+        changes.subscribe(),
+        STATE_SAVE_INTERVAL,
+    ));
+    let shared = Shared {
+        player: player.clone(),
+        token: token_env,
+        changes,
+        playlists: Arc::new(Playlists::new(PlaylistStore::open_default())),
+        library: crate::library::library_path(),
+    };
+    let library = spawn_library_maintenance();
+
+    let mut servers = tokio::task::JoinSet::new();
+    for l in listeners {
+        spawn_listener(&mut servers, l, shared.clone(), stop.clone());
+    }
+    wait_for_exit(&mut servers, &mut stop).await;
+    stop_tx.send_replace(true);
+    while servers.join_next().await.is_some() {}
+
+    for h in [advance, forwarder, library].into_iter().flatten() {
+        h.abort();
+    }
+    saver.abort();
+    if let Err(e) = save_state(&state_file, &snapshot(&player).await) {
+        eprintln!("daemon: failed to save state: {}", e);
+    }
+    println!("daemon stopped");
+    Ok(())
+}
+
+// Incremental rescan of the music dirs, plus watching if `watch_library` is set in the config.
+fn spawn_library_maintenance() -> Option<JoinHandle<()>> {
+    let cfg = crate::config::load_config();
+    let index = crate::library::library_path();
+    let roots = crate::library_watch::library_roots(&cfg, &index);
+    if roots.is_empty() {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        if let Err(e) =
+            crate::library_watch::maintain_library(roots, index, cfg.watch_library).await
+        {
+            eprintln!("daemon: library updates stopped: {:#}", e);
         }
+    }))
+}
 
-        // cleanup socket on exit
-        let _ = std::fs::remove_file(&sock);
-        println!("daemon stopped");
-        Ok(())
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Player snapshot including the adapter's current position and volume.
+async fn snapshot(player: &tokio::sync::Mutex<Player>) -> crate::state::PlayerState {
+    let mut pl = player.lock().await;
+    let mut st = pl.snapshot();
+    if st.current.is_some() {
+        st.position = pl.adapter_mut().get_position().await.ok();
     }
+    st.volume = pl.adapter_mut().get_volume().await.ok();
+    st
+}
 
-    #[cfg(not(unix))]
-    {
-        // Fallback TCP listener bound to localhost:0 (ephemeral port)
-        use tokio::net::TcpListener;
-        let listener = TcpListener::bind("127.0.0.1:0").await?;
-        println!("daemon listening on {}", listener.local_addr()?);
-        let player = Arc::new(tokio::sync::Mutex::new(player));
-        loop {
-            tokio::select! {
-                _ = shutdown.notified() => break,
-                accept = listener.accept() => match accept {
-                    Ok((stream, _addr)) => {
-                        let player = player.clone();
-                        let shutdown = shutdown.clone();
-                        let token_env = token_env.clone();
-                        let shutdown_flag = shutdown_flag.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = handle_tcp_connection(
-                                stream,
-                                player,
-                                shutdown,
-                                shutdown_flag,
-                                token_env,
-                            )
-                            .await
-                            {
-                                eprintln!("daemon tcp conn error: {}", e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        eprintln!("daemon accept error: {}", e);
-                        break;
-                    }
+/// Load the saved state into the player. With `resume` the saved current track is played and
+/// seeked to its saved position; otherwise it is put back at the head of the queue.
+async fn restore_state(player: &tokio::sync::Mutex<Player>, path: &std::path::Path, resume: bool) {
+    let Some(st) = load_state(path) else {
+        return;
+    };
+    let position = st.position;
+    let volume = st.volume;
+    let mut pl = player.lock().await;
+    let current = pl.restore(st);
+    println!(
+        "daemon: restored {} queued item(s) from {}",
+        pl.list().len(),
+        path.display()
+    );
+    if let Some(v) = volume {
+        let _ = pl.adapter_mut().set_volume(v).await;
+    }
+    let Some(track) = current else {
+        return;
+    };
+    if !resume {
+        pl.insert_next(track);
+        return;
+    }
+    // subscribe before playing so the Started event cannot be missed
+    let events = pl.adapter_mut().subscribe().ok();
+    if let Err(e) = pl.play_track(track.clone()).await {
+        eprintln!("daemon: failed to resume {}: {}", track, e);
+        return;
+    }
+    println!("daemon: resuming {}", track);
+    let Some(pos) = position.filter(|p| *p > 0) else {
+        return;
+    };
+    drop(pl);
+    // the adapter cannot seek until the file is loaded; wait without holding the player
+    if let Some(mut rx) = events {
+        let started = async {
+            while let Ok(ev) = rx.recv().await {
+                if ev == PlaybackEvent::Started {
+                    break;
                 }
             }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(5), started).await;
+    }
+    if let Err(e) = player.lock().await.adapter_mut().seek_to(pos).await {
+        eprintln!("daemon: failed to seek to saved position: {}", e);
+    }
+}
+
+/// Write the player state whenever `changes` fires, and every `interval` so the saved
+/// position keeps up with playback. The write is skipped when nothing changed.
+async fn save_state_on_change(
+    player: Arc<tokio::sync::Mutex<Player>>,
+    path: std::path::PathBuf,
+    mut changes: watch::Receiver<()>,
+    interval: Duration,
+) {
+    let mut last = None;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            changed = changes.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+        }
+        let st = snapshot(&player).await;
+        if last.as_ref() == Some(&st) {
+            continue;
+        }
+        match save_state(&path, &st) {
+            Ok(()) => last = Some(st),
+            Err(e) => eprintln!("daemon: failed to save state: {}", e),
+        }
+    }
+}
+
+/// Start the background task that plays the next queued item whenever the adapter reports
+/// that the current one finished. Returns None if the adapter does not emit events, in which
+/// case the queue only advances on an explicit `next`.
+pub(crate) async fn spawn_auto_advance(
+    player: Arc<tokio::sync::Mutex<Player>>,
+) -> Option<JoinHandle<()>> {
+    let rx = match player.lock().await.adapter_mut().subscribe() {
+        Ok(rx) => rx,
+        Err(e) => {
+            println!("daemon: queue auto-advance disabled ({})", e);
+            return None;
+        }
+    };
+    Some(tokio::spawn(auto_advance(player, rx)))
+}
+
+/// Wake subscribers on adapter events as well, so track changes from auto-advance and pauses
+/// made outside the daemon reach them without waiting for the next tick.
+async fn spawn_change_forwarder(
+    player: Arc<tokio::sync::Mutex<Player>>,
+    changes: watch::Sender<()>,
+) -> Option<JoinHandle<()>> {
+    let mut rx = player.lock().await.adapter_mut().subscribe().ok()?;
+    Some(tokio::spawn(async move {
+        while !matches!(rx.recv().await, Err(broadcast::error::RecvError::Closed)) {
+            changes.send_replace(());
+        }
+    }))
+}
+
+async fn auto_advance(
+    player: Arc<tokio::sync::Mutex<Player>>,
+    mut rx: broadcast::Receiver<PlaybackEvent>,
+) {
+    loop {
+        let reason = match rx.recv().await {
+            Ok(PlaybackEvent::Ended(reason)) => reason,
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        // `Stop` means the item was replaced (play/next), so the queue already moved on.
+        // Errors advance too so a broken item does not stall the queue.
+        if !matches!(reason, EndReason::Eof | EndReason::Error(_)) {
+            continue;
+        }
+        match player.lock().await.play_after_end().await {
+            Ok(Some(it)) => println!("daemon: track ended, now playing: {}", it),
+            Ok(None) => {}
+            Err(e) => eprintln!("daemon: failed to carry on after the track ended: {:#}", e),
+        }
+    }
+}
+
+/// What every connection needs.
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) player: Arc<tokio::sync::Mutex<Player>>,
+    pub(crate) token: Option<String>,
+    /// Bumped whenever the player may have changed; wakes subscribers
+    pub(crate) changes: watch::Sender<()>,
+    pub(crate) playlists: Arc<Playlists>,
+    /// The library index, whose presence is the `library` capability
+    pub(crate) library: PathBuf,
+}
+
+#[cfg(test)]
+impl Shared {
+    /// Token "secret", with playlists and library in a fresh temp dir rather than the user's
+    /// config dir.
+    pub(crate) fn for_test(player: Player) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "apple-daemon-shared-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        Shared {
+            player: Arc::new(tokio::sync::Mutex::new(player)),
+            token: Some("secret".into()),
+            changes: watch::channel(()).0,
+            playlists: Arc::new(Playlists::new(PlaylistStore::new(dir.join("playlists")))),
+            library: dir.join("library.json"),
+        }
+    }
+}
+
+/// The playlist store with its names cached, so the live state does not read the playlist dir
+/// on every change.
+pub(crate) struct Playlists {
+    store: PlaylistStore,
+    names: std::sync::Mutex<Vec<String>>,
+}
+
+impl Playlists {
+    pub(crate) fn new(store: PlaylistStore) -> Self {
+        let names = store.names().unwrap_or_default();
+        Playlists {
+            store,
+            names: std::sync::Mutex::new(names),
         }
-        println!("daemon stopped");
-        Ok(())
     }
+
+    fn names(&self) -> Vec<String> {
+        self.names.lock().unwrap().clone()
+    }
+
+    // Re-read the names after a request that may have changed them.
+    fn refresh(&self) {
+        let names = self.store.names().unwrap_or_default();
+        *self.names.lock().unwrap() = names;
+    }
+}
+
+/// Per-connection protocol state.
+#[derive(Default)]
+pub(crate) struct Session {
+    /// Set by `subscribe`
+    pub(crate) events: Option<Subscription>,
+    // whether the request being answered came in as JSON-RPC
+    jsonrpc: bool,
+    peer: Peer,
+}
+
+/// What the unix socket's peer credentials said about the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(not(unix), allow(dead_code))]
+enum Peer {
+    /// Not a unix socket, or the daemon's own user: the token decides
+    #[default]
+    Unchecked,
+    /// In the allowlist; no token needed
+    Allowed,
+    /// Not in the allowlist; the token is needed even when none is set
+    Refused,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Subscription {
+    /// Interval of `position` events
+    pub(crate) tick: Option<Duration>,
+    /// Send events as JSON-RPC notifications
+    jsonrpc: bool,
+}
+
+/// Socket connections that are not subscribed are closed after this long without a request.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Fastest `position` rate a subscriber can ask for.
+const MIN_TICK: Duration = Duration::from_millis(50);
+
+// A socket connection speaking the line protocol.
+async fn protocol_connection<S>(
+    stream: S,
+    shared: Shared,
+    stop: watch::Receiver<bool>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    handle_connection(stream, shared, stop, Some(IDLE_TIMEOUT), Peer::Unchecked).await
+}
+
+// A unix socket connection; the peer's credentials (SO_PEERCRED on Linux) decide whether it
+// needs the token.
 #[cfg(unix)]
-async fn handle_unix_connection(
+async fn unix_connection(
     stream: tokio::net::UnixStream,
-    player: Arc<tokio::sync::Mutex<Player>>,
-    _shutdown_notify: Arc<tokio::sync::Notify>,
-    shutdown_flag: Arc<AtomicBool>,
-    token_env: Option<String>,
+    shared: Shared,
+    stop: watch::Receiver<bool>,
+    peers: PeerAllowlist,
 ) -> Result<()> {
-    use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
+    let peer = match stream.peer_cred() {
+        Ok(cred) if peers.allows(cred.uid(), cred.gid()) => Peer::Allowed,
+        Ok(cred) if cred.uid() == nix::unistd::geteuid().as_raw() => Peer::Unchecked,
+        Ok(cred) => {
+            eprintln!(
+                "daemon: uid {} (gid {}) is not in the allowlist",
+                cred.uid(),
+                cred.gid()
+            );
+            Peer::Refused
+        }
+        Err(e) => {
+            eprintln!("daemon: cannot read the peer's credentials: {}", e);
+            Peer::Refused
+        }
+    };
+    handle_connection(stream, shared, stop, Some(IDLE_TIMEOUT), peer).await
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    shared: Shared,
+    mut stop: watch::Receiver<bool>,
+    idle_timeout: Option<Duration>,
+    peer: Peer,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
     let (r, mut w) = split(stream);
-    let mut reader = BufReader::new(r);
+    let mut lines = BufReader::new(r).lines();
+    let mut session = Session {
+        peer,
+        ..Session::default()
+    };
+    let mut changes = shared.changes.subscribe();
+    let mut ticker: Option<tokio::time::Interval> = None;
+    // what the subscriber has been told so far
+    let mut seen: Option<LiveState> = None;
     loop {
-        // read a line with timeout
-        let mut line = String::new();
-        match tokio::time::timeout(Duration::from_secs(30), reader.read_line(&mut line)).await {
-            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => {
-                // EOF, read error, or timeout: close connection
-                break;
-            }
-            Ok(Ok(_)) => {}
-        }
-
-        // allow shutdown to preempt long handling
-        if shutdown_flag.load(Ordering::SeqCst) {
-            break;
-        }
-
-        // parse and handle command
-        match serde_json::from_str::<Cmd>(&line) {
-            Ok(c) => {
-                // token check if required
-                if let Some(ref expected) = token_env {
-                    if c.token.as_deref() != Some(expected.as_str()) {
-                        let resp = Resp {
-                            ok: false,
-                            msg: "unauthorized".into(),
-                            items: None,
-                        };
-                        let j = serde_json::to_string(&resp)? + "\n";
-                        let _ = w.write_all(j.as_bytes()).await;
-                        continue;
-                    }
+        // subscribers stay connected while they listen
+        let idle = idle_timeout.filter(|_| session.events.is_none());
+        tokio::select! {
+            _ = stopped(&mut stop) => break,
+            line = lines.next_line() => {
+                // EOF or read error: close connection
+                let Ok(Some(line)) = line else { break };
+                let before = session.events;
+                let reply = if jsonrpc::is_jsonrpc(&line) {
+                    respond_jsonrpc(&line, &shared, &mut session).await
+                } else {
+                    Some(serde_json::to_string(&respond(&line, &shared, &mut session).await)?)
+                };
+                if let Some(j) = reply {
+                    w.write_all((j + "\n").as_bytes()).await?;
                 }
-
-                let mut pl = player.lock().await;
-                let res = match c.cmd.as_str() {
-                    "play" => {
-                        if let Some(u) = c.arg.as_deref() {
-                            // block insecure http unless explicitly allowed via env
-                            if u.starts_with("http://")
-                                && !std::env::var("APPLE_ALLOW_INSECURE")
-                                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-                                    .unwrap_or(false)
-                            {
-                                Resp {
-                            ok: false,
-                            msg: "Refusing insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow".into(),
-                            items: None,
-                        }
-                            } else if u.starts_with("https://") {
-                                if let Err(e) = validate_https_url(u).await {
-                                    Resp {
-                                        ok: false,
-                                        msg: format!("url validation failed: {}", e),
-                                        items: None,
-                                    }
-                                } else {
-                                    let _ = pl.play_item(u).await;
-                                    Resp {
-                                        ok: true,
-                                        msg: "playing".into(),
-                                        items: None,
-                                    }
-                                }
-                            } else {
-                                // allow other schemes (file://, etc.) without validation
-                                let _ = pl.play_item(u).await;
-                                Resp {
-                                    ok: true,
-                                    msg: "playing".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
+                if session.events != before {
+                    // (re)subscribed: start over with the full state
+                    seen = None;
+                    ticker = session.events.and_then(|s| s.tick).map(ticker_every);
+                    changes.mark_unchanged();
+                    if let Some(sub) = session.events {
+                        push_events(&mut w, &shared, sub, &mut seen).await?;
                     }
-                    "pause" => {
-                        let _ = pl.adapter_mut().pause().await;
-                        Resp {
-                            ok: true,
-                            msg: "paused".into(),
-                            items: None,
-                        }
-                    }
-                    "enqueue" => {
-                        if let Some(item) = c.arg.as_deref() {
-                            if item.starts_with("http://")
-                                && !std::env::var("APPLE_ALLOW_INSECURE")
-                                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-                                    .unwrap_or(false)
-                            {
-                                Resp {
-                            ok: false,
-                            msg: "Refusing insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow".into(),
-                            items: None,
-                        }
-                            } else if item.starts_with("https://") {
-                                if let Err(e) = validate_https_url(item).await {
-                                    Resp {
-                                        ok: false,
-                                        msg: format!("url validation failed: {}", e),
-                                        items: None,
-                                    }
-                                } else {
-                                    pl.enqueue(item.to_string());
-                                    Resp {
-                                        ok: true,
-                                        msg: "enqueued".into(),
-                                        items: None,
-                                    }
-                                }
-                            } else {
-                                pl.enqueue(item.to_string());
-                                Resp {
-                                    ok: true,
-                                    msg: "enqueued".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "next" => {
-                        if let Some(it) = pl.next_item() {
-                            let _ = pl.play_item(&it).await;
-                            Resp {
-                                ok: true,
-                                msg: format!("playing {}", it),
-                                items: None,
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "queue empty".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "status" => {
-                        let s = pl
-                            .adapter_mut()
-                            .status()
-                            .await
-                            .unwrap_or_else(|e| format!("err: {}", e));
-                        Resp {
-                            ok: true,
-                            msg: s,
-                            items: None,
-                        }
-                    }
-                    "list" => Resp {
-                        ok: true,
-                        msg: "ok".into(),
-                        items: Some(pl.list()),
-                    },
-                    "artist_info" => {
-                        if let Some(artist_id) = c.arg.as_deref() {
-                            let info = pl
-                                .adapter_mut()
-                                .artist_info(artist_id)
-                                .await
-                                .unwrap_or_else(|e| format!("err: {}", e));
-                            // split lines into items for structured response
-                            let items = info.lines().map(|s| s.to_string()).collect();
-                            Resp {
-                                ok: true,
-                                msg: "artist info".into(),
-                                items: Some(items),
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "artist_discography" => {
-                        if let Some(artist_id) = c.arg.as_deref() {
-                            let disc = pl
-                                .adapter_mut()
-                                .artist_discography(artist_id)
-                                .await
-                                .unwrap_or_else(|e| format!("err: {}", e));
-                            let items = if disc.is_empty() {
-                                vec![]
-                            } else {
-                                disc.lines().map(|s| s.to_string()).collect()
-                            };
-                            Resp {
-                                ok: true,
-                                msg: "discography".into(),
-                                items: Some(items),
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "volume_up" => {
-                        let _ = pl.adapter_mut().volume_up().await;
-                        Resp {
-                            ok: true,
-                            msg: "volume up".into(),
-                            items: None,
-                        }
-                    }
-                    "volume_down" => {
-                        let _ = pl.adapter_mut().volume_down().await;
-                        Resp {
-                            ok: true,
-                            msg: "volume down".into(),
-                            items: None,
-                        }
-                    }
-                    "set_volume" => {
-                        if let Some(vol_str) = c.arg.as_deref() {
-                            if let Ok(volume) = vol_str.parse::<u8>() {
-                                let _ = pl.adapter_mut().set_volume(volume).await;
-                                Resp {
-                                    ok: true,
-                                    msg: format!("volume set to {}", volume),
-                                    items: None,
-                                }
-                            } else {
-                                Resp {
-                                    ok: false,
-                                    msg: "invalid volume".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing volume".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "mute" => {
-                        let _ = pl.adapter_mut().mute().await;
-                        Resp {
-                            ok: true,
-                            msg: "muted".into(),
-                            items: None,
-                        }
-                    }
-                    "unmute" => {
-                        let _ = pl.adapter_mut().unmute().await;
-                        Resp {
-                            ok: true,
-                            msg: "unmuted".into(),
-                            items: None,
-                        }
-                    }
-                    "seek_forward" => {
-                        let seconds = c.arg.as_deref().and_then(|s| s.parse().ok()).unwrap_or(10);
-                        let _ = pl.adapter_mut().seek_forward(seconds).await;
-                        Resp {
-                            ok: true,
-                            msg: format!("seek forward {} seconds", seconds),
-                            items: None,
-                        }
-                    }
-                    "seek_backward" => {
-                        let seconds = c.arg.as_deref().and_then(|s| s.parse().ok()).unwrap_or(10);
-                        let _ = pl.adapter_mut().seek_backward(seconds).await;
-                        Resp {
-                            ok: true,
-                            msg: format!("seek backward {} seconds", seconds),
-                            items: None,
-                        }
-                    }
-                    "seek_to" => {
-                        if let Some(secs_str) = c.arg.as_deref() {
-                            if let Ok(seconds) = secs_str.parse::<u64>() {
-                                let _ = pl.adapter_mut().seek_to(seconds).await;
-                                Resp {
-                                    ok: true,
-                                    msg: format!("seek to {} seconds", seconds),
-                                    items: None,
-                                }
-                            } else {
-                                Resp {
-                                    ok: false,
-                                    msg: "invalid seconds".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing seconds".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "position" => {
-                        let pos = pl.adapter_mut().get_position().await.unwrap_or(0);
-                        Resp {
-                            ok: true,
-                            msg: pos.to_string(),
-                            items: None,
-                        }
-                    }
-                    "duration" => {
-                        let dur = pl.adapter_mut().get_duration().await.unwrap_or(0);
-                        Resp {
-                            ok: true,
-                            msg: dur.to_string(),
-                            items: None,
-                        }
-                    }
-                    _ => Resp {
-                        ok: false,
-                        msg: "unknown cmd".into(),
-                        items: None,
-                    },
-                };
-                let j = serde_json::to_string(&res)? + "\n";
-                let _ = w.write_all(j.as_bytes()).await;
+                }
+            }
+            Ok(()) = changes.changed(), if session.events.is_some() => {
+                if let Some(sub) = session.events {
+                    push_events(&mut w, &shared, sub, &mut seen).await?;
+                }
             }
-            Err(_) => {
-                let _ = w
-                    .write_all(b"{\"ok\":false,\"msg\":\"parse error\"}\n")
-                    .await;
+            _ = tick(&mut ticker), if ticker.is_some() => {
+                if let Some(sub) = session.events {
+                    push_events(&mut w, &shared, sub, &mut seen).await?;
+                }
             }
+            _ = tokio::time::sleep(idle.unwrap_or_default()), if idle.is_some() => break,
         }
     }
     Ok(())
 }
 
-#[cfg(not(unix))]
-async fn handle_tcp_connection(
-    mut stream: tokio::net::TcpStream,
-    player: Arc<tokio::sync::Mutex<Player>>,
-    _shutdown_notify: Arc<tokio::sync::Notify>,
-    shutdown_flag: Arc<AtomicBool>,
-    token_env: Option<String>,
-) -> Result<()> {
-    use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
-    let (r, mut w) = split(stream);
-    let mut reader = BufReader::new(r);
-    loop {
-        // read a line with timeout
-        let mut line = String::new();
-        match tokio::time::timeout(Duration::from_secs(30), reader.read_line(&mut line)).await {
-            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => {
-                // EOF, read error, or timeout: close connection
-                break;
-            }
-            Ok(Ok(_)) => {}
-        }
-
-        if shutdown_flag.load(Ordering::SeqCst) {
-            break;
-        }
-
-        match serde_json::from_str::<Cmd>(&line) {
-            Ok(c) => {
-                if let Some(ref expected) = token_env {
-                    if c.token.as_deref() != Some(expected.as_str()) {
-                        let resp = Resp {
-                            ok: false,
-                            msg: "unauthorized".into(),
-                            items: None,
-                        };
-                        let j = serde_json::to_string(&resp)? + "\n";
-                        let _ = w.write_all(j.as_bytes()).await;
-                        continue;
-                    }
+pub(crate) async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+    match ticker {
+        Some(t) => {
+            t.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// The address for an `--http` / `--mpd` value: host:port, or a bare port on localhost.
+pub fn listen_addr(s: &str) -> String {
+    match s.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => s.to_string(),
+    }
+}
+
+pub(crate) fn ticker_every(period: Duration) -> tokio::time::Interval {
+    let mut i = tokio::time::interval(period);
+    i.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    i
+}
+
+/// Send a subscriber whatever changed since `seen`.
+async fn push_events<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    shared: &Shared,
+    sub: Subscription,
+    seen: &mut Option<LiveState>,
+) -> std::io::Result<()> {
+    for event in changed_events(shared, seen).await {
+        let line = if sub.jsonrpc {
+            jsonrpc::notification("event", &event)
+        } else {
+            serde_json::to_string(&EventLine {
+                v: PROTOCOL_VERSION,
+                event,
+            })?
+        };
+        w.write_all((line + "\n").as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// The events since `seen`, which then becomes the current state.
+pub(crate) async fn changed_events(shared: &Shared, seen: &mut Option<LiveState>) -> Vec<Event> {
+    let now = live_state(shared).await;
+    let events = now.events_since(seen.as_ref());
+    *seen = Some(now);
+    events
+}
+
+pub(crate) async fn live_state(shared: &Shared) -> LiveState {
+    let mut pl = shared.player.lock().await;
+    LiveState {
+        status: pl.status().await.unwrap_or_default(),
+        queue: pl.list(),
+        history: pl.history(),
+        playlists: shared.playlists.names(),
+    }
+}
+
+// Answer one request line, in either protocol format.
+async fn respond(line: &str, shared: &Shared, session: &mut Session) -> Response {
+    let incoming = match parse_request(line) {
+        Ok(i) => i,
+        Err(f) => return versioned(f.into(), None),
+    };
+    session.jsonrpc = false;
+    let resp = dispatch(incoming.request, incoming.token.as_deref(), shared, session).await;
+    versioned(resp, incoming.id)
+}
+
+// Answer a JSON-RPC line; `None` when it held only notifications.
+async fn respond_jsonrpc(line: &str, shared: &Shared, session: &mut Session) -> Option<String> {
+    let message = match jsonrpc::parse_message(line) {
+        Ok(m) => m,
+        Err(reply) => return jsonrpc::encode(false, vec![reply]),
+    };
+    session.jsonrpc = true;
+    let mut replies = Vec::new();
+    for entry in message.entries {
+        match entry {
+            Err(reply) => replies.push(reply),
+            Ok(call) => {
+                // notifications run all the same, their result is just not sent
+                let resp = dispatch(call.request, call.token.as_deref(), shared, session).await;
+                if let Some(id) = call.id {
+                    replies.push(RpcResponse::from_response(resp, id));
                 }
+            }
+        }
+    }
+    jsonrpc::encode(message.batch, replies)
+}
 
-                let mut pl = player.lock().await;
-                let res = match c.cmd.as_str() {
-                    "play" => {
-                        if let Some(u) = c.arg.as_deref() {
-                            // block insecure http unless explicitly allowed via env
-                            if u.starts_with("http://")
-                                && !std::env::var("APPLE_ALLOW_INSECURE")
-                                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-                                    .unwrap_or(false)
-                            {
-                                Resp {
-                            ok: false,
-                            msg: "Refusing insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow".into(),
-                            items: None,
-                        }
-                            } else if u.starts_with("https://") {
-                                if let Err(e) = validate_https_url(u).await {
-                                    Resp {
-                                        ok: false,
-                                        msg: format!("url validation failed: {}", e),
-                                        items: None,
-                                    }
-                                } else {
-                                    let _ = pl.play_item(u).await;
-                                    Resp {
-                                        ok: true,
-                                        msg: "playing".into(),
-                                        items: None,
-                                    }
-                                }
-                            } else {
-                                // allow other schemes (file://, etc.) without validation
-                                let _ = pl.play_item(u).await;
-                                Resp {
-                                    ok: true,
-                                    msg: "playing".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "pause" => {
-                        let _ = pl.adapter_mut().pause().await;
-                        Resp {
-                            ok: true,
-                            msg: "paused".into(),
-                            items: None,
-                        }
-                    }
-                    "enqueue" => {
-                        if let Some(item) = c.arg.as_deref() {
-                            if item.starts_with("http://")
-                                && !std::env::var("APPLE_ALLOW_INSECURE")
-                                    .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
-                                    .unwrap_or(false)
-                            {
-                                Resp {
-                            ok: false,
-                            msg: "Refusing insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow".into(),
-                            items: None,
-                        }
-                            } else if item.starts_with("https://") {
-                                if let Err(e) = validate_https_url(item).await {
-                                    Resp {
-                                        ok: false,
-                                        msg: format!("url validation failed: {}", e),
-                                        items: None,
-                                    }
-                                } else {
-                                    pl.enqueue(item.to_string());
-                                    Resp {
-                                        ok: true,
-                                        msg: "enqueued".into(),
-                                        items: None,
-                                    }
-                                }
-                            } else {
-                                pl.enqueue(item.to_string());
-                                Resp {
-                                    ok: true,
-                                    msg: "enqueued".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "next" => {
-                        if let Some(it) = pl.next_item() {
-                            let _ = pl.play_item(&it).await;
-                            Resp {
-                                ok: true,
-                                msg: format!("playing {}", it),
-                                items: None,
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "queue empty".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "status" => {
-                        let s = pl
-                            .adapter_mut()
-                            .status()
-                            .await
-                            .unwrap_or_else(|e| format!("err: {}", e));
-                        Resp {
-                            ok: true,
-                            msg: s,
-                            items: None,
-                        }
-                    }
-                    "list" => Resp {
-                        ok: true,
-                        msg: "ok".into(),
-                        items: Some(pl.list()),
-                    },
-                    "artist_info" => {
-                        if let Some(artist_id) = c.arg.as_deref() {
-                            let info = pl
-                                .adapter_mut()
-                                .artist_info(artist_id)
-                                .await
-                                .unwrap_or_else(|e| format!("err: {}", e));
-                            // split lines into items for structured response
-                            let items = info.lines().map(|s| s.to_string()).collect();
-                            Resp {
-                                ok: true,
-                                msg: "artist info".into(),
-                                items: Some(items),
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "artist_discography" => {
-                        if let Some(artist_id) = c.arg.as_deref() {
-                            let disc = pl
-                                .adapter_mut()
-                                .artist_discography(artist_id)
-                                .await
-                                .unwrap_or_else(|e| format!("err: {}", e));
-                            let items = if disc.is_empty() {
-                                vec![]
-                            } else {
-                                disc.lines().map(|s| s.to_string()).collect()
-                            };
-                            Resp {
-                                ok: true,
-                                msg: "discography".into(),
-                                items: Some(items),
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing arg".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "volume_up" => {
-                        let _ = pl.adapter_mut().volume_up().await;
-                        Resp {
-                            ok: true,
-                            msg: "volume up".into(),
-                            items: None,
-                        }
-                    }
-                    "volume_down" => {
-                        let _ = pl.adapter_mut().volume_down().await;
-                        Resp {
-                            ok: true,
-                            msg: "volume down".into(),
-                            items: None,
-                        }
-                    }
-                    "set_volume" => {
-                        if let Some(vol_str) = c.arg.as_deref() {
-                            if let Ok(volume) = vol_str.parse::<u8>() {
-                                let _ = pl.adapter_mut().set_volume(volume).await;
-                                Resp {
-                                    ok: true,
-                                    msg: format!("volume set to {}", volume),
-                                    items: None,
-                                }
-                            } else {
-                                Resp {
-                                    ok: false,
-                                    msg: "invalid volume".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing volume".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "mute" => {
-                        let _ = pl.adapter_mut().mute().await;
-                        Resp {
-                            ok: true,
-                            msg: "muted".into(),
-                            items: None,
-                        }
-                    }
-                    "unmute" => {
-                        let _ = pl.adapter_mut().unmute().await;
-                        Resp {
-                            ok: true,
-                            msg: "unmuted".into(),
-                            items: None,
-                        }
-                    }
-                    "seek_forward" => {
-                        let seconds = c.arg.as_deref().and_then(|s| s.parse().ok()).unwrap_or(10);
-                        let _ = pl.adapter_mut().seek_forward(seconds).await;
-                        Resp {
-                            ok: true,
-                            msg: format!("seek forward {} seconds", seconds),
-                            items: None,
-                        }
-                    }
-                    "seek_backward" => {
-                        let seconds = c.arg.as_deref().and_then(|s| s.parse().ok()).unwrap_or(10);
-                        let _ = pl.adapter_mut().seek_backward(seconds).await;
-                        Resp {
-                            ok: true,
-                            msg: format!("seek backward {} seconds", seconds),
-                            items: None,
-                        }
-                    }
-                    "seek_to" => {
-                        if let Some(secs_str) = c.arg.as_deref() {
-                            if let Ok(seconds) = secs_str.parse::<u64>() {
-                                let _ = pl.adapter_mut().seek_to(seconds).await;
-                                Resp {
-                                    ok: true,
-                                    msg: format!("seek to {} seconds", seconds),
-                                    items: None,
-                                }
-                            } else {
-                                Resp {
-                                    ok: false,
-                                    msg: "invalid seconds".into(),
-                                    items: None,
-                                }
-                            }
-                        } else {
-                            Resp {
-                                ok: false,
-                                msg: "missing seconds".into(),
-                                items: None,
-                            }
-                        }
-                    }
-                    "position" => {
-                        let pos = pl.adapter_mut().get_position().await.unwrap_or(0);
-                        Resp {
-                            ok: true,
-                            msg: pos.to_string(),
-                            items: None,
-                        }
-                    }
-                    "duration" => {
-                        let dur = pl.adapter_mut().get_duration().await.unwrap_or(0);
-                        Resp {
-                            ok: true,
-                            msg: dur.to_string(),
-                            items: None,
-                        }
+// Check the token and run a request, whichever framing it came in.
+pub(crate) async fn dispatch(
+    request: std::result::Result<Request, Failure>,
+    given_token: Option<&str>,
+    shared: &Shared,
+    session: &mut Session,
+) -> Response {
+    let token = shared.token.as_deref();
+    let authorized = |given: Option<&str>| match session.peer {
+        Peer::Unchecked => token.is_none() || given == token,
+        Peer::Allowed => true,
+        Peer::Refused => token.is_some() && given == token,
+    };
+    match request {
+        Err(f) => f.into(),
+        // hello works without the token so clients can find out that they need one
+        Ok(Request::Hello { .. }) => hello(shared, !authorized(None)).await,
+        Ok(_) if !authorized(given_token) => {
+            Response::fail(ErrorCode::Unauthorized, "unauthorized")
+        }
+        Ok(Request::Subscribe { tick_ms }) => {
+            session.events = Some(Subscription {
+                tick: (tick_ms > 0).then(|| Duration::from_millis(tick_ms).max(MIN_TICK)),
+                jsonrpc: session.jsonrpc,
+            });
+            Response::ok("subscribed")
+        }
+        Ok(Request::Unsubscribe) => {
+            session.events = None;
+            Response::ok("unsubscribed")
+        }
+        Ok(request) => {
+            let changes_state = request.changes_state();
+            let changes_playlists = request.changes_playlists();
+            let resp = {
+                let mut pl = shared.player.lock().await;
+                execute(&mut pl, &shared.playlists.store, request).await
+            };
+            if changes_playlists {
+                shared.playlists.refresh();
+            }
+            if changes_state {
+                shared.changes.send_replace(());
+            }
+            resp
+        }
+    }
+}
+
+fn versioned(mut resp: Response, id: Option<u64>) -> Response {
+    resp.v = Some(PROTOCOL_VERSION);
+    resp.id = id;
+    resp
+}
+
+async fn hello(shared: &Shared, auth_required: bool) -> Response {
+    let mut capabilities = vec!["legacy".to_string(), "jsonrpc".to_string()];
+    if shared.player.lock().await.adapter_mut().subscribe().is_ok() {
+        capabilities.push("events".into());
+    }
+    if shared.library.exists() {
+        capabilities.push("library".into());
+    }
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        server: format!("apple {}", env!("CARGO_PKG_VERSION")),
+        auth_required,
+        commands: COMMANDS.iter().map(|c| c.to_string()).collect(),
+        capabilities,
+    };
+    Response {
+        hello: Some(hello),
+        ..Response::ok(format!("apple daemon, protocol v{}", PROTOCOL_VERSION))
+    }
+}
+
+async fn execute(pl: &mut Player, store: &PlaylistStore, request: Request) -> Response {
+    match request {
+        Request::Hello { .. } => Response::ok("hello"),
+        Request::Play { uri } => match check_item(&uri).await {
+            Ok(()) => {
+                let _ = pl.play_item(&uri).await;
+                Response::ok("playing")
+            }
+            Err(f) => f.into(),
+        },
+        Request::Pause => {
+            let _ = pl.adapter_mut().pause().await;
+            Response::ok("paused")
+        }
+        Request::Enqueue { uri } => match check_item(&uri).await {
+            Ok(()) => {
+                let track = pl.track_for(&uri);
+                pl.enqueue(track);
+                Response::ok("enqueued")
+            }
+            Err(f) => f.into(),
+        },
+        Request::InsertNext { uri } => match check_item(&uri).await {
+            Ok(()) => {
+                let track = pl.track_for(&uri);
+                pl.insert_next(track);
+                Response::ok("inserted next")
+            }
+            Err(f) => f.into(),
+        },
+        Request::Next => match pl.next_item() {
+            Some(it) => {
+                let _ = pl.play_track(it.clone()).await;
+                Response::ok(format!("playing {}", it))
+            }
+            None => Response::fail(ErrorCode::NotFound, "queue empty"),
+        },
+        Request::Prev => match pl.previous().await {
+            Ok(Some(it)) => Response::ok(format!("playing {}", it)),
+            // nothing in the local history: let the adapter try its own playlist
+            Ok(None) => match pl.adapter_mut().prev().await {
+                Ok(()) => Response::ok("prev"),
+                Err(e) => Response::error(e),
+            },
+            Err(e) => Response::error(e),
+        },
+        Request::Clear => {
+            pl.clear();
+            Response::ok("queue cleared")
+        }
+        Request::List => Response::ok("ok").with_tracks(pl.list()),
+        Request::History => Response::ok("ok").with_tracks(pl.history()),
+        Request::Status => match pl.status().await {
+            Ok(st) => Response {
+                status: Some(st.clone()),
+                ..Response::ok(st.to_string())
+            },
+            Err(e) => Response::error(e),
+        },
+        Request::Position => {
+            let pos = pl.adapter_mut().get_position().await.unwrap_or(0);
+            Response::ok(pos.to_string())
+        }
+        Request::Duration => {
+            let dur = pl.adapter_mut().get_duration().await.unwrap_or(0);
+            Response::ok(dur.to_string())
+        }
+        Request::Remove { index } => match pl.remove(index) {
+            Some(t) => Response::ok(format!("removed {}", t)),
+            None => Response::fail(ErrorCode::NotFound, "index out of range"),
+        },
+        Request::Move { from, to } => {
+            if pl.move_item(from, to) {
+                Response::ok(format!("moved {} to {}", from, to))
+            } else {
+                Response::fail(ErrorCode::NotFound, "index out of range")
+            }
+        }
+        Request::PlayIndex { index } => match pl.jump_to(index) {
+            Some(it) => {
+                let _ = pl.play_track(it.clone()).await;
+                Response::ok(format!("playing {}", it))
+            }
+            None => Response::fail(ErrorCode::NotFound, "index out of range"),
+        },
+        Request::Repeat { mode } => {
+            pl.set_repeat(mode);
+            Response::ok(format!("repeat {}", mode))
+        }
+        Request::Shuffle { on, seed } => {
+            pl.set_shuffle(on, seed);
+            Response::ok(format!("shuffle {}", if on { "on" } else { "off" }))
+        }
+        Request::QueueLoad { path } => queue_load(pl, store, &path),
+        Request::QueueSave { path } => queue_save(pl, store, &path),
+        Request::EnqueueTracks { tracks } => enqueue_tracks(pl, tracks),
+        Request::Search { query } => search(pl, &query).await,
+        Request::EnqueueId { ids } => enqueue_ids(pl, &ids),
+        Request::PlayIds { ids } => play_ids(pl, &ids).await,
+        Request::PlaylistList => match store.names() {
+            Ok(names) => Response::ok(format!("{} playlist(s)", names.len())).with_items(names),
+            Err(e) => Response::error(e),
+        },
+        Request::PlaylistShow { name } => match store.tracks(&name) {
+            Ok(tracks) => {
+                Response::ok(format!("{}: {} item(s)", name, tracks.len())).with_tracks(tracks)
+            }
+            Err(e) => Response::error(e),
+        },
+        Request::PlaylistCreate { name } => match store.create(&name) {
+            Ok(()) => Response::ok(format!("created {}", name)),
+            Err(e) => Response::error(e),
+        },
+        Request::PlaylistRename { from, to } => match store.rename(&from, &to) {
+            Ok(()) => Response::ok(format!("renamed {} to {}", from, to)),
+            Err(e) => Response::error(e),
+        },
+        Request::PlaylistDelete { name } => match store.delete(&name) {
+            Ok(()) => Response::ok(format!("deleted {}", name)),
+            Err(e) => Response::error(e),
+        },
+        Request::PlaylistAdd { name, uri } => {
+            let track = match uri {
+                Some(uri) => {
+                    if let Err(f) = check_item(&uri).await {
+                        return f.into();
                     }
-                    _ => Resp {
-                        ok: false,
-                        msg: "unknown cmd".into(),
-                        items: None,
-                    },
-                };
-                let j = serde_json::to_string(&res)? + "\n";
-                let _ = w.write_all(j.as_bytes()).await;
+                    pl.track_for(&uri)
+                }
+                None => match pl.current() {
+                    Some(t) => t.clone(),
+                    None => return Response::fail(ErrorCode::NotFound, "nothing playing"),
+                },
+            };
+            match store.append(&name, &[track]) {
+                Ok(n) => Response::ok(format!("added to {} ({} items)", name, n)),
+                Err(e) => Response::error(e),
+            }
+        }
+        Request::PlaylistSave { name } => {
+            let tracks = pl.list();
+            match store.save(&name, &tracks) {
+                Ok(()) => Response::ok(format!("saved {} item(s) to {}", tracks.len(), name)),
+                Err(e) => Response::error(e),
+            }
+        }
+        Request::PlaylistLoad { name } => match store.tracks(&name) {
+            Ok(tracks) => {
+                let n = tracks.len();
+                for t in tracks {
+                    pl.enqueue(t);
+                }
+                Response::ok(format!("queued {} item(s) from {}", n, name))
+            }
+            Err(e) => Response::error(e),
+        },
+        Request::ArtistInfo { artist_id } => {
+            let info = pl
+                .adapter_mut()
+                .artist_info(&artist_id)
+                .await
+                .unwrap_or_else(|e| format!("err: {}", e));
+            // split lines into items for structured response
+            let items = info.lines().map(|s| s.to_string()).collect();
+            Response::ok("artist info").with_items(items)
+        }
+        Request::ArtistDiscography { artist_id } => {
+            let disc = pl
+                .adapter_mut()
+                .artist_discography(&artist_id)
+                .await
+                .unwrap_or_else(|e| format!("err: {}", e));
+            let items = disc.lines().map(|s| s.to_string()).collect();
+            Response::ok("discography").with_items(items)
+        }
+        Request::VolumeUp => {
+            let _ = pl.adapter_mut().volume_up().await;
+            Response::ok("volume up")
+        }
+        Request::VolumeDown => {
+            let _ = pl.adapter_mut().volume_down().await;
+            Response::ok("volume down")
+        }
+        Request::SetVolume { volume } => {
+            let _ = pl.adapter_mut().set_volume(volume).await;
+            Response::ok(format!("volume set to {}", volume))
+        }
+        Request::Mute => {
+            let _ = pl.adapter_mut().mute().await;
+            Response::ok("muted")
+        }
+        Request::Unmute => {
+            let _ = pl.adapter_mut().unmute().await;
+            Response::ok("unmuted")
+        }
+        Request::SeekForward { seconds } => {
+            let _ = pl.adapter_mut().seek_forward(seconds).await;
+            Response::ok(format!("seek forward {} seconds", seconds))
+        }
+        Request::SeekBackward { seconds } => {
+            let _ = pl.adapter_mut().seek_backward(seconds).await;
+            Response::ok(format!("seek backward {} seconds", seconds))
+        }
+        Request::SeekTo { seconds } => {
+            let _ = pl.adapter_mut().seek_to(seconds).await;
+            Response::ok(format!("seek to {} seconds", seconds))
+        }
+        // handled per connection in `dispatch`
+        Request::Subscribe { .. } | Request::Unsubscribe => {
+            Response::fail(ErrorCode::Failed, "subscribe needs a connection")
+        }
+    }
+}
+
+// A playlist file path from a client, resolved inside the playlist dir.
+fn playlist_file(store: &PlaylistStore, path: &str) -> Result<PathBuf, Failure> {
+    store
+        .file_path(path)
+        .map_err(|e| Failure::new(ErrorCode::Forbidden, format!("{:#}", e)))
+}
+
+// Append a playlist file (relative to the playlist dir) to the queue. Entries that fail the
+// same checks as `enqueue` are skipped.
+fn queue_load(pl: &mut Player, store: &PlaylistStore, path: &str) -> Response {
+    let path = match playlist_file(store, path) {
+        Ok(p) => p,
+        Err(f) => return f.into(),
+    };
+    match crate::playlist::load_playlist(&path, pl.source()) {
+        Ok(tracks) => enqueue_tracks(pl, tracks),
+        Err(e) => Response::error(e),
+    }
+}
+
+// Queue tracks parsed from a playlist file, keeping their metadata. The id and source are
+// redone here since a client may have read the file without knowing the adapter. Entries only
+// get the scheme check: probing each https URL would hold the player lock for seconds per
+// entry, and a dead stream fails when it is played anyway.
+fn enqueue_tracks(pl: &mut Player, tracks: Vec<Track>) -> Response {
+    let mut loaded = Vec::new();
+    let mut skipped = Vec::new();
+    for t in tracks {
+        let t = Track {
+            title: t.title,
+            artist: t.artist,
+            album: t.album,
+            duration: t.duration,
+            ..pl.track_for(&t.uri)
+        };
+        match check_scheme(&t.uri) {
+            Ok(()) => {
+                loaded.push(t.clone());
+                pl.enqueue(t);
             }
-            Err(_) => {
-                let _ = w
-                    .write_all(b"{\"ok\":false,\"msg\":\"parse error\"}\n")
-                    .await;
+            Err(f) => skipped.push(format!("{}: {}", t.uri, f.msg)),
+        }
+    }
+    let mut msg = format!("loaded {} item(s)", loaded.len());
+    if !skipped.is_empty() {
+        msg += &format!("; skipped {}: {}", skipped.len(), skipped.join("; "));
+    }
+    Response::ok(msg).with_tracks(loaded)
+}
+
+fn queue_save(pl: &Player, store: &PlaylistStore, path: &str) -> Response {
+    let path = match playlist_file(store, path) {
+        Ok(p) => p,
+        Err(f) => return f.into(),
+    };
+    if let Some(dir) = path.parent() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            return Response::error(anyhow::Error::new(e).context("creating playlist dir"));
+        }
+    }
+    let tracks = pl.list();
+    match crate::playlist::save_playlist(&path, &tracks) {
+        Ok(()) => Response::ok(format!("saved {} item(s)", tracks.len())),
+        Err(e) => Response::error(e),
+    }
+}
+
+// Structured search when the adapter supports it (local library), plain text otherwise.
+async fn search(pl: &mut Player, query: &str) -> Response {
+    match pl.adapter_mut().search_tracks(query).await {
+        Ok(tracks) => Response::ok(format!("{} result(s)", tracks.len())).with_tracks(tracks),
+        Err(_) => match pl.adapter_mut().search(query).await {
+            Ok(text) => Response::ok(text),
+            Err(e) => Response::error(e),
+        },
+    }
+}
+
+// Queue library tracks (ids as returned by `search`) in the given order.
+fn enqueue_ids(pl: &mut Player, ids: &[String]) -> Response {
+    match library_tracks(ids) {
+        Ok(tracks) => {
+            let msg = match tracks.as_slice() {
+                [t] => format!("queued {}", t),
+                _ => format!("queued {} track(s)", tracks.len()),
+            };
+            for t in tracks {
+                pl.enqueue(t);
             }
+            Response::ok(msg)
+        }
+        Err(f) => f.into(),
+    }
+}
+
+// Play the first library track now and queue the rest next, e.g. a whole album picked in the
+// TUI's library browser.
+async fn play_ids(pl: &mut Player, ids: &[String]) -> Response {
+    let tracks = match library_tracks(ids) {
+        Ok(t) => t,
+        Err(f) => return f.into(),
+    };
+    let msg = format!("playing {} (+{} queued)", tracks[0], tracks.len() - 1);
+    match pl.play_tracks(tracks).await {
+        Ok(()) => Response::ok(msg),
+        Err(e) => Response::error(e),
+    }
+}
+
+// Look up ids in the library index; fails on the first unknown one.
+fn library_tracks(ids: &[String]) -> std::result::Result<Vec<Track>, Failure> {
+    if ids.is_empty() {
+        return Err(Failure::new(ErrorCode::InvalidParams, "no ids given"));
+    }
+    let lib = crate::library::load_cached()
+        .map_err(|e| Failure::new(ErrorCode::Failed, format!("{:#}", e)))?;
+    ids.iter()
+        .map(|id| {
+            lib.by_id(id).map(|e| e.track.clone()).ok_or_else(|| {
+                Failure::new(
+                    ErrorCode::NotFound,
+                    format!("no library track with id {}", id),
+                )
+            })
+        })
+        .collect()
+}
+
+// Refuse plain http unless allowed, validate https; anything else (paths, file://, ...) passes.
+async fn check_item(item: &str) -> std::result::Result<(), Failure> {
+    check_scheme(item)?;
+    if item.starts_with("https://") {
+        if let Err(e) = validate_https_url(item).await {
+            return Err(Failure::new(
+                ErrorCode::Failed,
+                format!("url validation failed: {}", e),
+            ));
         }
     }
     Ok(())
 }
 
+// The part of `check_item` that needs no network: refuse plain http unless allowed.
+fn check_scheme(item: &str) -> std::result::Result<(), Failure> {
+    if item.starts_with("http://")
+        && !std::env::var("APPLE_ALLOW_INSECURE")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    {
+        return Err(Failure::new(
+            ErrorCode::Forbidden,
+            "Refusing insecure http URL; set APPLE_ALLOW_INSECURE=1 to allow",
+        ));
+    }
+    Ok(())
+}
+
 async fn validate_https_url(url: &str) -> anyhow::Result<()> {
     // Only validate https URLs
     if !url.starts_with("https://") {
@@ -890,3 +1482,647 @@ async fn validate_https_url(url: &str) -> anyhow::Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::{PlaybackAdapter, PlaybackStatus};
+    use crate::player::RepeatMode;
+
+    // Adapter that records what it was asked to play and seek to and lets the test emit events.
+    struct FakeAdapter {
+        played: Arc<std::sync::Mutex<Vec<String>>>,
+        seeks: Arc<std::sync::Mutex<Vec<u64>>>,
+        events: broadcast::Sender<PlaybackEvent>,
+    }
+
+    impl FakeAdapter {
+        fn new(played: Arc<std::sync::Mutex<Vec<String>>>) -> Self {
+            Self {
+                played,
+                seeks: Arc::default(),
+                events: broadcast::channel(16).0,
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl PlaybackAdapter for FakeAdapter {
+        async fn search(&mut self, _query: &str) -> Result<String> {
+            Ok("".into())
+        }
+        async fn play(&mut self, track_id: Option<&str>) -> Result<()> {
+            if let Some(id) = track_id {
+                self.played.lock().unwrap().push(id.to_string());
+                let _ = self.events.send(PlaybackEvent::Started);
+            }
+            Ok(())
+        }
+        async fn pause(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn next(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn prev(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn status(&mut self) -> Result<PlaybackStatus> {
+            Ok(PlaybackStatus::default())
+        }
+        async fn seek_to(&mut self, seconds: u64) -> Result<()> {
+            self.seeks.lock().unwrap().push(seconds);
+            Ok(())
+        }
+        fn subscribe(&self) -> Result<broadcast::Receiver<PlaybackEvent>> {
+            Ok(self.events.subscribe())
+        }
+    }
+
+    async fn wait_for_played(played: &std::sync::Mutex<Vec<String>>, n: usize) {
+        for _ in 0..100 {
+            if played.lock().unwrap().len() >= n {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "expected {} played items, got {:?}",
+            n,
+            played.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_advances_when_track_ends() {
+        let played = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let adapter = FakeAdapter::new(played.clone());
+        let events = adapter.events.clone();
+        let mut player = Player::new(Box::new(adapter));
+        player.enqueue(player.track_for("one"));
+        player.enqueue(player.track_for("two"));
+        let player = Arc::new(tokio::sync::Mutex::new(player));
+
+        let handle = spawn_auto_advance(player.clone())
+            .await
+            .expect("fake adapter emits events");
+
+        // a replaced item must not advance the queue
+        events.send(PlaybackEvent::Ended(EndReason::Stop)).unwrap();
+        events.send(PlaybackEvent::Ended(EndReason::Eof)).unwrap();
+        wait_for_played(&played, 1).await;
+        let queued = player.lock().await.list();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].uri, "two");
+
+        events
+            .send(PlaybackEvent::Ended(EndReason::Error("boom".into())))
+            .unwrap();
+        wait_for_played(&played, 2).await;
+
+        // queue is empty now; further ends are ignored
+        events.send(PlaybackEvent::Ended(EndReason::Eof)).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*played.lock().unwrap(), vec!["one", "two"]);
+        assert!(player.lock().await.list().is_empty());
+
+        handle.abort();
+    }
+
+    #[tokio::test]
+    async fn saved_state_is_restored_and_resumed() {
+        let dir = std::env::temp_dir().join(format!("apple-daemon-state-{}", std::process::id()));
+        let path = dir.join("state.json");
+
+        let played = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut player = Player::new(Box::new(FakeAdapter::new(played.clone())));
+        player.enqueue(player.track_for("two"));
+        player.play_item("one").await.unwrap();
+        player.set_repeat(RepeatMode::All);
+        let player = tokio::sync::Mutex::new(player);
+        let mut st = snapshot(&player).await;
+        st.position = Some(42);
+        save_state(&path, &st).unwrap();
+
+        // without resume the saved track goes back to the head of the queue
+        let fresh =
+            tokio::sync::Mutex::new(Player::new(Box::new(FakeAdapter::new(Arc::default()))));
+        restore_state(&fresh, &path, false).await;
+        let pl = fresh.lock().await;
+        let queued: Vec<_> = pl.list().into_iter().map(|t| t.uri).collect();
+        assert_eq!(queued, vec!["one", "two"]);
+        assert_eq!(pl.repeat(), RepeatMode::All);
+        drop(pl);
+
+        // with resume it plays again and seeks to the saved position
+        let played = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let adapter = FakeAdapter::new(played.clone());
+        let seeks = adapter.seeks.clone();
+        let resumed = tokio::sync::Mutex::new(Player::new(Box::new(adapter)));
+        restore_state(&resumed, &path, true).await;
+        assert_eq!(*played.lock().unwrap(), vec!["one"]);
+        assert_eq!(*seeks.lock().unwrap(), vec![42]);
+        assert_eq!(resumed.lock().await.current().unwrap().uri, "one");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn state_is_saved_when_the_player_changes() {
+        let dir = std::env::temp_dir().join(format!("apple-daemon-save-{}", std::process::id()));
+        let path = dir.join("state.json");
+        let player = Arc::new(tokio::sync::Mutex::new(Player::new(Box::new(
+            FakeAdapter::new(Arc::default()),
+        ))));
+        let changes = watch::channel(()).0;
+        // the interval's first tick is immediate; after that only changes can save
+        let saver = tokio::spawn(save_state_on_change(
+            player.clone(),
+            path.clone(),
+            changes.subscribe(),
+            Duration::from_secs(3600),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        {
+            let mut pl = player.lock().await;
+            let t = pl.track_for("one");
+            pl.enqueue(t);
+        }
+        changes.send_replace(());
+        let saved = async {
+            loop {
+                match load_state(&path) {
+                    Some(st) if !st.queue.is_empty() => break st,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        };
+        let st = tokio::time::timeout(Duration::from_secs(5), saved)
+            .await
+            .expect("state saved after the change");
+        assert_eq!(st.queue[0].uri, "one");
+
+        saver.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // A request in the original `{cmd, arg}` form.
+    async fn run(pl: &mut Player, store: &PlaylistStore, cmd: &str, arg: Option<&str>) -> Response {
+        execute(pl, store, Request::from_legacy(cmd, arg).unwrap()).await
+    }
+
+    fn shared() -> Shared {
+        Shared::for_test(Player::new(Box::new(FakeAdapter::new(Arc::default()))))
+    }
+
+    #[tokio::test]
+    async fn playlist_names_are_cached_until_a_playlist_changes() {
+        let shared = shared();
+        let session = &mut Session::default();
+        let mut send = async |request| {
+            let r = dispatch(Ok(request), Some("secret"), &shared, session).await;
+            assert!(r.ok, "{}", r.msg);
+        };
+        send(Request::PlaylistCreate { name: "mix".into() }).await;
+        assert_eq!(live_state(&shared).await.playlists, vec!["mix"]);
+
+        // written behind the daemon's back: seen on the next playlist change
+        shared.playlists.store.save("other", &[]).unwrap();
+        assert_eq!(live_state(&shared).await.playlists, vec!["mix"]);
+        send(Request::PlaylistDelete { name: "mix".into() }).await;
+        assert_eq!(live_state(&shared).await.playlists, vec!["other"]);
+        send(Request::PlaylistDelete {
+            name: "other".into(),
+        })
+        .await;
+        assert!(live_state(&shared).await.playlists.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_echo_ids_and_check_the_token() {
+        let shared = shared();
+        let session = &mut Session::default();
+
+        let r = respond(
+            r#"{"v":1,"id":4,"cmd":"hello","params":{"version":1}}"#,
+            &shared,
+            session,
+        )
+        .await;
+        assert_eq!(r.id, Some(4));
+        let hello = r.hello.unwrap();
+        assert!(hello.auth_required);
+        assert!(hello.commands.iter().any(|c| c == "enqueue"));
+        assert!(hello.capabilities.iter().any(|c| c == "events"));
+
+        let r = respond(r#"{"v":1,"id":5,"cmd":"list"}"#, &shared, session).await;
+        assert_eq!((r.id, r.code), (Some(5), Some(ErrorCode::Unauthorized)));
+
+        let line = r#"{"v":1,"id":6,"token":"secret","cmd":"enqueue","params":{"uri":"a.mp3"}}"#;
+        assert!(respond(line, &shared, session).await.ok);
+        // the original format still works
+        let r = respond(
+            r#"{"cmd":"list","arg":null,"token":"secret"}"#,
+            &shared,
+            session,
+        )
+        .await;
+        assert_eq!(r.items, Some(vec!["a.mp3".to_string()]));
+        let r = respond(
+            r#"{"cmd":"remove","arg":"3","token":"secret"}"#,
+            &shared,
+            session,
+        )
+        .await;
+        assert_eq!(r.code, Some(ErrorCode::NotFound));
+    }
+
+    #[tokio::test]
+    async fn jsonrpc_batches_and_notifications() {
+        let shared = shared();
+        let rpc = |line: &'static str| {
+            let shared = &shared;
+            async move {
+                respond_jsonrpc(line, shared, &mut Session::default())
+                    .await
+                    .map(|j| serde_json::from_str::<serde_json::Value>(&j).unwrap())
+            }
+        };
+
+        // a notification runs but gets no reply
+        let r = rpc(
+            r#"{"jsonrpc":"2.0","method":"enqueue","params":{"uri":"a.mp3","token":"secret"}}"#,
+        )
+        .await;
+        assert_eq!(r, None);
+
+        let r = rpc(r#"[
+            {"jsonrpc":"2.0","id":1,"method":"list","params":{"token":"secret"}},
+            {"jsonrpc":"2.0","method":"clear","params":{"token":"secret"}},
+            {"jsonrpc":"2.0","id":"b","method":"remove","params":{"index":5,"token":"secret"}},
+            {"jsonrpc":"2.0","id":3,"method":"list"},
+            {"jsonrpc":"2.0","id":4,"method":"hello"},
+            {"jsonrpc":"2.0","id":5,"method":"dance"},
+            7
+        ]"#)
+        .await
+        .unwrap();
+        let replies = r.as_array().unwrap();
+        assert_eq!(replies.len(), 6);
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["items"], serde_json::json!(["a.mp3"]));
+        // the notification cleared the queue before the remove
+        assert_eq!(replies[1]["id"], "b");
+        assert_eq!(replies[1]["error"]["code"], -32002);
+        assert_eq!(replies[2]["error"]["data"]["code"], "unauthorized");
+        assert_eq!(replies[3]["result"]["hello"]["auth_required"], true);
+        assert_eq!(replies[4]["error"]["code"], jsonrpc::METHOD_NOT_FOUND);
+        assert_eq!(replies[5]["error"]["code"], jsonrpc::INVALID_REQUEST);
+        assert_eq!(replies[5]["id"], serde_json::Value::Null);
+
+        // only notifications: nothing to send back
+        let r = rpc(r#"[{"jsonrpc":"2.0","method":"next","params":{"token":"secret"}}]"#).await;
+        assert_eq!(r, None);
+        let r = rpc(r#"{"jsonrpc":"2.0","id":1,"method":"#).await.unwrap();
+        assert_eq!(r["error"]["code"], jsonrpc::PARSE_ERROR);
+    }
+
+    async fn next_json<R: tokio::io::AsyncBufRead + Unpin>(
+        lines: &mut tokio::io::Lines<R>,
+    ) -> serde_json::Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_the_state_then_changes() {
+        let shared = shared();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (_stop_tx, stop) = watch::channel(false);
+        tokio::spawn(handle_connection(
+            server,
+            shared.clone(),
+            stop,
+            None,
+            Peer::Unchecked,
+        ));
+        let (r, mut w) = tokio::io::split(client);
+        let mut lines = tokio::io::BufReader::new(r).lines();
+        let sub = r#"{"v":1,"id":1,"token":"secret","cmd":"subscribe","params":{"tick_ms":0}}"#;
+        w.write_all(format!("{}\n", sub).as_bytes()).await.unwrap();
+        let r = next_json(&mut lines).await;
+        assert_eq!((r["id"].as_u64(), r["ok"].as_bool()), (Some(1), Some(true)));
+        // the full state first
+        let mut first = Vec::new();
+        for _ in 0..8 {
+            first.push(
+                next_json(&mut lines).await["event"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        assert!(first.contains(&"queue".to_string()), "{:?}", first);
+
+        // a request on another connection is pushed as a change
+        let other = &mut Session::default();
+        let line = r#"{"v":1,"token":"secret","cmd":"enqueue","params":{"uri":"a.mp3"}}"#;
+        assert!(respond(line, &shared, other).await.ok);
+        let ev = next_json(&mut lines).await;
+        assert_eq!(ev["v"], 1);
+        assert_eq!(ev["event"], "queue");
+        assert_eq!(ev["tracks"][0]["uri"], "a.mp3");
+
+        // requests still work on the subscribed connection
+        let list = r#"{"v":1,"id":2,"token":"secret","cmd":"list"}"#;
+        w.write_all(format!("{}\n", list).as_bytes()).await.unwrap();
+        let r = next_json(&mut lines).await;
+        assert_eq!(r["id"], 2);
+        assert_eq!(r["items"][0], "a.mp3");
+    }
+
+    // Requests every transport must answer the same way.
+    async fn command_suite<S: AsyncRead + AsyncWrite>(stream: S) {
+        let (r, mut w) = tokio::io::split(stream);
+        let mut lines = tokio::io::BufReader::new(r).lines();
+        let mut ask = async |line: &str| {
+            w.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+            next_json(&mut lines).await
+        };
+
+        let r = ask(r#"{"v":1,"id":1,"cmd":"hello","params":{}}"#).await;
+        assert_eq!(
+            (r["id"].as_u64(), r["hello"]["version"].as_u64()),
+            (Some(1), Some(1))
+        );
+        let r = ask(r#"{"v":1,"id":2,"cmd":"list"}"#).await;
+        assert_eq!(r["code"], "unauthorized");
+        let r = ask(r#"{"v":1,"id":3,"token":"secret","cmd":"enqueue","params":{"uri":"a.mp3"}}"#)
+            .await;
+        assert_eq!(r["ok"], true, "{}", r);
+        let r = ask(r#"{"cmd":"list","token":"secret"}"#).await;
+        assert_eq!(r["items"], serde_json::json!(["a.mp3"]));
+        let r = ask(r#"{"cmd":"move","arg":"0 5","token":"secret"}"#).await;
+        assert_eq!(r["code"], "not_found");
+        let r =
+            ask(r#"{"jsonrpc":"2.0","id":"q","method":"list","params":{"token":"secret"}}"#).await;
+        assert_eq!(r["result"]["items"], serde_json::json!(["a.mp3"]));
+        // a notification gets no reply, so the next line answers the next request
+        let r = ask(concat!(
+            r#"{"jsonrpc":"2.0","method":"clear","params":{"token":"secret"}}"#,
+            "\n",
+            r#"{"v":1,"id":4,"token":"secret","cmd":"list"}"#
+        ))
+        .await;
+        assert_eq!(
+            (r["id"].as_u64(), r["items"].as_array().map(Vec::len)),
+            (Some(4), Some(0))
+        );
+        let r = ask("not json").await;
+        assert_eq!(r["code"], "parse_error");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_transport_runs_the_command_suite() {
+        let path = std::env::temp_dir().join(format!("apple-test-{}.sock", std::process::id()));
+        let unix = || Transport::Unix(path.clone(), PeerAllowlist::default());
+        // a socket left behind by a daemon that is gone is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = unix().bind().await.unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let server = tokio::spawn(listener.serve(shared(), stop));
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // a live one is not
+        let err = unix().bind().await.err().unwrap();
+        assert!(err.to_string().contains("another daemon"), "{:#}", err);
+
+        command_suite(tokio::net::UnixStream::connect(&path).await.unwrap()).await;
+        // connections are independent
+        command_suite(tokio::net::UnixStream::connect(&path).await.unwrap()).await;
+
+        stop_tx.send_replace(true);
+        server.await.unwrap();
+        assert!(!path.exists());
+
+        // nor is anything that is not a socket
+        std::fs::write(&path, "keep me").unwrap();
+        assert!(unix().bind().await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sockets_are_private_from_the_start() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("apple-private-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = bind_private(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn allowlisted_peers_need_no_token() {
+        let peers = PeerAllowlist::parse(Some("1000, 1001"), Some("")).unwrap();
+        assert_eq!(peers.socket_access(), (0o666, None));
+        assert_eq!((peers.uids, peers.gids), (vec![1000, 1001], vec![]));
+        let group = PeerAllowlist::parse(None, Some("100")).unwrap();
+        assert_eq!(group.socket_access(), (0o660, Some(100)));
+        let groups = PeerAllowlist::parse(None, Some("100,101")).unwrap();
+        assert_eq!(groups.socket_access(), (0o666, None));
+        assert_eq!(PeerAllowlist::default().socket_access(), (0o600, None));
+        assert!(PeerAllowlist::parse(None, Some("wheel")).is_err());
+
+        // everyone in this process is the daemon's own user, so let its group in
+        let path = std::env::temp_dir().join(format!("apple-peers-{}.sock", std::process::id()));
+        let peers = PeerAllowlist {
+            uids: Vec::new(),
+            gids: vec![nix::unistd::getegid().as_raw()],
+        };
+        let listener = Transport::Unix(path.clone(), peers).bind().await.unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let server = tokio::spawn(listener.serve(shared(), stop));
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (r, mut w) = tokio::io::split(stream);
+        let mut lines = tokio::io::BufReader::new(r).lines();
+        w.write_all(b"{\"v\":1,\"cmd\":\"hello\",\"params\":{}}\n{\"v\":1,\"cmd\":\"list\"}\n")
+            .await
+            .unwrap();
+        let hello = next_json(&mut lines).await;
+        assert_eq!(hello["hello"]["auth_required"], false);
+        let list = next_json(&mut lines).await;
+        assert_eq!(list["ok"], true, "{}", list);
+        stop_tx.send_replace(true);
+        server.await.unwrap();
+
+        // other users need the token, even when none is set
+        let open = Shared {
+            token: None,
+            ..shared()
+        };
+        let refused = &mut Session {
+            peer: Peer::Refused,
+            ..Session::default()
+        };
+        let r = respond(r#"{"v":1,"cmd":"hello","params":{}}"#, &open, refused).await;
+        assert!(r.hello.unwrap().auth_required);
+        let r = respond(r#"{"v":1,"cmd":"list"}"#, &open, refused).await;
+        assert_eq!(r.code, Some(ErrorCode::Unauthorized));
+    }
+
+    #[tokio::test]
+    async fn tcp_transport_runs_the_command_suite() {
+        let listener = Transport::Tcp("127.0.0.1:0".into()).bind().await.unwrap();
+        let Listener::Tcp(l) = &listener else {
+            panic!("not a tcp listener");
+        };
+        let addr = l.local_addr().unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let server = tokio::spawn(listener.serve(shared(), stop));
+
+        command_suite(tokio::net::TcpStream::connect(addr).await.unwrap()).await;
+
+        stop_tx.send_replace(true);
+        server.await.unwrap();
+    }
+
+    // `Transport::Stdio` wires stdin and stdout into a `Single` listener; a pipe stands in here.
+    #[tokio::test]
+    async fn stdio_transport_runs_the_command_suite() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (_stop_tx, stop) = watch::channel(false);
+        let server = tokio::spawn(Listener::Single(Box::new(server)).serve(shared(), stop));
+
+        command_suite(client).await;
+        // the daemon is done once its only client hangs up
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_stdio_listener_ends_the_daemon() {
+        let (stop_tx, mut stop) = watch::channel(false);
+        let mut servers = tokio::task::JoinSet::new();
+        // say MPRIS lost the session bus
+        servers.spawn(async { ("MPRIS", false) });
+        let (client, server) = tokio::io::duplex(1024);
+        spawn_listener(
+            &mut servers,
+            Listener::Single(Box::new(server)),
+            shared(),
+            stop.clone(),
+        );
+        let waiting = tokio::spawn(async move { wait_for_exit(&mut servers, &mut stop).await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        // the stdio client going away does end it
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("daemon stopped with stdio")
+            .unwrap();
+        drop(stop_tx);
+    }
+
+    #[test]
+    fn listen_addrs_default_to_localhost() {
+        assert_eq!(listen_addr("8080"), "127.0.0.1:8080");
+        assert_eq!(listen_addr("0.0.0.0:80"), "0.0.0.0:80");
+    }
+
+    #[tokio::test]
+    async fn enqueued_tracks_keep_their_metadata() {
+        let store = PlaylistStore::new(std::env::temp_dir().join("apple-unused-playlists"));
+        let mut pl = Player::new(Box::new(FakeAdapter::new(Arc::default())));
+        // as a client parses a playlist file, without knowing the daemon's adapter
+        let mut song = Track::from_uri("one.mp3", "");
+        song.title = Some("One".into());
+        song.duration = Some(61);
+        let tracks = vec![
+            song,
+            Track::from_uri("http://example.com/two.mp3", ""),
+            // not probed over the network, so an unresolvable host is still queued
+            Track::from_uri("https://stream.invalid/three.mp3", ""),
+        ];
+
+        let r = execute(&mut pl, &store, Request::EnqueueTracks { tracks }).await;
+        assert!(r.ok, "{}", r.msg);
+        assert!(r.msg.contains("skipped 1"), "{}", r.msg);
+        let queued = pl.list();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].title.as_deref(), Some("One"));
+        assert_eq!(queued[0].duration, Some(61));
+        assert_eq!(queued[0].id, pl.track_for("one.mp3").id);
+        assert_eq!(queued[0].source, pl.source());
+
+        let arg = serde_json::to_string(&queued).unwrap();
+        let legacy = Request::from_legacy("enqueue_tracks", Some(&arg)).unwrap();
+        assert_eq!(legacy, Request::EnqueueTracks { tracks: queued });
+    }
+
+    #[tokio::test]
+    async fn playlist_commands_round_trip_through_the_queue() {
+        let dir =
+            std::env::temp_dir().join(format!("apple-daemon-playlists-{}", std::process::id()));
+        let store = PlaylistStore::new(dir.clone());
+        let mut pl = Player::new(Box::new(FakeAdapter::new(Arc::default())));
+
+        let r = run(&mut pl, &store, "playlist_create", Some("mix")).await;
+        assert!(r.ok, "{}", r.msg);
+        let r = run(&mut pl, &store, "playlist_add", Some("mix one.mp3")).await;
+        assert!(r.ok, "{}", r.msg);
+        // without a uri the current track is added
+        assert!(!run(&mut pl, &store, "playlist_add", Some("mix")).await.ok);
+        pl.play_item("two.mp3").await.unwrap();
+        assert!(run(&mut pl, &store, "playlist_add", Some("mix")).await.ok);
+
+        let r = run(&mut pl, &store, "playlist_rename", Some("mix tape")).await;
+        assert!(r.ok, "{}", r.msg);
+        let r = run(&mut pl, &store, "playlist_list", None).await;
+        assert_eq!(r.items, Some(vec!["tape".to_string()]));
+
+        let r = run(&mut pl, &store, "playlist_load", Some("tape")).await;
+        assert!(r.ok, "{}", r.msg);
+        let queued: Vec<_> = pl.list().into_iter().map(|t| t.uri).collect();
+        assert_eq!(queued, vec!["one.mp3", "two.mp3"]);
+
+        // playlist files live under the playlist dir too
+        let r = run(&mut pl, &store, "queue_save", Some("exports/tape.m3u")).await;
+        assert!(r.ok, "{}", r.msg);
+        assert!(dir.join("exports/tape.m3u").exists());
+        let r = run(&mut pl, &store, "queue_load", Some("exports/tape.m3u")).await;
+        assert!(r.ok, "{}", r.msg);
+        assert_eq!(pl.list().len(), 4);
+        for path in ["/tmp/tape.m3u", "../tape.m3u"] {
+            let r = run(&mut pl, &store, "queue_save", Some(path)).await;
+            assert_eq!(r.code, Some(ErrorCode::Forbidden), "{}", path);
+            let r = run(&mut pl, &store, "queue_load", Some(path)).await;
+            assert_eq!(r.code, Some(ErrorCode::Forbidden), "{}", path);
+        }
+
+        assert!(
+            run(&mut pl, &store, "playlist_delete", Some("tape"))
+                .await
+                .ok
+        );
+        assert!(!run(&mut pl, &store, "playlist_show", Some("tape")).await.ok);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
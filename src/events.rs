@@ -0,0 +1,200 @@
+// State-change events streamed to subscribed clients.
+//
+// The daemon keeps a `LiveState` per subscriber and, whenever something may have changed (a
+// request ran, the adapter reported an event, the position ticker fired), takes a new one and
+// sends the events in `LiveState::events_since`. Clients rebuild the same state with
+// `LiveState::apply` instead of polling `status`, `list`, `position` and `duration`.
+
+use crate::playback::{PlaybackState, PlaybackStatus};
+use crate::player::RepeatMode;
+use crate::track::Track;
+use serde::{Deserialize, Serialize};
+
+/// Default rate of `position` events for `subscribe`.
+pub const DEFAULT_TICK_MS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A different track is loaded (or none)
+    Track {
+        track: Option<Track>,
+        /// Length in seconds
+        duration: Option<u64>,
+    },
+    /// Playing, paused, stopped or idle
+    State { state: PlaybackState },
+    Volume {
+        volume: Option<u8>,
+        muted: Option<bool>,
+    },
+    /// Repeat and shuffle settings
+    Modes { repeat: RepeatMode, shuffle: bool },
+    /// The whole queue, after any change to it
+    Queue { tracks: Vec<Track> },
+    /// Played tracks, most recent first
+    History { tracks: Vec<Track> },
+    /// Names of the stored playlists
+    Playlists { names: Vec<String> },
+    /// Seconds into the current track
+    Position {
+        position: Option<u64>,
+        duration: Option<u64>,
+    },
+}
+
+/// What a subscriber knows about the daemon.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LiveState {
+    pub status: PlaybackStatus,
+    pub queue: Vec<Track>,
+    /// Most recent first
+    pub history: Vec<Track>,
+    pub playlists: Vec<String>,
+}
+
+impl LiveState {
+    /// The events that turn `prev` into `self`; every event when there is no `prev`.
+    pub fn events_since(&self, prev: Option<&LiveState>) -> Vec<Event> {
+        let st = &self.status;
+        let changed =
+            |same: &dyn Fn(&PlaybackStatus) -> bool| prev.is_none_or(|p| !same(&p.status));
+        let mut events = Vec::new();
+        if changed(&|o| o.track == st.track && o.duration == st.duration) {
+            events.push(Event::Track {
+                track: st.track.clone(),
+                duration: st.duration,
+            });
+        }
+        if changed(&|o| o.state == st.state) {
+            events.push(Event::State { state: st.state });
+        }
+        if changed(&|o| o.volume == st.volume && o.muted == st.muted) {
+            events.push(Event::Volume {
+                volume: st.volume,
+                muted: st.muted,
+            });
+        }
+        if changed(&|o| o.repeat == st.repeat && o.shuffle == st.shuffle) {
+            events.push(Event::Modes {
+                repeat: st.repeat,
+                shuffle: st.shuffle,
+            });
+        }
+        if prev.is_none_or(|p| p.queue != self.queue) {
+            events.push(Event::Queue {
+                tracks: self.queue.clone(),
+            });
+        }
+        if prev.is_none_or(|p| p.history != self.history) {
+            events.push(Event::History {
+                tracks: self.history.clone(),
+            });
+        }
+        if prev.is_none_or(|p| p.playlists != self.playlists) {
+            events.push(Event::Playlists {
+                names: self.playlists.clone(),
+            });
+        }
+        if changed(&|o| o.position == st.position) {
+            events.push(Event::Position {
+                position: st.position,
+                duration: st.duration,
+            });
+        }
+        events
+    }
+
+    pub fn apply(&mut self, event: Event) {
+        let st = &mut self.status;
+        match event {
+            Event::Track { track, duration } => {
+                st.track = track;
+                st.duration = duration;
+            }
+            Event::State { state } => st.state = state,
+            Event::Volume { volume, muted } => {
+                st.volume = volume;
+                st.muted = muted;
+            }
+            Event::Modes { repeat, shuffle } => {
+                st.repeat = repeat;
+                st.shuffle = shuffle;
+            }
+            Event::Queue { tracks } => {
+                st.queue_len = tracks.len();
+                self.queue = tracks;
+            }
+            Event::History { tracks } => self.history = tracks,
+            Event::Playlists { names } => self.playlists = names,
+            Event::Position { position, duration } => {
+                st.position = position;
+                st.duration = duration;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> LiveState {
+        let mut st = LiveState::default();
+        st.status.state = PlaybackState::Playing;
+        st.status.track = Some(Track::from_uri("a.mp3", "mpv"));
+        st.status.duration = Some(200);
+        st.status.position = Some(5);
+        st.status.volume = Some(50);
+        st.status.queue_len = 1;
+        st.queue = vec![Track::from_uri("b.mp3", "mpv")];
+        st.playlists = vec!["mix".into()];
+        st
+    }
+
+    #[test]
+    fn replaying_events_rebuilds_the_state() {
+        let full = state();
+        let events = full.events_since(None);
+        assert_eq!(events.len(), 8);
+        let mut client = LiveState::default();
+        for e in events {
+            client.apply(e);
+        }
+        assert_eq!(client, full);
+    }
+
+    #[test]
+    fn only_changes_are_sent() {
+        let before = state();
+        assert!(before.events_since(Some(&before)).is_empty());
+
+        let mut after = before.clone();
+        after.status.state = PlaybackState::Paused;
+        after.status.position = Some(6);
+        after.queue.clear();
+        after.status.queue_len = 0;
+        assert_eq!(
+            after.events_since(Some(&before)),
+            vec![
+                Event::State {
+                    state: PlaybackState::Paused
+                },
+                Event::Queue { tracks: Vec::new() },
+                Event::Position {
+                    position: Some(6),
+                    duration: Some(200)
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(Event::State {
+            state: PlaybackState::Paused,
+        })
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"event": "state", "state": "paused"})
+        );
+    }
+}
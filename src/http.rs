@@ -0,0 +1,782 @@
+// HTTP control API: the daemon's commands as REST endpoints, for scripts and tools that speak
+// HTTP more easily than the line protocol.
+//
+// Every endpoint builds a `Request` and runs it through the same `dispatch` as the sockets, so
+// the token check, error codes and change notifications are shared. The token goes in an
+// `Authorization: Bearer <APPLE_DAEMON_TOKEN>` header. Bodies in both directions are JSON, and
+// request bodies must say so with `Content-Type: application/json`; replies are the protocol's
+// `Response` (`ok`, `msg`, `code`, `items`, ...) with an HTTP status that follows its `code`.
+// Queue indices are zero-based, as in the protocol.
+//
+// Web pages on other sites must not reach the API through the user's browser, so requests are
+// refused when their `Origin` is not the API's own, or when their `Host` does not name the
+// address it listens on (a DNS name pointed at it, as in DNS rebinding). Use the address or
+// `localhost` in URLs.
+//
+// `GET /events` is the `subscribe` feed as a WebSocket, one `EventLine` per text message, and
+// `GET /` serves a small now-playing page built on it. Browsers cannot set headers on
+// WebSocket requests, so there (and only there) the token may be a `?token=` query parameter
+// instead.
+
+use crate::daemon::{changed_events, dispatch, stopped, tick, ticker_every, Session, Shared};
+use crate::events::DEFAULT_TICK_MS;
+use crate::protocol::{ErrorCode, EventLine, Failure, Request, Response, PROTOCOL_VERSION};
+use axum::body::Bytes;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRequest, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::uri::Authority;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// Where `--http` listens when no address is given.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7780";
+
+/// Serve the API on `listener` until `stop` is set.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    shared: Shared,
+    mut stop: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    let bound = listener.local_addr()?;
+    axum::serve(listener, router(shared, stop.clone(), bound))
+        .with_graceful_shutdown(async move { stopped(&mut stop).await })
+        .await
+}
+
+const NOW_PLAYING: &str = include_str!("../assets/now_playing.html");
+
+/// The API listening on `bound`; open WebSockets are closed when `stop` is set.
+pub(crate) fn router(shared: Shared, stop: watch::Receiver<bool>, bound: SocketAddr) -> Router {
+    Router::new()
+        .route("/", get(|| async { Html(NOW_PLAYING) }))
+        .route(
+            "/events",
+            get(
+                move |api: Api, q: Query<HashMap<String, String>>, ws: WebSocketUpgrade| {
+                    events(api, q, ws, stop.clone())
+                },
+            ),
+        )
+        .route(
+            "/hello",
+            get(|api: Api| {
+                api.run(Request::Hello {
+                    version: PROTOCOL_VERSION,
+                    client: None,
+                })
+            }),
+        )
+        .route("/status", get(|api: Api| api.run(Request::Status)))
+        .route("/position", get(|api: Api| api.run(Request::Position)))
+        .route("/duration", get(|api: Api| api.run(Request::Duration)))
+        .route("/history", get(|api: Api| api.run(Request::History)))
+        .route("/play", post(play))
+        .route("/pause", post(|api: Api| api.run(Request::Pause)))
+        .route("/next", post(|api: Api| api.run(Request::Next)))
+        .route("/prev", post(|api: Api| api.run(Request::Prev)))
+        .route("/seek", post(seek))
+        .route(
+            "/volume",
+            put(|api: Api, Body(p): Body<Params>| api.command("set_volume", p)),
+        )
+        .route("/volume/up", post(|api: Api| api.run(Request::VolumeUp)))
+        .route(
+            "/volume/down",
+            post(|api: Api| api.run(Request::VolumeDown)),
+        )
+        .route("/mute", post(|api: Api| api.run(Request::Mute)))
+        .route("/unmute", post(|api: Api| api.run(Request::Unmute)))
+        .route(
+            "/repeat",
+            put(|api: Api, Body(p): Body<Params>| api.command("repeat", p)),
+        )
+        .route(
+            "/shuffle",
+            put(|api: Api, Body(p): Body<Params>| api.command("shuffle", p)),
+        )
+        .route(
+            "/queue",
+            get(|api: Api| api.run(Request::List))
+                .post(enqueue)
+                .delete(|api: Api| api.run(Request::Clear)),
+        )
+        .route(
+            "/queue/{index}",
+            delete(|api: Api, Index(index): Index| api.run(Request::Remove { index })),
+        )
+        .route(
+            "/queue/{index}/play",
+            post(|api: Api, Index(index): Index| api.run(Request::PlayIndex { index })),
+        )
+        .route(
+            "/queue/move",
+            post(|api: Api, Body(p): Body<Params>| api.command("move", p)),
+        )
+        .route(
+            "/queue/load",
+            post(|api: Api, Body(p): Body<Params>| api.command("queue_load", p)),
+        )
+        .route(
+            "/queue/save",
+            post(|api: Api, Body(p): Body<Params>| api.command("queue_save", p)),
+        )
+        .route("/search", get(search))
+        .route(
+            "/playlists",
+            get(|api: Api| api.run(Request::PlaylistList))
+                .post(|api: Api, Body(p): Body<Params>| api.command("playlist_create", p)),
+        )
+        .route(
+            "/playlists/{name}",
+            get(|api: Api, Path(name): Path<String>| api.run(Request::PlaylistShow { name }))
+                .delete(|api: Api, Path(name): Path<String>| {
+                    api.run(Request::PlaylistDelete { name })
+                }),
+        )
+        .route("/playlists/{name}/rename", post(playlist_rename))
+        .route("/playlists/{name}/tracks", post(playlist_add))
+        .route(
+            "/playlists/{name}/save",
+            post(|api: Api, Path(name): Path<String>| api.run(Request::PlaylistSave { name })),
+        )
+        .route(
+            "/playlists/{name}/load",
+            post(|api: Api, Path(name): Path<String>| api.run(Request::PlaylistLoad { name })),
+        )
+        .route(
+            "/artists/{id}",
+            get(|api: Api, Path(artist_id): Path<String>| {
+                api.run(Request::ArtistInfo { artist_id })
+            }),
+        )
+        .route(
+            "/artists/{id}/discography",
+            get(|api: Api, Path(artist_id): Path<String>| {
+                api.run(Request::ArtistDiscography { artist_id })
+            }),
+        )
+        // any command by name, with its parameters as the body
+        .route(
+            "/commands/{cmd}",
+            post(|api: Api, Path(cmd): Path<String>, Body(p): Body<Params>| api.command(&cmd, p)),
+        )
+        .fallback(|| async { Reply::from(Failure::new(ErrorCode::NotFound, "no such endpoint")) })
+        .layer(middleware::from_fn_with_state(bound, same_site))
+        .with_state(shared)
+}
+
+// Refuse requests that a page on another site made through the browser: see the top of the
+// file. Requests without an Origin (scripts, curl) only need a good Host.
+async fn same_site(
+    State(bound): State<SocketAddr>,
+    req: axum::extract::Request,
+    next: Next,
+) -> axum::response::Response {
+    let headers = req.headers();
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    if host.is_some_and(|h| !host_allowed(h, bound)) {
+        return Reply::from(Failure::new(ErrorCode::Forbidden, "unexpected Host header"))
+            .into_response();
+    }
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        let own = host.map(|h| format!("http://{}", h));
+        let same = match (origin.to_str(), own) {
+            (Ok(origin), Some(own)) => origin.eq_ignore_ascii_case(&own),
+            _ => false,
+        };
+        if !same {
+            return Reply::from(Failure::new(
+                ErrorCode::Forbidden,
+                "cross-origin requests are not allowed",
+            ))
+            .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+// Whether a Host header names `bound`: its IP address, or localhost when that reaches it.
+// Any IP address will do when listening on all of them.
+fn host_allowed(host: &str, bound: SocketAddr) -> bool {
+    let Ok(authority) = host.parse::<Authority>() else {
+        return false;
+    };
+    if authority.port_u16().unwrap_or(80) != bound.port() {
+        return false;
+    }
+    let name = authority.host();
+    let ip = bound.ip();
+    match name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(addr) => ip.is_unspecified() || addr == ip,
+        Err(_) => {
+            name.eq_ignore_ascii_case("localhost") && (ip.is_loopback() || ip.is_unspecified())
+        }
+    }
+}
+
+type Params = Map<String, Value>;
+
+/// A caller: the daemon and the token from its `Authorization` header, if any.
+struct Api {
+    shared: Shared,
+    token: Option<String>,
+}
+
+impl FromRequestParts<Shared> for Api {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, shared: &Shared) -> Result<Self, Infallible> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|t| t.trim().to_string());
+        Ok(Api {
+            shared: shared.clone(),
+            token,
+        })
+    }
+}
+
+impl Api {
+    async fn run(self, request: Request) -> Reply {
+        self.reply(Ok(request)).await
+    }
+
+    // not `async` so that the future does not borrow `cmd`
+    fn command(self, cmd: &str, params: Params) -> impl std::future::Future<Output = Reply> {
+        self.reply(Request::from_params(cmd, params))
+    }
+
+    async fn reply(self, request: Result<Request, Failure>) -> Reply {
+        // a plain request has no connection to push events on
+        if let Ok(Request::Subscribe { .. } | Request::Unsubscribe) = request {
+            return Failure::new(
+                ErrorCode::InvalidParams,
+                "subscribe through the /events WebSocket",
+            )
+            .into();
+        }
+        let token = self.token.as_deref();
+        Reply(dispatch(request, token, &self.shared, &mut Session::default()).await)
+    }
+}
+
+/// A JSON request body, which needs `Content-Type: application/json`; an empty body counts as
+/// `{}`. Other bodies are answered with `invalid_params` like any other bad request, with the
+/// HTTP status of the `Json` rejection (415 for a missing content type).
+struct Body<T>(T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for Body<T> {
+    type Rejection = axum::response::Response;
+
+    async fn from_request(
+        req: axum::extract::Request,
+        state: &S,
+    ) -> Result<Self, axum::response::Response> {
+        let (parts, body) = req.into_parts();
+        let request = |body| axum::extract::Request::from_parts(parts.clone(), body);
+        let bytes = Bytes::from_request(request(body), state)
+            .await
+            .map_err(|e| rejected(e.status(), e.body_text()))?;
+        // nothing to parse, so no content type needed
+        let json = if bytes.iter().all(u8::is_ascii_whitespace) {
+            Json::from_bytes(b"{}")
+        } else {
+            Json::from_request(request(bytes.into()), state).await
+        };
+        json.map(|Json(v)| Body(v)).map_err(|e: JsonRejection| {
+            rejected(e.status(), format!("invalid body: {}", e.body_text()))
+        })
+    }
+}
+
+// An `invalid_params` reply with the status of the rejection it stands for.
+fn rejected(status: StatusCode, msg: String) -> axum::response::Response {
+    let mut resp = Reply::from(Failure::new(ErrorCode::InvalidParams, msg)).into_response();
+    *resp.status_mut() = status;
+    resp
+}
+
+/// A queue index from the path.
+struct Index(usize);
+
+impl<S: Send + Sync> FromRequestParts<S> for Index {
+    type Rejection = Reply;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Reply> {
+        Path::<usize>::from_request_parts(parts, state)
+            .await
+            .map(|Path(i)| Index(i))
+            .map_err(|e| Failure::new(ErrorCode::InvalidParams, e.body_text()).into())
+    }
+}
+
+/// A daemon response as an HTTP response.
+struct Reply(Response);
+
+impl From<Failure> for Reply {
+    fn from(f: Failure) -> Self {
+        Reply(f.into())
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> axum::response::Response {
+        let status = match self.0.code {
+            _ if self.0.ok => StatusCode::OK,
+            Some(code) => status_for(code),
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let mut resp = (status, Json(self.0)).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        resp
+    }
+}
+
+fn status_for(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::ParseError | ErrorCode::UnsupportedVersion | ErrorCode::InvalidParams => {
+            StatusCode::BAD_REQUEST
+        }
+        ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+        ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::UnknownCommand | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Body of `POST /play` and `POST /queue`: a uri, or library track ids.
+#[derive(Deserialize)]
+struct Items {
+    uri: Option<String>,
+    #[serde(default)]
+    ids: Vec<String>,
+    /// Queue right after the current item instead of at the end
+    #[serde(default)]
+    next: bool,
+}
+
+enum Item {
+    Uri(String),
+    Ids(Vec<String>),
+}
+
+impl Items {
+    fn item(&self) -> Result<Item, Failure> {
+        match (&self.uri, self.ids.is_empty()) {
+            (Some(uri), true) => Ok(Item::Uri(uri.clone())),
+            (None, false) => Ok(Item::Ids(self.ids.clone())),
+            _ => Err(Failure::new(
+                ErrorCode::InvalidParams,
+                "expected either \"uri\" or \"ids\"",
+            )),
+        }
+    }
+}
+
+async fn play(api: Api, Body(items): Body<Items>) -> Reply {
+    let request = items.item().map(|item| match item {
+        Item::Uri(uri) => Request::Play { uri },
+        Item::Ids(ids) => Request::PlayIds { ids },
+    });
+    api.reply(request).await
+}
+
+async fn enqueue(api: Api, Body(items): Body<Items>) -> Reply {
+    let request = items.item().and_then(|item| match (item, items.next) {
+        (Item::Uri(uri), false) => Ok(Request::Enqueue { uri }),
+        (Item::Uri(uri), true) => Ok(Request::InsertNext { uri }),
+        (Item::Ids(ids), false) => Ok(Request::EnqueueId { ids }),
+        (Item::Ids(_), true) => Err(Failure::new(
+            ErrorCode::InvalidParams,
+            "\"next\" only works with a uri",
+        )),
+    });
+    api.reply(request).await
+}
+
+/// Body of `POST /seek`: exactly one of the fields, in seconds.
+#[derive(Deserialize)]
+struct Seek {
+    to: Option<u64>,
+    forward: Option<u64>,
+    backward: Option<u64>,
+}
+
+async fn seek(api: Api, Body(seek): Body<Seek>) -> Reply {
+    let request = match (seek.to, seek.forward, seek.backward) {
+        (Some(seconds), None, None) => Ok(Request::SeekTo { seconds }),
+        (None, Some(seconds), None) => Ok(Request::SeekForward { seconds }),
+        (None, None, Some(seconds)) => Ok(Request::SeekBackward { seconds }),
+        _ => Err(Failure::new(
+            ErrorCode::InvalidParams,
+            "expected one of \"to\", \"forward\" or \"backward\"",
+        )),
+    };
+    api.reply(request).await
+}
+
+async fn search(api: Api, Query(q): Query<HashMap<String, String>>) -> Reply {
+    match q.get("q") {
+        Some(query) => {
+            api.run(Request::Search {
+                query: query.clone(),
+            })
+            .await
+        }
+        None => Failure::new(ErrorCode::InvalidParams, "missing ?q=").into(),
+    }
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    to: String,
+}
+
+async fn playlist_rename(
+    api: Api,
+    Path(from): Path<String>,
+    Body(Rename { to }): Body<Rename>,
+) -> Reply {
+    api.run(Request::PlaylistRename { from, to }).await
+}
+
+/// Body of `POST /playlists/{name}/tracks`; without a uri the current track is added.
+#[derive(Deserialize)]
+struct Add {
+    uri: Option<String>,
+}
+
+async fn playlist_add(api: Api, Path(name): Path<String>, Body(Add { uri }): Body<Add>) -> Reply {
+    api.run(Request::PlaylistAdd { name, uri }).await
+}
+
+// `GET /events?tick_ms=..`: authorize like `subscribe`, with the token from the header or
+// `?token=`, then stream the events until the client goes away or the daemon stops. Browsers
+// let any page open a WebSocket anywhere, so `same_site` refusing foreign origins is what keeps
+// other sites from reading the feed with a token taken from the now-playing page's URL.
+async fn events(
+    api: Api,
+    Query(q): Query<HashMap<String, String>>,
+    ws: WebSocketUpgrade,
+    stop: watch::Receiver<bool>,
+) -> axum::response::Response {
+    let tick_ms = match q.get("tick_ms").map(|t| t.parse()) {
+        None => DEFAULT_TICK_MS,
+        Some(Ok(ms)) => ms,
+        Some(Err(_)) => {
+            return Reply::from(Failure::new(ErrorCode::InvalidParams, "invalid tick_ms"))
+                .into_response()
+        }
+    };
+    let mut session = Session::default();
+    let request = Ok(Request::Subscribe { tick_ms });
+    let token = api.token.as_ref().or(q.get("token"));
+    let resp = dispatch(
+        request,
+        token.map(String::as_str),
+        &api.shared,
+        &mut session,
+    )
+    .await;
+    if !resp.ok {
+        return Reply(resp).into_response();
+    }
+    let period = session.events.and_then(|s| s.tick);
+    ws.on_upgrade(move |socket| feed(socket, api.shared, period, stop))
+}
+
+async fn feed(
+    mut socket: WebSocket,
+    shared: Shared,
+    period: Option<std::time::Duration>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut changes = shared.changes.subscribe();
+    let mut ticker = period.map(ticker_every);
+    let mut seen = None;
+    loop {
+        for event in changed_events(&shared, &mut seen).await {
+            let line = EventLine {
+                v: PROTOCOL_VERSION,
+                event,
+            };
+            let Ok(text) = serde_json::to_string(&line) else {
+                continue;
+            };
+            if socket.send(Message::Text(text.into())).await.is_err() {
+                return;
+            }
+        }
+        tokio::select! {
+            _ = stopped(&mut stop) => break,
+            r = changes.changed() => if r.is_err() { break },
+            _ = tick(&mut ticker), if ticker.is_some() => {}
+            // nothing is expected from the client; pings are answered by the socket itself
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => continue,
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::NoopAdapter;
+    use crate::player::Player;
+    use serde_json::json;
+    use tokio::task::JoinHandle;
+
+    // An API server on a free port, with token "secret": its address, stop switch and task.
+    async fn start() -> (
+        std::net::SocketAddr,
+        watch::Sender<bool>,
+        JoinHandle<std::io::Result<()>>,
+    ) {
+        let shared = Shared::for_test(Player::new(Box::new(NoopAdapter::new())));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        (addr, stop_tx, tokio::spawn(serve(listener, shared, stop)))
+    }
+
+    #[tokio::test]
+    async fn endpoints_run_commands_behind_the_token() {
+        let (addr, stop_tx, server) = start().await;
+        let base = format!("http://{}", addr);
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("{}{}", base, path);
+        let send = async |req: reqwest::RequestBuilder| {
+            let resp = req.bearer_auth("secret").send().await.unwrap();
+            (resp.status(), resp.json::<Value>().await.unwrap())
+        };
+
+        // hello needs no token
+        let r = client.get(url("/hello")).send().await.unwrap();
+        assert_eq!(r.status(), StatusCode::OK);
+        assert_eq!(
+            r.json::<Value>().await.unwrap()["hello"]["auth_required"],
+            true
+        );
+        for req in [
+            client.get(url("/queue")),
+            client.get(url("/queue")).bearer_auth("wrong"),
+            // the query token is only for the WebSocket
+            client.get(url("/queue?token=secret")),
+        ] {
+            let r = req.send().await.unwrap();
+            assert_eq!(r.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(r.headers()[header::WWW_AUTHENTICATE], "Bearer");
+            assert_eq!(r.json::<Value>().await.unwrap()["code"], "unauthorized");
+        }
+
+        let (status, _) = send(client.post(url("/queue")).json(&json!({"uri": "a.mp3"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = json!({"uri": "b.mp3", "next": true});
+        send(client.post(url("/queue")).json(&body)).await;
+        let (_, r) = send(client.get(url("/queue"))).await;
+        assert_eq!(r["items"], json!(["b.mp3", "a.mp3"]));
+        let (status, _) = send(
+            client
+                .post(url("/queue/move"))
+                .json(&json!({"from": 1, "to": 0})),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, r) = send(client.delete(url("/queue/5"))).await;
+        assert_eq!(
+            (status, r["code"].as_str()),
+            (StatusCode::NOT_FOUND, Some("not_found"))
+        );
+        let (status, r) = send(client.delete(url("/queue/0"))).await;
+        assert_eq!(
+            (status, r["msg"].as_str()),
+            (StatusCode::OK, Some("removed a.mp3"))
+        );
+
+        let (status, r) = send(client.post(url("/seek")).json(&json!({"to": 30}))).await;
+        assert_eq!(
+            (status, r["msg"].as_str()),
+            (StatusCode::OK, Some("seek to 30 seconds"))
+        );
+        let (status, r) = send(client.post(url("/seek")).json(&json!({}))).await;
+        assert_eq!(
+            (status, r["code"].as_str()),
+            (StatusCode::BAD_REQUEST, Some("invalid_params"))
+        );
+        let bad = client
+            .post(url("/queue"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body("not json");
+        let (status, _) = send(bad).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        // a form on another site can post text/plain without a preflight; JSON cannot
+        let form = client
+            .post(url("/queue"))
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(r#"{"uri": "c.mp3"}"#);
+        let (status, r) = send(form).await;
+        assert_eq!(
+            (status, r["code"].as_str()),
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, Some("invalid_params"))
+        );
+
+        // only pages served by the API itself may call it from a browser
+        let own = format!("http://{}", addr);
+        let (status, _) = send(client.get(url("/queue")).header(header::ORIGIN, &own)).await;
+        assert_eq!(status, StatusCode::OK);
+        let localhost = format!("localhost:{}", addr.port());
+        for (name, value) in [
+            (header::ORIGIN, "https://evil.example".to_string()),
+            (header::ORIGIN, "null".to_string()),
+            (header::HOST, format!("evil.example:{}", addr.port())),
+            (header::HOST, "127.0.0.1:1".to_string()),
+        ] {
+            let (status, r) = send(client.post(url("/next")).header(name, &value)).await;
+            assert_eq!(
+                (status, r["code"].as_str()),
+                (StatusCode::FORBIDDEN, Some("forbidden")),
+                "{}",
+                value
+            );
+        }
+        let (status, _) = send(client.get(url("/queue")).header(header::HOST, &localhost)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, r) = send(client.get(url("/status"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(r["status"].is_object(), "{}", r);
+
+        // every command is reachable by name
+        let (_, r) = send(client.post(url("/commands/list"))).await;
+        assert_eq!(r["items"], json!(["b.mp3"]));
+        let (status, _) = send(client.post(url("/commands/dance"))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(client.post(url("/commands/subscribe"))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        stop_tx.send_replace(true);
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn hosts_must_name_the_bound_address() {
+        let local: SocketAddr = "127.0.0.1:7780".parse().unwrap();
+        assert!(host_allowed("127.0.0.1:7780", local));
+        assert!(host_allowed("LocalHost:7780", local));
+        assert!(!host_allowed("127.0.0.1", local));
+        assert!(!host_allowed("192.168.1.2:7780", local));
+        assert!(!host_allowed("rebound.example:7780", local));
+
+        let any: SocketAddr = "[::]:80".parse().unwrap();
+        assert!(host_allowed("192.168.1.2", any));
+        assert!(host_allowed("[fe80::1]:80", any));
+        assert!(!host_allowed("music.example", any));
+    }
+
+    #[tokio::test]
+    async fn websocket_streams_the_state_then_changes() {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        use tokio_tungstenite::tungstenite::{self, Error as WsError};
+
+        let (addr, stop_tx, server) = start().await;
+        let page = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        assert_eq!(page.status(), StatusCode::OK);
+        assert!(page.text().await.unwrap().contains("/events"));
+
+        let refused = tokio_tungstenite::connect_async(format!("ws://{}/events", addr)).await;
+        match refused {
+            Err(WsError::Http(resp)) => assert_eq!(resp.status(), StatusCode::UNAUTHORIZED),
+            other => panic!("expected 401, got {:?}", other.map(|_| ())),
+        }
+
+        // a page elsewhere cannot open the feed, even with the token
+        let url = format!("ws://{}/events?token=secret&tick_ms=0", addr);
+        let mut foreign = url.as_str().into_client_request().unwrap();
+        foreign.headers_mut().insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://evil.example"),
+        );
+        match tokio_tungstenite::connect_async(foreign).await {
+            Err(WsError::Http(resp)) => assert_eq!(resp.status(), StatusCode::FORBIDDEN),
+            other => panic!("expected 403, got {:?}", other.map(|_| ())),
+        }
+
+        // the now-playing page's own origin can
+        let mut own = url.as_str().into_client_request().unwrap();
+        let origin = HeaderValue::from_str(&format!("http://{}", addr)).unwrap();
+        own.headers_mut().insert(header::ORIGIN, origin);
+        let (mut ws, _) = tokio_tungstenite::connect_async(own).await.unwrap();
+        let mut next = async || {
+            let msg = tokio::time::timeout(std::time::Duration::from_secs(5), ws.next())
+                .await
+                .unwrap();
+            match msg {
+                Some(Ok(tungstenite::Message::Text(t))) => {
+                    serde_json::from_str::<Value>(&t).unwrap()
+                }
+                other => panic!("expected an event, got {:?}", other),
+            }
+        };
+        // the full state first
+        let mut first = Vec::new();
+        for _ in 0..8 {
+            first.push(next().await["event"].as_str().unwrap().to_string());
+        }
+        assert!(first.contains(&"position".to_string()), "{:?}", first);
+
+        // a change made through the REST API is pushed
+        let r = reqwest::Client::new()
+            .post(format!("http://{}/queue", addr))
+            .bearer_auth("secret")
+            .json(&json!({"uri": "a.mp3"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(r.status(), StatusCode::OK);
+        let ev = next().await;
+        assert_eq!(
+            (ev["v"].as_u64(), ev["event"].as_str()),
+            (Some(1), Some("queue"))
+        );
+        assert_eq!(ev["tracks"][0]["uri"], "a.mp3");
+
+        // stopping the daemon closes the feed
+        stop_tx.send_replace(true);
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(5), ws.next())
+            .await
+            .unwrap();
+        assert!(
+            matches!(closed, Some(Ok(tungstenite::Message::Close(_))) | None),
+            "{:?}",
+            closed
+        );
+        drop(ws);
+        server.await.unwrap().unwrap();
+    }
+}
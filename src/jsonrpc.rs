@@ -0,0 +1,346 @@
+// JSON-RPC 2.0 framing for the daemon, for editors and scripts that already speak it.
+//
+// Shares the socket with the line protocol in `protocol`: a line holding a JSON array (a batch)
+// or an object with a "jsonrpc" member is read as JSON-RPC, anything else as a regular request.
+// Methods are the daemon commands and take their parameters by name:
+//   {"jsonrpc":"2.0","id":1,"method":"seek_to","params":{"seconds":90,"token":"secret"}}
+//   {"jsonrpc":"2.0","id":1,"result":{"msg":"seek to 90 seconds"}}
+// The token, when the daemon requires one, travels in `params`. Requests without an id are
+// notifications and get no reply; a batch gets one array with the replies to its requests.
+
+use crate::protocol::{ErrorCode, Failure, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// Start of the range the spec leaves to the server; see `error_code`.
+pub const SERVER_ERROR: i64 = -32000;
+
+/// Whether `line` should be answered as JSON-RPC rather than with the line protocol.
+pub fn is_jsonrpc(line: &str) -> bool {
+    let line = line.trim_start();
+    if line.starts_with('[') {
+        return true;
+    }
+    match serde_json::from_str::<Value>(line) {
+        Ok(value) => value.get("jsonrpc").is_some(),
+        // unparseable, but clearly meant for us: reply with a JSON-RPC parse error
+        Err(_) => line.contains("\"jsonrpc\""),
+    }
+}
+
+/// One request of a message, ready to run.
+#[derive(Debug)]
+pub struct Call {
+    /// `None` for notifications, which get no reply
+    pub id: Option<Value>,
+    pub token: Option<String>,
+    pub request: Result<Request, Failure>,
+}
+
+/// A parsed line: a single request or a batch. Entries that are not valid requests at all
+/// are already their error reply.
+#[derive(Debug)]
+pub struct Message {
+    pub batch: bool,
+    pub entries: Vec<Result<Call, RpcResponse>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    /// `{"code": "<protocol error code>"}`, the same spelling as the line protocol
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    // boxed to keep `Result<_, RpcResponse>` small
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Box<RpcError>>,
+    /// The request's id; null when it could not be read
+    pub id: Value,
+}
+
+impl RpcResponse {
+    pub fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        RpcResponse {
+            jsonrpc: JSONRPC_VERSION.into(),
+            result: None,
+            error: Some(Box::new(RpcError {
+                code,
+                message: message.into(),
+                data: None,
+            })),
+            id,
+        }
+    }
+
+    /// Wrap a daemon response: its payload becomes the result, a failure the error object.
+    pub fn from_response(resp: Response, id: Value) -> Self {
+        if !resp.ok {
+            let code = resp.code.unwrap_or(ErrorCode::Failed);
+            let mut r = RpcResponse::error(id, error_code(code), resp.msg);
+            if let Some(e) = &mut r.error {
+                e.data = Some(serde_json::json!({ "code": code }));
+            }
+            return r;
+        }
+        let mut result = serde_json::to_value(&resp).unwrap_or_default();
+        if let Some(obj) = result.as_object_mut() {
+            // envelope fields of the line protocol
+            for key in ["v", "id", "ok", "code"] {
+                obj.remove(key);
+            }
+            // `items` is only always present for the line protocol's older clients
+            if obj.get("items").is_some_and(Value::is_null) {
+                obj.remove("items");
+            }
+        }
+        RpcResponse {
+            jsonrpc: JSONRPC_VERSION.into(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+}
+
+/// JSON-RPC error code for a protocol error code. Errors specific to the daemon use the
+/// server range, -32000 to -32099.
+pub fn error_code(code: ErrorCode) -> i64 {
+    match code {
+        ErrorCode::ParseError => PARSE_ERROR,
+        ErrorCode::UnsupportedVersion => INVALID_REQUEST,
+        ErrorCode::UnknownCommand => METHOD_NOT_FOUND,
+        ErrorCode::InvalidParams => INVALID_PARAMS,
+        ErrorCode::Failed => SERVER_ERROR,
+        ErrorCode::Unauthorized => SERVER_ERROR - 1,
+        ErrorCode::NotFound => SERVER_ERROR - 2,
+        ErrorCode::Forbidden => SERVER_ERROR - 3,
+    }
+}
+
+/// Parse a JSON-RPC line. Fails with the reply to send when the line is not JSON or is an
+/// empty batch.
+pub fn parse_message(line: &str) -> Result<Message, RpcResponse> {
+    let value: Value = serde_json::from_str(line)
+        .map_err(|e| RpcResponse::error(Value::Null, PARSE_ERROR, format!("parse error: {}", e)))?;
+    match value {
+        Value::Array(items) if items.is_empty() => Err(RpcResponse::error(
+            Value::Null,
+            INVALID_REQUEST,
+            "empty batch",
+        )),
+        Value::Array(items) => Ok(Message {
+            batch: true,
+            entries: items.into_iter().map(parse_call).collect(),
+        }),
+        value => Ok(Message {
+            batch: false,
+            entries: vec![parse_call(value)],
+        }),
+    }
+}
+
+fn parse_call(value: Value) -> Result<Call, RpcResponse> {
+    let Value::Object(mut obj) = value else {
+        return Err(RpcResponse::error(
+            Value::Null,
+            INVALID_REQUEST,
+            "expected a request object",
+        ));
+    };
+    let id = obj.remove("id");
+    let reply_id = id.clone().unwrap_or(Value::Null);
+    if !matches!(
+        id,
+        None | Some(Value::Null | Value::Number(_) | Value::String(_))
+    ) {
+        return Err(RpcResponse::error(
+            Value::Null,
+            INVALID_REQUEST,
+            "id must be a string, number or null",
+        ));
+    }
+    if obj.get("jsonrpc").and_then(Value::as_str) != Some(JSONRPC_VERSION) {
+        return Err(RpcResponse::error(
+            reply_id,
+            INVALID_REQUEST,
+            "expected \"jsonrpc\": \"2.0\"",
+        ));
+    }
+    let Some(Value::String(method)) = obj.remove("method") else {
+        return Err(RpcResponse::error(
+            reply_id,
+            INVALID_REQUEST,
+            "missing method",
+        ));
+    };
+    let mut params = match obj.remove("params") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(_) => {
+            return Ok(Call {
+                id,
+                token: None,
+                request: Err(Failure::new(
+                    ErrorCode::InvalidParams,
+                    "params must be an object",
+                )),
+            })
+        }
+    };
+    let token = match params.remove("token") {
+        Some(Value::String(t)) => Some(t),
+        _ => None,
+    };
+    Ok(Call {
+        id,
+        token,
+        request: Request::from_params(&method, params),
+    })
+}
+
+/// The line to send back for `replies`: one object, a batch array, or nothing when only
+/// notifications were received.
+pub fn encode(batch: bool, mut replies: Vec<RpcResponse>) -> Option<String> {
+    let json = if batch {
+        if replies.is_empty() {
+            return None;
+        }
+        serde_json::to_string(&replies)
+    } else {
+        serde_json::to_string(&replies.pop()?)
+    };
+    json.ok()
+}
+
+/// A request without an id, as the daemon sends pushed events.
+pub fn notification<T: Serialize>(method: &str, params: &T) -> String {
+    serde_json::json!({
+        "jsonrpc": JSONRPC_VERSION,
+        "method": method,
+        "params": params,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn single(line: &str) -> Result<Call, RpcResponse> {
+        let mut m = parse_message(line).unwrap();
+        assert!(!m.batch);
+        m.entries.pop().unwrap()
+    }
+
+    #[test]
+    fn methods_map_to_requests() {
+        let call = single(
+            r#"{"jsonrpc":"2.0","id":1,"method":"seek_to","params":{"seconds":90,"token":"t"}}"#,
+        )
+        .unwrap();
+        assert_eq!(call.id, Some(json!(1)));
+        assert_eq!(call.token.as_deref(), Some("t"));
+        assert_eq!(call.request, Ok(Request::SeekTo { seconds: 90 }));
+
+        // no params, or an empty object, for commands without any
+        let call = single(r#"{"jsonrpc":"2.0","id":"a","method":"pause"}"#).unwrap();
+        assert_eq!(call.request, Ok(Request::Pause));
+        let call = single(r#"{"jsonrpc":"2.0","id":2,"method":"next","params":{}}"#).unwrap();
+        assert_eq!(call.request, Ok(Request::Next));
+        let call =
+            single(r#"{"jsonrpc":"2.0","id":3,"method":"seek_forward","params":{}}"#).unwrap();
+        assert_eq!(call.request, Ok(Request::SeekForward { seconds: 10 }));
+
+        // notification
+        let call = single(r#"{"jsonrpc":"2.0","method":"artist_info","params":{"artist_id":"x"}}"#)
+            .unwrap();
+        assert_eq!(call.id, None);
+        assert_eq!(
+            call.request,
+            Ok(Request::ArtistInfo {
+                artist_id: "x".into()
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_requests_get_error_objects() {
+        let err = |r: Result<Call, RpcResponse>| r.unwrap_err().error.unwrap().code;
+        assert_eq!(
+            parse_message("[").unwrap_err().error.unwrap().code,
+            PARSE_ERROR
+        );
+        assert_eq!(
+            parse_message("[]").unwrap_err().error.unwrap().code,
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            err(single(r#"{"jsonrpc":"1.0","id":1,"method":"next"}"#)),
+            INVALID_REQUEST
+        );
+        assert_eq!(err(single(r#"{"jsonrpc":"2.0","id":1}"#)), INVALID_REQUEST);
+
+        let code = |line: &str| single(line).unwrap().request.unwrap_err().code;
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","id":1,"method":"dance"}"#),
+            ErrorCode::UnknownCommand
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","id":1,"method":"remove","params":[1]}"#),
+            ErrorCode::InvalidParams
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","id":1,"method":"play"}"#),
+            ErrorCode::InvalidParams
+        );
+
+        let m = parse_message(r#"[1, {"jsonrpc":"2.0","id":2,"method":"list"}]"#).unwrap();
+        assert!(m.batch);
+        assert!(m.entries[0].is_err());
+        assert!(m.entries[1].is_ok());
+    }
+
+    #[test]
+    fn responses_become_results_or_errors() {
+        let ok = RpcResponse::from_response(Response::ok("paused"), json!(7));
+        let v = serde_json::to_value(&ok).unwrap();
+        assert_eq!(v["result"]["msg"], "paused");
+        assert_eq!(v["result"], json!({"msg": "paused"}));
+        assert!(v.get("error").is_none());
+        assert_eq!(v["id"], 7);
+
+        let fail = RpcResponse::from_response(
+            Response::fail(ErrorCode::NotFound, "index out of range"),
+            json!("x"),
+        );
+        let v = serde_json::to_value(&fail).unwrap();
+        assert!(v.get("result").is_none());
+        assert_eq!(v["error"]["code"], -32002);
+        assert_eq!(v["error"]["message"], "index out of range");
+        assert_eq!(v["error"]["data"]["code"], "not_found");
+
+        assert_eq!(encode(true, Vec::new()), None);
+        assert_eq!(encode(false, Vec::new()), None);
+        assert!(encode(true, vec![ok]).unwrap().starts_with('['));
+
+        assert!(is_jsonrpc(r#" [{"jsonrpc":"2.0"}]"#));
+        assert!(is_jsonrpc(r#"{"jsonrpc":"2.0","method":"next"}"#));
+        assert!(!is_jsonrpc(r#"{"v":1,"cmd":"next"}"#));
+        assert!(!is_jsonrpc("garbage"));
+    }
+}
@@ -1,5 +1,19 @@
 pub mod cli;
 pub mod config;
 pub mod daemon;
+pub mod events;
+pub mod http;
+pub mod jsonrpc;
+pub mod library;
+pub mod library_watch;
+pub mod mpd;
+#[cfg(all(unix, not(target_os = "macos")))]
+pub mod mpris;
 pub mod playback;
 pub mod player;
+pub mod playlist;
+pub mod playlist_store;
+pub mod protocol;
+pub mod search;
+pub mod state;
+pub mod track;
@@ -0,0 +1,705 @@
+// Local music library: walks the configured music directories, reads tags and keeps an index
+// (`library.json` in the config dir) that search works against without any network access.
+
+use crate::track::Track;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+
+/// `Track::source` of library tracks.
+pub const SOURCE: &str = "library";
+
+/// File extensions the scanner picks up (lowercase).
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "opus", "m4a", "mp4", "wav"];
+
+/// A scanned file: the playable track plus tags that only matter for browsing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibraryEntry {
+    pub track: Track,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album_artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disc_number: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+    /// Modification time (nanoseconds since the epoch) and size when the file was read; a
+    /// rescan only reads the file again when either changed
+    #[serde(default)]
+    pub mtime: u64,
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl LibraryEntry {
+    pub fn path(&self) -> &Path {
+        Path::new(&self.track.uri)
+    }
+
+    /// Artist the entry is filed under: the album artist, falling back to the track artist.
+    pub fn filed_artist(&self) -> Option<&str> {
+        self.album_artist
+            .as_deref()
+            .or(self.track.artist.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Album {
+    pub title: String,
+    pub artist: Option<String>,
+    pub year: Option<u32>,
+    /// Indices into `Library::entries`, in track order
+    pub tracks: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Library {
+    /// Directories the index was built from
+    #[serde(default)]
+    pub roots: Vec<PathBuf>,
+    /// Sorted by artist, album, disc and track number
+    #[serde(default)]
+    pub entries: Vec<LibraryEntry>,
+}
+
+/// Outcome of a (re)scan.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScanStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+impl ScanStats {
+    pub fn changed(&self) -> bool {
+        self.added + self.updated + self.removed > 0
+    }
+}
+
+impl fmt::Display for ScanStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} updated, {} removed, {} unchanged",
+            self.added, self.updated, self.removed, self.unchanged
+        )
+    }
+}
+
+pub fn library_path() -> PathBuf {
+    crate::config::default_config_dir().join("library.json")
+}
+
+/// Directories to scan: `music_dirs` from the config, or ~/Music if none are configured.
+pub fn music_dirs(cfg: &crate::config::Config) -> Vec<PathBuf> {
+    if !cfg.music_dirs.is_empty() {
+        return cfg.music_dirs.clone();
+    }
+    std::env::var("HOME")
+        .map(|h| vec![PathBuf::from(h).join("Music")])
+        .unwrap_or_default()
+}
+
+impl Library {
+    /// Walk `roots` and read the tags of every audio file found. Unreadable files are indexed
+    /// with whatever can be derived from the path and reported as warnings.
+    pub fn scan(roots: &[PathBuf]) -> (Library, Vec<String>) {
+        let mut lib = Library::default();
+        let (_, warnings) = lib.rescan(roots);
+        (lib, warnings)
+    }
+
+    /// Bring the index up to date with `roots`: only files that are new or whose mtime or
+    /// size changed are read again, entries for vanished files are dropped.
+    pub fn rescan(&mut self, roots: &[PathBuf]) -> (ScanStats, Vec<String>) {
+        let mut old = self.take_entries();
+        let mut fresh = BTreeMap::new();
+        let mut stats = ScanStats::default();
+        let mut warnings = Vec::new();
+        for root in roots {
+            for path in audio_files_under(root, &mut warnings) {
+                let uri = path.to_string_lossy().into_owned();
+                let prev = old.remove(&uri);
+                let entry = update_entry(&path, prev, &mut stats, &mut warnings);
+                fresh.insert(uri, entry);
+            }
+        }
+        stats.removed = old.len();
+        self.roots = roots.to_vec();
+        self.entries = fresh.into_values().collect();
+        self.sort();
+        (stats, warnings)
+    }
+
+    /// Re-check specific files or directories, e.g. the ones a file watcher reported.
+    /// Paths that no longer exist drop their entries (and everything below them).
+    pub fn refresh_paths(&mut self, paths: &[PathBuf]) -> (ScanStats, Vec<String>) {
+        let mut entries = self.take_entries();
+        let mut stats = ScanStats::default();
+        let mut warnings = Vec::new();
+        for path in paths {
+            if path.is_dir() {
+                let mut seen = std::collections::HashSet::new();
+                for file in audio_files_under(path, &mut warnings) {
+                    let uri = file.to_string_lossy().into_owned();
+                    let prev = entries.remove(&uri);
+                    let entry = update_entry(&file, prev, &mut stats, &mut warnings);
+                    seen.insert(uri.clone());
+                    entries.insert(uri, entry);
+                }
+                stats.removed += remove_under(&mut entries, path, |uri| !seen.contains(uri));
+            } else if path.is_file() {
+                if is_audio_file(path) {
+                    let uri = path.to_string_lossy().into_owned();
+                    let prev = entries.remove(&uri);
+                    let entry = update_entry(path, prev, &mut stats, &mut warnings);
+                    entries.insert(uri, entry);
+                }
+            } else {
+                stats.removed += remove_under(&mut entries, path, |_| true);
+            }
+        }
+        self.entries = entries.into_values().collect();
+        self.sort();
+        (stats, warnings)
+    }
+
+    fn take_entries(&mut self) -> BTreeMap<String, LibraryEntry> {
+        std::mem::take(&mut self.entries)
+            .into_iter()
+            .map(|e| (e.track.uri.clone(), e))
+            .collect()
+    }
+
+    pub fn load(path: &Path) -> Result<Library> {
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("no library index at {}", path.display()))?;
+        serde_json::from_str(&s).context("parsing library index")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("creating library dir")?;
+        }
+        let s = serde_json::to_string(self).context("serialize library")?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, s).context("write library index")?;
+        fs::rename(&tmp, path).context("replace library index")
+    }
+
+    fn sort(&mut self) {
+        self.entries.sort_by_cached_key(|e| {
+            (
+                e.filed_artist().map(str::to_lowercase),
+                e.track.album.as_deref().map(str::to_lowercase),
+                e.disc_number,
+                e.track_number,
+                e.track.uri.clone(),
+            )
+        });
+    }
+
+    pub fn tracks(&self) -> impl Iterator<Item = &Track> {
+        self.entries.iter().map(|e| &e.track)
+    }
+
+    /// Distinct artists (album artist, else track artist), sorted case-insensitively.
+    pub fn artists(&self) -> Vec<String> {
+        let mut seen: BTreeMap<String, String> = BTreeMap::new();
+        for e in &self.entries {
+            if let Some(a) = e.filed_artist() {
+                seen.entry(a.to_lowercase())
+                    .or_insert_with(|| a.to_string());
+            }
+        }
+        seen.into_values().collect()
+    }
+
+    /// Distinct genres, sorted case-insensitively.
+    pub fn genres(&self) -> Vec<String> {
+        let mut seen: BTreeMap<String, String> = BTreeMap::new();
+        for g in self.entries.iter().filter_map(|e| e.genre.as_deref()) {
+            seen.entry(g.to_lowercase())
+                .or_insert_with(|| g.to_string());
+        }
+        seen.into_values().collect()
+    }
+
+    /// Indices of the entries filed under `artist` (see `LibraryEntry::filed_artist`),
+    /// ignoring case.
+    pub fn by_artist(&self, artist: &str) -> Vec<usize> {
+        self.matching(|e| e.filed_artist(), artist)
+    }
+
+    /// Indices of the entries tagged with `genre`, ignoring case.
+    pub fn by_genre(&self, genre: &str) -> Vec<usize> {
+        self.matching(|e| e.genre.as_deref(), genre)
+    }
+
+    fn matching(&self, field: impl Fn(&LibraryEntry) -> Option<&str>, value: &str) -> Vec<usize> {
+        let value = value.to_lowercase();
+        (0..self.entries.len())
+            .filter(|&i| field(&self.entries[i]).is_some_and(|f| f.to_lowercase() == value))
+            .collect()
+    }
+
+    /// Albums grouped by (artist, title), in index order.
+    pub fn albums(&self) -> Vec<Album> {
+        self.albums_of(0..self.entries.len())
+    }
+
+    /// Like `albums`, restricted to the entries at `indices`.
+    pub fn albums_of(&self, indices: impl IntoIterator<Item = usize>) -> Vec<Album> {
+        let mut albums: Vec<Album> = Vec::new();
+        let mut by_key: BTreeMap<(Option<String>, String), usize> = BTreeMap::new();
+        for i in indices {
+            let e = &self.entries[i];
+            let Some(title) = &e.track.album else {
+                continue;
+            };
+            let artist = e.filed_artist().map(str::to_string);
+            let key = (
+                artist.as_deref().map(str::to_lowercase),
+                title.to_lowercase(),
+            );
+            let idx = *by_key.entry(key).or_insert_with(|| {
+                albums.push(Album {
+                    title: title.clone(),
+                    artist,
+                    year: e.year,
+                    tracks: Vec::new(),
+                });
+                albums.len() - 1
+            });
+            albums[idx].tracks.push(i);
+        }
+        albums
+    }
+
+    /// Entries matching `query` (see `crate::search`), best match first.
+    pub fn search(&self, query: &str) -> Vec<&LibraryEntry> {
+        crate::search::rank(self, &crate::search::Query::parse(query))
+    }
+
+    pub fn by_id(&self, id: &str) -> Option<&LibraryEntry> {
+        self.entries.iter().find(|e| e.track.id == id)
+    }
+}
+
+// The index at `library_path()`, reloaded only when the file changes, so repeated searches
+// (e.g. search-as-you-type) do not re-parse it every time.
+static CACHE: Mutex<Option<(SystemTime, Arc<Library>)>> = Mutex::new(None);
+
+pub fn load_cached() -> Result<Arc<Library>> {
+    let path = library_path();
+    let mtime = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .context("local search needs a library index; run `apple library scan` first")?;
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((t, lib)) = cache.as_ref() {
+        if *t == mtime {
+            return Ok(lib.clone());
+        }
+    }
+    let lib = Arc::new(Library::load(&path)?);
+    *cache = Some((mtime, lib.clone()));
+    Ok(lib)
+}
+
+/// Ranked search over the saved index; used by the adapters that play local files.
+pub fn search_tracks(query: &str) -> Result<Vec<Track>> {
+    let lib = load_cached()?;
+    Ok(lib
+        .search(query)
+        .into_iter()
+        .map(|e| e.track.clone())
+        .collect())
+}
+
+/// A track from the saved index by `Track::id`.
+pub fn track_by_id(id: &str) -> Result<Option<Track>> {
+    Ok(load_cached()?.by_id(id).map(|e| e.track.clone()))
+}
+
+/// One line per hit: id, track and path, tab-separated.
+pub fn format_results(query: &str, tracks: &[Track]) -> String {
+    if tracks.is_empty() {
+        return format!("no local tracks match '{}'", query);
+    }
+    tracks
+        .iter()
+        .map(|t| format!("{}\t{}\t{}", t.id, t, t.uri))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn audio_files_under(root: &Path, warnings: &mut Vec<String>) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for item in walkdir::WalkDir::new(root).follow_links(true) {
+        match item {
+            Ok(i) if i.file_type().is_file() && is_audio_file(i.path()) => {
+                files.push(i.into_path())
+            }
+            Ok(_) => {}
+            Err(e) => warnings.push(e.to_string()),
+        }
+    }
+    files
+}
+
+// Keep `prev` if the file still has the same mtime and size, otherwise read it again.
+fn update_entry(
+    path: &Path,
+    prev: Option<LibraryEntry>,
+    stats: &mut ScanStats,
+    warnings: &mut Vec<String>,
+) -> LibraryEntry {
+    if let Some(prev) = prev {
+        if file_stamp(path) == Some((prev.mtime, prev.size)) {
+            stats.unchanged += 1;
+            return prev;
+        }
+        stats.updated += 1;
+    } else {
+        stats.added += 1;
+    }
+    let (entry, warning) = read_entry(path);
+    warnings.extend(warning);
+    entry
+}
+
+// Remove entries at or below `path` for which `pred(uri)` holds; returns how many.
+fn remove_under(
+    entries: &mut BTreeMap<String, LibraryEntry>,
+    path: &Path,
+    pred: impl Fn(&str) -> bool,
+) -> usize {
+    let before = entries.len();
+    entries.retain(|uri, _| !(Path::new(uri).starts_with(path) && pred(uri)));
+    before - entries.len()
+}
+
+// (mtime in nanoseconds since the epoch, size)
+fn file_stamp(path: &Path) -> Option<(u64, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_nanos();
+    Some((u64::try_from(mtime).ok()?, meta.len()))
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| AUDIO_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Index a single file. Files whose tags cannot be read still get an entry (titled after the
+/// file name), together with a warning.
+pub fn read_entry(path: &Path) -> (LibraryEntry, Option<String>) {
+    let (mtime, size) = file_stamp(path).unwrap_or_default();
+    let mut entry = LibraryEntry {
+        track: Track::from_uri(&path.to_string_lossy(), SOURCE),
+        album_artist: None,
+        track_number: None,
+        disc_number: None,
+        year: None,
+        genre: None,
+        mtime,
+        size,
+    };
+    let warning = match read_tags(path, &mut entry) {
+        Ok(()) => None,
+        Err(e) => Some(format!("{}: {:#}", path.display(), e)),
+    };
+    if entry.track.title.is_none() {
+        entry.track.title = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .map(str::to_string);
+    }
+    (entry, warning)
+}
+
+fn read_tags(path: &Path, entry: &mut LibraryEntry) -> Result<()> {
+    let file = fs::File::open(path).context("open")?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("unsupported or corrupt audio file")?;
+
+    // Tags in front of the stream (ID3v2 on mp3) come with the probe, the rest from the
+    // container (Vorbis comments, MP4 atoms, FLAC blocks).
+    let mut tags: Vec<Tag> = Vec::new();
+    if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        tags.extend(rev.tags().iter().cloned());
+    }
+    if let Some(rev) = probed.format.metadata().current() {
+        tags.extend(rev.tags().iter().cloned());
+    }
+    for tag in &tags {
+        let Some(key) = tag.std_key else {
+            continue;
+        };
+        let value = tag.value.to_string();
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        let t = &mut entry.track;
+        // first occurrence wins
+        match key {
+            StandardTagKey::TrackTitle => set(&mut t.title, value),
+            StandardTagKey::Artist => set(&mut t.artist, value),
+            StandardTagKey::Album => set(&mut t.album, value),
+            StandardTagKey::AlbumArtist => set(&mut entry.album_artist, value),
+            StandardTagKey::Genre => set(&mut entry.genre, value),
+            StandardTagKey::TrackNumber => set_num(&mut entry.track_number, value),
+            StandardTagKey::DiscNumber => set_num(&mut entry.disc_number, value),
+            StandardTagKey::Date | StandardTagKey::ReleaseDate | StandardTagKey::OriginalDate => {
+                set_num(&mut entry.year, value.get(..4).unwrap_or(value))
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(track) = probed.format.default_track() {
+        let p = &track.codec_params;
+        if let (Some(frames), Some(tb)) = (p.n_frames, p.time_base) {
+            entry.track.duration = Some(tb.calc_time(frames).seconds);
+        } else if let (Some(frames), Some(rate)) = (p.n_frames, p.sample_rate) {
+            entry.track.duration = Some(frames / rate as u64);
+        }
+    }
+    Ok(())
+}
+
+fn set(field: &mut Option<String>, value: &str) {
+    if field.is_none() {
+        *field = Some(value.to_string());
+    }
+}
+
+// "3/12" style numbers keep the part before the slash.
+fn set_num(field: &mut Option<u32>, value: &str) {
+    if field.is_none() {
+        *field = value.split('/').next().and_then(|n| n.trim().parse().ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal FLAC: STREAMINFO (44.1kHz, 3s) plus a Vorbis comment block, no audio frames.
+    fn flac_bytes(comments: &[&str]) -> Vec<u8> {
+        let mut out = b"fLaC".to_vec();
+        out.extend([0x00, 0x00, 0x00, 34]);
+        out.extend(4096u16.to_be_bytes());
+        out.extend(4096u16.to_be_bytes());
+        out.extend([0; 6]);
+        // 20 bits sample rate, 3 bits channels-1, 5 bits bps-1, 36 bits total samples
+        let packed: u64 = (44_100 << 44) | (15 << 36) | 132_300;
+        out.extend(packed.to_be_bytes());
+        out.extend([0; 16]);
+
+        let mut block = Vec::new();
+        let vendor = b"test";
+        block.extend((vendor.len() as u32).to_le_bytes());
+        block.extend(vendor);
+        block.extend((comments.len() as u32).to_le_bytes());
+        for c in comments {
+            block.extend((c.len() as u32).to_le_bytes());
+            block.extend(c.as_bytes());
+        }
+        out.push(0x80 | 4);
+        out.extend(&(block.len() as u32).to_be_bytes()[1..]);
+        out.extend(block);
+
+        // one frame of silence: fixed 4096-sample block, 44.1kHz, mono, 16 bit, constant subframe
+        let mut frame = vec![0xff, 0xf8, 0xc9, 0x08, 0x00];
+        frame.push(crc(&frame, 0x07, 8) as u8);
+        frame.extend([0x00, 0x00, 0x00]);
+        frame.extend((crc(&frame, 0x8005, 16) as u16).to_be_bytes());
+        out.extend(frame);
+        out
+    }
+
+    fn crc(data: &[u8], poly: u32, width: u32) -> u32 {
+        let top = 1 << (width - 1);
+        let mask = (1u32 << width) - 1;
+        let mut c = 0u32;
+        for b in data {
+            c ^= (*b as u32) << (width - 8);
+            for _ in 0..8 {
+                c = if c & top != 0 {
+                    (c << 1) ^ poly
+                } else {
+                    c << 1
+                } & mask;
+            }
+        }
+        c
+    }
+
+    // ID3v2.3 tag followed by a few silent MPEG-1 layer III frames.
+    fn mp3_bytes(frames: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (id, text) in frames {
+            body.extend(id.as_bytes());
+            body.extend(((text.len() + 1) as u32).to_be_bytes());
+            body.extend([0, 0, 0]);
+            body.extend(text.as_bytes());
+        }
+        let n = body.len() as u32;
+        let mut out = b"ID3\x03\x00\x00".to_vec();
+        out.extend([
+            (n >> 21) as u8 & 0x7f,
+            (n >> 14) as u8 & 0x7f,
+            (n >> 7) as u8 & 0x7f,
+            n as u8 & 0x7f,
+        ]);
+        out.extend(body);
+        for _ in 0..8 {
+            let mut frame = vec![0u8; 417];
+            frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0xc4]);
+            out.extend(frame);
+        }
+        out
+    }
+
+    #[test]
+    fn scan_reads_tags_and_groups_albums() {
+        let dir = std::env::temp_dir().join(format!("apple-library-test-{}", std::process::id()));
+        let album = dir.join("Band").join("Record");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(
+            album.join("02.flac"),
+            flac_bytes(&[
+                "TITLE=Second",
+                "ARTIST=Band",
+                "ALBUM=Record",
+                "TRACKNUMBER=2/9",
+                "DATE=2001-05-01",
+                "GENRE=Shoegaze",
+            ]),
+        )
+        .unwrap();
+        fs::write(
+            album.join("01.mp3"),
+            mp3_bytes(&[
+                ("TIT2", "First"),
+                ("TPE1", "Band"),
+                ("TALB", "Record"),
+                ("TRCK", "1"),
+            ]),
+        )
+        .unwrap();
+        fs::write(dir.join("untagged.ogg"), b"not really ogg").unwrap();
+        fs::write(dir.join("cover.jpg"), b"jpeg").unwrap();
+
+        let (lib, warnings) = Library::scan(std::slice::from_ref(&dir));
+        assert_eq!(lib.entries.len(), 3, "{:?}", lib.entries);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+
+        let titles: Vec<_> = lib.tracks().map(|t| t.title.as_deref().unwrap()).collect();
+        // untagged files sort first and are named after the file
+        assert_eq!(titles, vec!["untagged", "First", "Second"]);
+        let second = &lib.entries[2];
+        assert_eq!(second.track_number, Some(2));
+        assert_eq!(second.year, Some(2001));
+        assert_eq!(second.track.duration, Some(3));
+        assert_eq!(second.track.source, SOURCE);
+
+        assert_eq!(lib.artists(), vec!["Band"]);
+        let albums = lib.albums();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].title, "Record");
+        assert_eq!(albums[0].tracks, vec![1, 2]);
+        assert_eq!(lib.by_artist("band"), vec![1, 2]);
+        assert_eq!(lib.genres(), vec!["Shoegaze"]);
+        assert_eq!(lib.albums_of(lib.by_genre("shoegaze"))[0].tracks, vec![2]);
+
+        let hits: Vec<_> = lib
+            .search("band sec")
+            .iter()
+            .map(|e| e.track.title.clone())
+            .collect();
+        assert_eq!(hits, vec![Some("Second".to_string())]);
+
+        let path = dir.join("library.json");
+        lib.save(&path).unwrap();
+        assert_eq!(Library::load(&path).unwrap().entries, lib.entries);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rescan_only_reads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("apple-rescan-test-{}", std::process::id()));
+        let sub = dir.join("sub");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(dir.join("a.mp3"), b"a").unwrap();
+        fs::write(dir.join("b.mp3"), b"b").unwrap();
+        fs::write(sub.join("c.mp3"), b"c").unwrap();
+
+        let roots = vec![dir.clone()];
+        let (mut lib, _) = Library::scan(&roots);
+        assert_eq!(lib.entries.len(), 3);
+
+        // a tag edit that the scanner picked up must survive a rescan of an unchanged file
+        lib.entries[0].genre = Some("kept".into());
+        let (stats, _) = lib.rescan(&roots);
+        assert_eq!(stats.unchanged, 3);
+        assert!(!stats.changed());
+        assert_eq!(lib.entries[0].genre.as_deref(), Some("kept"));
+
+        fs::write(dir.join("b.mp3"), b"bigger").unwrap();
+        fs::remove_file(dir.join("a.mp3")).unwrap();
+        fs::write(dir.join("d.mp3"), b"d").unwrap();
+        let (stats, _) = lib.rescan(&roots);
+        assert_eq!(
+            stats,
+            ScanStats {
+                added: 1,
+                updated: 1,
+                removed: 1,
+                unchanged: 1
+            }
+        );
+
+        // a deleted directory drops everything below it
+        fs::remove_dir_all(&sub).unwrap();
+        let (stats, _) = lib.refresh_paths(std::slice::from_ref(&sub));
+        assert_eq!(stats.removed, 1);
+        let titles: Vec<_> = lib.tracks().map(|t| t.title.clone().unwrap()).collect();
+        assert_eq!(titles, vec!["b", "d"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
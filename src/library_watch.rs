@@ -0,0 +1,147 @@
+// Keeps the library index current while the daemon runs: one incremental rescan at startup,
+// then (optionally) filesystem notifications (inotify on Linux) for the music directories.
+
+use crate::library::Library;
+use anyhow::{Context, Result};
+use notify::event::{AccessKind, AccessMode, EventKind};
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long the directories must be quiet before changes are applied, so a file that is
+/// still being downloaded is read once it is complete.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Directories the daemon keeps indexed: `music_dirs` from the config, else the ones the
+/// saved index was built from. Empty if the user never set up a library.
+pub fn library_roots(cfg: &crate::config::Config, index: &Path) -> Vec<PathBuf> {
+    if !cfg.music_dirs.is_empty() {
+        return cfg.music_dirs.clone();
+    }
+    Library::load(index).map(|l| l.roots).unwrap_or_default()
+}
+
+/// Rescan `roots` incrementally, save the index at `index` if anything changed, and with
+/// `watch` keep applying filesystem changes until the task is aborted.
+pub async fn maintain_library(roots: Vec<PathBuf>, index: PathBuf, watch: bool) -> Result<()> {
+    // start watching before the rescan so nothing that changes meanwhile is missed
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+    let _watcher = if watch {
+        Some(start_watcher(&roots, tx)?)
+    } else {
+        None
+    };
+
+    let lib = Library::load(&index).unwrap_or_default();
+    let scan_roots = roots.clone();
+    let mut lib = update(&index, lib, move |lib| lib.rescan(&scan_roots)).await?;
+    if !watch {
+        return Ok(());
+    }
+
+    while let Some(first) = rx.recv().await {
+        let mut paths = vec![first];
+        while let Ok(Some(p)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+            paths.push(p);
+        }
+        paths.sort();
+        paths.dedup();
+        lib = update(&index, lib, move |lib| lib.refresh_paths(&paths)).await?;
+    }
+    Ok(())
+}
+
+fn start_watcher(
+    roots: &[PathBuf],
+    tx: mpsc::UnboundedSender<PathBuf>,
+) -> Result<notify::RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let Ok(ev) = res else {
+            return;
+        };
+        let relevant = match ev.kind {
+            // a finished write; plain opens and reads do not change anything
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+            EventKind::Access(_) => false,
+            _ => true,
+        };
+        if relevant {
+            for p in ev.paths {
+                let _ = tx.send(p);
+            }
+        }
+    })
+    .context("starting file watcher")?;
+    for root in roots {
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            eprintln!("library: cannot watch {}: {}", root.display(), e);
+        }
+    }
+    println!(
+        "library: watching {} director(ies) for changes",
+        roots.len()
+    );
+    Ok(watcher)
+}
+
+// Apply `f` off the async runtime and save the index when it changed something.
+async fn update<F>(index: &Path, mut lib: Library, f: F) -> Result<Library>
+where
+    F: FnOnce(&mut Library) -> (crate::library::ScanStats, Vec<String>) + Send + 'static,
+{
+    let index = index.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let (stats, warnings) = f(&mut lib);
+        for w in warnings {
+            eprintln!("library: {}", w);
+        }
+        if stats.changed() {
+            println!("library: {}", stats);
+            lib.save(&index)?;
+        }
+        Ok(lib)
+    })
+    .await
+    .context("library update task failed")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait_for(index: &Path, f: impl Fn(&Library) -> bool) -> bool {
+        for _ in 0..100 {
+            if Library::load(index).is_ok_and(|l| f(&l)) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn new_and_deleted_files_update_the_index() {
+        let dir = std::env::temp_dir().join(format!("apple-watch-test-{}", std::process::id()));
+        let music = dir.join("music");
+        std::fs::create_dir_all(&music).unwrap();
+        std::fs::write(music.join("old.mp3"), b"x").unwrap();
+        let index = dir.join("library.json");
+
+        let task = tokio::spawn(maintain_library(vec![music.clone()], index.clone(), true));
+        assert!(wait_for(&index, |l| l.entries.len() == 1).await);
+
+        std::fs::create_dir_all(music.join("new album")).unwrap();
+        std::fs::write(music.join("new album").join("song.flac"), b"y").unwrap();
+        std::fs::remove_file(music.join("old.mp3")).unwrap();
+        assert!(
+            wait_for(&index, |l| {
+                l.tracks().map(|t| t.title.as_deref()).collect::<Vec<_>>() == vec![Some("song")]
+            })
+            .await
+        );
+
+        task.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
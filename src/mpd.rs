@@ -0,0 +1,1011 @@
+// MPD protocol listener, so MPD clients (mpc, ncmpcpp, phone remotes) can drive the daemon.
+//
+// Each connection speaks the MPD text protocol: one command per line, answered with
+// `key: value` lines and `OK`, or a single `ACK [error@index] {command} message` line. The
+// commands are mapped onto `Request`s and run through the daemon's `dispatch`, so they act on
+// the same `Player` and adapter as every other client and wake its subscribers.
+//
+// MPD's "playlist" is the current track followed by the queue, so position 0 is whatever is
+// playing while something is. Song ids are derived from track ids; a track queued twice shares
+// one. With APPLE_DAEMON_TOKEN set, clients have to send it with `password` first.
+
+use crate::daemon::{dispatch, live_state, ticker_every, Session, Shared};
+use crate::events::{Event, LiveState};
+use crate::playback::PlaybackState;
+use crate::player::RepeatMode;
+use crate::protocol::{ErrorCode, Request, Response};
+use crate::track::Track;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Display, Write as _};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::watch;
+
+/// Where `--mpd` listens when no address is given.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6600";
+/// Protocol version in the greeting.
+const VERSION: &str = "0.23.0";
+/// Connections are closed after this long without a command, except while in `idle`.
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// How often `idle` checks for changes made behind the daemon's back (volume set in mpv).
+const POLL: Duration = Duration::from_secs(1);
+
+/// What `commands` lists.
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "consume",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "getvol",
+    "idle",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistadd",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "rename",
+    "repeat",
+    "rm",
+    "save",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "status",
+    "stop",
+    "tagtypes",
+    "volume",
+];
+
+// MPD's error codes
+const ACK_ARG: u32 = 2;
+const ACK_PASSWORD: u32 = 3;
+const ACK_PERMISSION: u32 = 4;
+const ACK_UNKNOWN: u32 = 5;
+const ACK_NO_EXIST: u32 = 50;
+const ACK_SYSTEM: u32 = 52;
+
+/// A failed command.
+#[derive(Debug, PartialEq)]
+struct Ack {
+    code: u32,
+    msg: String,
+}
+
+impl Ack {
+    fn new(code: u32, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    fn arg(msg: impl Into<String>) -> Self {
+        Self::new(ACK_ARG, msg)
+    }
+
+    /// The error for a failed `Response`.
+    fn from_response(resp: Response) -> Self {
+        let code = match resp.code {
+            Some(ErrorCode::NotFound) => ACK_NO_EXIST,
+            Some(ErrorCode::Unauthorized | ErrorCode::Forbidden) => ACK_PERMISSION,
+            Some(ErrorCode::UnknownCommand) => ACK_UNKNOWN,
+            Some(
+                ErrorCode::InvalidParams | ErrorCode::ParseError | ErrorCode::UnsupportedVersion,
+            ) => ACK_ARG,
+            Some(ErrorCode::Failed) | None => ACK_SYSTEM,
+        };
+        Self::new(code, resp.msg)
+    }
+
+    /// The reply line for the `index`th command of a list (0 outside lists).
+    fn line(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{}] {{{}}} {}\n",
+            self.code, index, command, self.msg
+        )
+    }
+}
+
+/// The body of a successful reply, without the closing `OK`.
+type Reply = Result<String, Ack>;
+
+/// Serve one MPD client until it closes the connection or `stop` is set.
+pub(crate) async fn handle_connection<S>(
+    stream: S,
+    shared: Shared,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (r, mut w) = split(stream);
+    let mut lines = BufReader::new(r).lines();
+    w.write_all(format!("OK MPD {}\n", VERSION).as_bytes())
+        .await?;
+    let mut client = Client {
+        authorized: shared.token.is_none(),
+        seen: live_state(&shared).await,
+        shared,
+    };
+    // commands between command_list_begin and command_list_end, and whether each gets a
+    // list_OK
+    let mut list: Option<(Vec<String>, bool)> = None;
+    loop {
+        let line = tokio::select! {
+            _ = crate::daemon::stopped(&mut stop) => break,
+            line = tokio::time::timeout(TIMEOUT, lines.next_line()) => match line {
+                Ok(Ok(Some(line))) => line,
+                // timeout, EOF or read error
+                _ => break,
+            },
+        };
+        if let Some((commands, list_ok)) = &mut list {
+            if line == "command_list_end" {
+                let reply = client.run_list(commands, *list_ok).await;
+                list = None;
+                w.write_all(reply.as_bytes()).await?;
+            } else {
+                commands.push(line);
+            }
+            continue;
+        }
+        let args = match split_args(&line) {
+            Ok(args) => args,
+            Err(ack) => {
+                w.write_all(ack.line(0, "").as_bytes()).await?;
+                continue;
+            }
+        };
+        let name = args.first().map(String::as_str).unwrap_or_default();
+        let reply = match name {
+            "close" => break,
+            // only meaningful while idle
+            "noidle" => continue,
+            "command_list_begin" | "command_list_ok_begin" => {
+                list = Some((Vec::new(), name == "command_list_ok_begin"));
+                continue;
+            }
+            "idle" if client.authorized => {
+                let changed = tokio::select! {
+                    _ = crate::daemon::stopped(&mut stop) => break,
+                    changed = client.idle(&args[1..]) => changed,
+                    line = lines.next_line() => match line {
+                        Ok(Some(line)) if line == "noidle" => String::new(),
+                        // anything else while idle ends the connection, as in MPD
+                        _ => break,
+                    },
+                };
+                Ok(changed)
+            }
+            _ => client.command(&args).await,
+        };
+        let reply = match reply {
+            Ok(body) => body + "OK\n",
+            Err(ack) => ack.line(0, name),
+        };
+        w.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Per-connection state.
+struct Client {
+    shared: Shared,
+    /// Whether the token was given with `password` (or none is needed)
+    authorized: bool,
+    /// The state `idle` reports changes against
+    seen: LiveState,
+}
+
+impl Client {
+    /// Run a command list; stops at the first failure.
+    async fn run_list(&mut self, commands: &[String], list_ok: bool) -> String {
+        let mut out = String::new();
+        for (i, line) in commands.iter().enumerate() {
+            let args = match split_args(line) {
+                Ok(args) => args,
+                Err(ack) => return out + &ack.line(i, ""),
+            };
+            match self.command(&args).await {
+                Ok(body) => {
+                    out += &body;
+                    if list_ok {
+                        out += "list_OK\n";
+                    }
+                }
+                Err(ack) => return out + &ack.line(i, &args[0]),
+            }
+        }
+        out + "OK\n"
+    }
+
+    async fn command(&mut self, args: &[String]) -> Reply {
+        let Some((name, args)) = args.split_first() else {
+            return Err(Ack::new(ACK_UNKNOWN, "No command given"));
+        };
+        let name = name.as_str();
+        match name {
+            "password" => return self.password(arg(args, 0)?),
+            "ping" => return Ok(String::new()),
+            "commands" => {
+                return Ok(COMMANDS
+                    .iter()
+                    .map(|c| format!("command: {}\n", c))
+                    .collect())
+            }
+            "notcommands" => return Ok(String::new()),
+            _ => {}
+        }
+        if !self.authorized {
+            return Err(Ack::new(
+                ACK_PERMISSION,
+                format!("you don't have permission for \"{}\"", name),
+            ));
+        }
+        match name {
+            "status" => self.status().await,
+            "currentsong" => {
+                let st = self.state().await;
+                let mut out = String::new();
+                if let Some(t) = current(&st) {
+                    song(&mut out, t, Some(0));
+                }
+                Ok(out)
+            }
+            "playlistinfo" => {
+                let st = self.state().await;
+                let songs = playlist(&st);
+                let range = match args.first() {
+                    Some(r) => range(r, songs.len())?,
+                    None => 0..songs.len(),
+                };
+                if range.end > songs.len() {
+                    return Err(Ack::arg("Bad song index"));
+                }
+                let mut out = String::new();
+                for pos in range {
+                    song(&mut out, songs[pos], Some(pos));
+                }
+                Ok(out)
+            }
+            "playlistid" => {
+                let st = self.state().await;
+                let wanted = args.first().map(|id| number::<u32>(id)).transpose()?;
+                let mut out = String::new();
+                for (pos, t) in playlist(&st).into_iter().enumerate() {
+                    if wanted.is_none_or(|id| id == song_id(t)) {
+                        song(&mut out, t, Some(pos));
+                    }
+                }
+                if wanted.is_some() && out.is_empty() {
+                    return Err(Ack::new(ACK_NO_EXIST, "No such song"));
+                }
+                Ok(out)
+            }
+            // there is no change log, so every change is the whole playlist
+            "plchanges" => {
+                let st = self.state().await;
+                let mut out = String::new();
+                for (pos, t) in playlist(&st).into_iter().enumerate() {
+                    song(&mut out, t, Some(pos));
+                }
+                Ok(out)
+            }
+            "plchangesposid" => {
+                let st = self.state().await;
+                let mut out = String::new();
+                for (pos, t) in playlist(&st).into_iter().enumerate() {
+                    field(&mut out, "cpos", pos);
+                    field(&mut out, "Id", song_id(t));
+                }
+                Ok(out)
+            }
+            "play" => {
+                let pos = args.first().map(|p| number::<usize>(p)).transpose()?;
+                self.play(pos).await
+            }
+            "playid" => {
+                let st = self.state().await;
+                let pos = match args.first() {
+                    Some(id) => Some(position_of(&st, number(id)?)?),
+                    None => None,
+                };
+                self.play(pos).await
+            }
+            "pause" => {
+                let paused = args.first().map(|b| flag(b)).transpose()?;
+                let state = self.state().await.status.state;
+                let toggle = match state {
+                    PlaybackState::Playing => paused != Some(false),
+                    PlaybackState::Paused => paused != Some(true),
+                    _ => false,
+                };
+                if toggle {
+                    self.run(Request::Pause).await?;
+                }
+                Ok(String::new())
+            }
+            // adapters have no separate stop
+            "stop" => {
+                if self.state().await.status.state == PlaybackState::Playing {
+                    self.run(Request::Pause).await?;
+                }
+                Ok(String::new())
+            }
+            "next" => self.run(Request::Next).await,
+            "previous" => self.run(Request::Prev).await,
+            "add" => {
+                let uri = arg(args, 0)?.to_string();
+                self.run(Request::Enqueue { uri }).await
+            }
+            "addid" => {
+                let uri = arg(args, 0)?.to_string();
+                self.run(Request::Enqueue { uri }).await?;
+                let st = self.state().await;
+                let last = st.queue.len() - 1;
+                if let Some(pos) = args.get(1) {
+                    let to = queue_index(&st, number(pos)?)?;
+                    self.run(Request::Move { from: last, to }).await?;
+                }
+                let mut out = String::new();
+                field(&mut out, "Id", song_id(&st.queue[last]));
+                Ok(out)
+            }
+            "delete" => {
+                let st = self.state().await;
+                let range = range(arg(args, 0)?, playlist(&st).len())?;
+                let indices = range
+                    .map(|pos| queue_index(&st, pos))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // from the back, so the earlier indices stay valid
+                for index in indices.into_iter().rev() {
+                    self.run(Request::Remove { index }).await?;
+                }
+                Ok(String::new())
+            }
+            "deleteid" => {
+                let st = self.state().await;
+                let index = queue_index(&st, position_of(&st, number(arg(args, 0)?)?)?)?;
+                self.run(Request::Remove { index }).await
+            }
+            "clear" => self.run(Request::Clear).await,
+            "move" | "moveid" => {
+                let st = self.state().await;
+                let from = match name {
+                    "move" => number(arg(args, 0)?)?,
+                    _ => position_of(&st, number(arg(args, 0)?)?)?,
+                };
+                let from = queue_index(&st, from)?;
+                let to = queue_index(&st, number(arg(args, 1)?)?)?;
+                self.run(Request::Move { from, to }).await
+            }
+            "setvol" => {
+                let volume = number::<u8>(arg(args, 0)?)?.min(100);
+                self.run(Request::SetVolume { volume }).await
+            }
+            "volume" => {
+                let change = number::<i32>(arg(args, 0)?)?;
+                let now = self.state().await.status.volume.unwrap_or(0);
+                let volume = (i32::from(now) + change).clamp(0, 100) as u8;
+                self.run(Request::SetVolume { volume }).await
+            }
+            "getvol" => {
+                let mut out = String::new();
+                field(&mut out, "volume", volume(&self.state().await));
+                Ok(out)
+            }
+            "seekcur" => {
+                let to = arg(args, 0)?;
+                let secs = |s: &str| number::<f64>(s).map(|s| s.round() as u64);
+                let request = if let Some(s) = to.strip_prefix('+') {
+                    Request::SeekForward { seconds: secs(s)? }
+                } else if let Some(s) = to.strip_prefix('-') {
+                    Request::SeekBackward { seconds: secs(s)? }
+                } else {
+                    Request::SeekTo { seconds: secs(to)? }
+                };
+                self.run(request).await
+            }
+            "seek" | "seekid" => {
+                let st = self.state().await;
+                let pos = match name {
+                    "seek" => number(arg(args, 0)?)?,
+                    _ => position_of(&st, number(arg(args, 0)?)?)?,
+                };
+                let seconds = number::<f64>(arg(args, 1)?)?.round() as u64;
+                if current(&st).is_none() || pos > 0 {
+                    self.play(Some(pos)).await?;
+                }
+                self.run(Request::SeekTo { seconds }).await
+            }
+            "repeat" | "single" => {
+                let on = flag(arg(args, 0)?)?;
+                let now = self.state().await.status.repeat;
+                let mode = match (name, on, now) {
+                    ("repeat", false, _) => RepeatMode::Off,
+                    ("repeat", true, RepeatMode::One) | ("single", true, _) => RepeatMode::One,
+                    ("repeat", true, _) | ("single", false, RepeatMode::One) => RepeatMode::All,
+                    (_, _, mode) => mode,
+                };
+                self.run(Request::Repeat { mode }).await
+            }
+            "random" => {
+                let on = flag(arg(args, 0)?)?;
+                self.run(Request::Shuffle { on, seed: None }).await
+            }
+            "consume" => match flag(arg(args, 0)?)? {
+                false => Ok(String::new()),
+                true => Err(Ack::arg("consume mode is not supported")),
+            },
+            "listplaylists" => {
+                let mut out = String::new();
+                for name in self.state().await.playlists {
+                    field(&mut out, "playlist", name);
+                }
+                Ok(out)
+            }
+            "listplaylist" | "listplaylistinfo" => {
+                let playlist = arg(args, 0)?.to_string();
+                let resp = self
+                    .request(Request::PlaylistShow { name: playlist })
+                    .await?;
+                let mut out = String::new();
+                for t in resp.tracks.unwrap_or_default() {
+                    if name == "listplaylistinfo" {
+                        song(&mut out, &t, None);
+                    } else {
+                        field(&mut out, "file", &t.uri);
+                    }
+                }
+                Ok(out)
+            }
+            "load" => {
+                let name = arg(args, 0)?.to_string();
+                self.run(Request::PlaylistLoad { name }).await
+            }
+            "save" => {
+                let name = arg(args, 0)?.to_string();
+                self.run(Request::PlaylistSave { name }).await
+            }
+            "rm" => {
+                let name = arg(args, 0)?.to_string();
+                self.run(Request::PlaylistDelete { name }).await
+            }
+            "rename" => {
+                let from = arg(args, 0)?.to_string();
+                let to = arg(args, 1)?.to_string();
+                self.run(Request::PlaylistRename { from, to }).await
+            }
+            "playlistadd" => {
+                let name = arg(args, 0)?.to_string();
+                let uri = Some(arg(args, 1)?.to_string());
+                self.run(Request::PlaylistAdd { name, uri }).await
+            }
+            "outputs" => Ok("outputid: 0\noutputname: apple\noutputenabled: 1\n".into()),
+            "tagtypes" => match args.first() {
+                // clients may narrow the tags down; every one we have is always sent
+                Some(_) => Ok(String::new()),
+                None => Ok(["Artist", "Album", "Title"]
+                    .iter()
+                    .map(|t| format!("tagtype: {}\n", t))
+                    .collect()),
+            },
+            "idle" | "noidle" | "close" | "command_list_begin" | "command_list_ok_begin" => Err(
+                Ack::arg(format!("\"{}\" is not allowed in a command list", name)),
+            ),
+            _ => Err(Ack::new(
+                ACK_UNKNOWN,
+                format!("unknown command \"{}\"", name),
+            )),
+        }
+    }
+
+    fn password(&mut self, given: &str) -> Reply {
+        match &self.shared.token {
+            Some(token) if token != given => Err(Ack::new(ACK_PASSWORD, "incorrect password")),
+            _ => {
+                self.authorized = true;
+                Ok(String::new())
+            }
+        }
+    }
+
+    async fn state(&self) -> LiveState {
+        live_state(&self.shared).await
+    }
+
+    async fn request(&self, request: Request) -> Result<Response, Ack> {
+        // `password` already checked the token
+        let token = self.shared.token.as_deref();
+        let resp = dispatch(Ok(request), token, &self.shared, &mut Session::default()).await;
+        if resp.ok {
+            Ok(resp)
+        } else {
+            Err(Ack::from_response(resp))
+        }
+    }
+
+    async fn run(&self, request: Request) -> Reply {
+        self.request(request).await.map(|_| String::new())
+    }
+
+    /// Play playlist position `pos`; without one, resume or start the queue.
+    async fn play(&self, pos: Option<usize>) -> Reply {
+        let st = self.state().await;
+        match (pos, current(&st)) {
+            (None | Some(0), Some(_)) if st.status.state == PlaybackState::Paused => {
+                self.run(Request::Pause).await
+            }
+            (None | Some(0), Some(_)) => Ok(String::new()),
+            (None, None) => self.run(Request::Next).await,
+            (Some(pos), _) => {
+                let index = queue_index(&st, pos)?;
+                self.run(Request::PlayIndex { index }).await
+            }
+        }
+    }
+
+    /// Wait until one of the `wanted` subsystems (any when empty) changes and name those that
+    /// did, as `changed:` lines.
+    async fn idle(&mut self, wanted: &[String]) -> String {
+        let mut changes = self.shared.changes.subscribe();
+        let mut poll = ticker_every(POLL);
+        loop {
+            let now = self.state().await;
+            let changed: Vec<_> = subsystems(&now.events_since(Some(&self.seen)))
+                .into_iter()
+                .filter(|s| wanted.is_empty() || wanted.iter().any(|w| w == s))
+                .collect();
+            if !changed.is_empty() {
+                self.seen = now;
+                return changed
+                    .iter()
+                    .map(|s| format!("changed: {}\n", s))
+                    .collect();
+            }
+            tokio::select! {
+                _ = changes.changed() => {}
+                _ = poll.tick() => {}
+            }
+        }
+    }
+
+    async fn status(&self) -> Reply {
+        let st = self.state().await;
+        let s = &st.status;
+        let songs = playlist(&st);
+        let mut out = String::new();
+        field(&mut out, "volume", volume(&st));
+        field(&mut out, "repeat", u8::from(s.repeat != RepeatMode::Off));
+        field(&mut out, "random", u8::from(s.shuffle));
+        field(&mut out, "single", u8::from(s.repeat == RepeatMode::One));
+        field(&mut out, "consume", 0);
+        field(&mut out, "playlist", version(&songs));
+        field(&mut out, "playlistlength", songs.len());
+        let state = match s.state {
+            PlaybackState::Playing => "play",
+            PlaybackState::Paused => "pause",
+            PlaybackState::Stopped | PlaybackState::Idle => "stop",
+        };
+        field(&mut out, "state", state);
+        if let Some(t) = current(&st) {
+            field(&mut out, "song", 0);
+            field(&mut out, "songid", song_id(t));
+            let elapsed = s.position.unwrap_or(0);
+            let duration = s.duration.or(t.duration);
+            field(
+                &mut out,
+                "time",
+                format!("{}:{}", elapsed, duration.unwrap_or(0)),
+            );
+            field(&mut out, "elapsed", format!("{}.000", elapsed));
+            if let Some(d) = duration {
+                field(&mut out, "duration", format!("{}.000", d));
+            }
+            if let Some(next) = songs.get(1) {
+                field(&mut out, "nextsong", 1);
+                field(&mut out, "nextsongid", song_id(next));
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn field(out: &mut String, key: &str, value: impl Display) {
+    let _ = writeln!(out, "{}: {}", key, value);
+}
+
+/// A song's tags, plus its position and id when it is in the playlist.
+fn song(out: &mut String, t: &Track, pos: Option<usize>) {
+    field(out, "file", &t.uri);
+    for (key, value) in [
+        ("Title", &t.title),
+        ("Artist", &t.artist),
+        ("Album", &t.album),
+    ] {
+        if let Some(v) = value {
+            field(out, key, v);
+        }
+    }
+    if let Some(d) = t.duration {
+        field(out, "Time", d);
+        field(out, "duration", format!("{}.000", d));
+    }
+    if let Some(pos) = pos {
+        field(out, "Pos", pos);
+        field(out, "Id", song_id(t));
+    }
+}
+
+/// The track playing or paused; MPD's playlist position 0.
+fn current(st: &LiveState) -> Option<&Track> {
+    match st.status.state {
+        PlaybackState::Playing | PlaybackState::Paused => st.status.track.as_ref(),
+        _ => None,
+    }
+}
+
+/// MPD's playlist: the current track, if any, then the queue.
+fn playlist(st: &LiveState) -> Vec<&Track> {
+    current(st).into_iter().chain(&st.queue).collect()
+}
+
+/// The queue index of playlist position `pos`.
+fn queue_index(st: &LiveState, pos: usize) -> Result<usize, Ack> {
+    match (current(st), pos) {
+        (Some(_), 0) => Err(Ack::arg("the current song is not in the queue")),
+        (Some(_), pos) => Ok(pos - 1),
+        (None, pos) => Ok(pos),
+    }
+}
+
+fn position_of(st: &LiveState, id: u32) -> Result<usize, Ack> {
+    playlist(st)
+        .into_iter()
+        .position(|t| song_id(t) == id)
+        .ok_or_else(|| Ack::new(ACK_NO_EXIST, "No such song"))
+}
+
+fn hash(value: impl Hash) -> u32 {
+    let mut h = DefaultHasher::new();
+    value.hash(&mut h);
+    // clients read these as signed ints
+    h.finish() as u32 & 0x7fff_ffff
+}
+
+fn song_id(t: &Track) -> u32 {
+    hash(&t.id)
+}
+
+/// Stands in for MPD's playlist version: changes whenever the playlist does.
+fn version(songs: &[&Track]) -> u32 {
+    hash(songs.iter().map(|t| &t.id).collect::<Vec<_>>())
+}
+
+fn volume(st: &LiveState) -> i32 {
+    match (st.status.volume, st.status.muted) {
+        (_, Some(true)) => 0,
+        (Some(v), _) => i32::from(v),
+        // unknown
+        (None, _) => -1,
+    }
+}
+
+/// The MPD subsystems that `events` touch.
+fn subsystems(events: &[Event]) -> Vec<&'static str> {
+    let mut subs = Vec::new();
+    for event in events {
+        let touched: &[&str] = match event {
+            // the old track leaves the playlist's head
+            Event::Track { .. } => &["player", "playlist"],
+            Event::State { .. } => &["player"],
+            Event::Volume { .. } => &["mixer"],
+            Event::Modes { .. } => &["options"],
+            Event::Queue { .. } => &["playlist"],
+            Event::Playlists { .. } => &["stored_playlist"],
+            Event::History { .. } | Event::Position { .. } => &[],
+        };
+        for s in touched {
+            if !subs.contains(s) {
+                subs.push(*s);
+            }
+        }
+    }
+    subs
+}
+
+/// Split a command line into words; a word in double quotes may contain spaces and
+/// backslash-escaped quotes and backslashes.
+fn split_args(line: &str) -> Result<Vec<String>, Ack> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        let Some(&c) = chars.peek() else { break };
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.push(chars.next().unwrap_or('\\')),
+                    Some(c) => word.push(c),
+                    None => return Err(Ack::arg("Missing closing '\"'")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_ascii_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn arg(args: &[String], i: usize) -> Result<&str, Ack> {
+    args.get(i)
+        .map(String::as_str)
+        .ok_or_else(|| Ack::arg("wrong number of arguments"))
+}
+
+fn number<T: FromStr>(s: &str) -> Result<T, Ack> {
+    s.parse()
+        .map_err(|_| Ack::arg(format!("Number expected: {}", s)))
+}
+
+fn flag(s: &str) -> Result<bool, Ack> {
+    match s {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Ack::arg(format!("Boolean (0/1) expected: {}", s))),
+    }
+}
+
+/// `pos`, or `start:end` with an exclusive end that defaults to `len`.
+fn range(s: &str, len: usize) -> Result<Range<usize>, Ack> {
+    match s.split_once(':') {
+        None => {
+            let pos = number(s)?;
+            Ok(pos..pos + 1)
+        }
+        Some((start, "")) => Ok(number(start)?..len),
+        Some((start, end)) => Ok(number(start)?..number(end)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::{PlaybackAdapter, PlaybackStatus};
+    use crate::player::Player;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{DuplexStream, Lines, ReadHalf, WriteHalf};
+
+    #[test]
+    fn arguments_split_on_spaces_outside_quotes() {
+        let args = split_args(r#"add  "My Music/a \"b\".mp3" x\y"#).unwrap();
+        assert_eq!(args, vec!["add", r#"My Music/a "b".mp3"#, r"x\y"]);
+        assert!(split_args(r#"add "open"#).is_err());
+        assert!(split_args("   ").unwrap().is_empty());
+        assert_eq!(range("2", 5).unwrap(), 2..3);
+        assert_eq!(range("1:", 5).unwrap(), 1..5);
+        assert_eq!(range("0:2", 5).unwrap(), 0..2);
+    }
+
+    // Adapter that keeps the state it is told to be in.
+    #[derive(Clone, Default)]
+    struct StateAdapter(Arc<Mutex<PlaybackStatus>>);
+
+    #[async_trait::async_trait]
+    impl PlaybackAdapter for StateAdapter {
+        async fn search(&mut self, _query: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+        async fn play(&mut self, track_id: Option<&str>) -> anyhow::Result<()> {
+            let mut st = self.0.lock().unwrap();
+            st.state = PlaybackState::Playing;
+            st.track = track_id.map(|uri| Track::from_uri(uri, "test"));
+            st.position = Some(0);
+            st.duration = Some(200);
+            Ok(())
+        }
+        async fn pause(&mut self) -> anyhow::Result<()> {
+            let mut st = self.0.lock().unwrap();
+            st.state = match st.state {
+                PlaybackState::Playing => PlaybackState::Paused,
+                _ => PlaybackState::Playing,
+            };
+            Ok(())
+        }
+        async fn next(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn prev(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn status(&mut self) -> anyhow::Result<PlaybackStatus> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+        async fn set_volume(&mut self, volume: u8) -> anyhow::Result<()> {
+            self.0.lock().unwrap().volume = Some(volume);
+            Ok(())
+        }
+        async fn seek_to(&mut self, seconds: u64) -> anyhow::Result<()> {
+            self.0.lock().unwrap().position = Some(seconds);
+            Ok(())
+        }
+    }
+
+    struct TestClient {
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+        w: WriteHalf<DuplexStream>,
+    }
+
+    impl TestClient {
+        async fn connect(shared: &Shared, stop: &watch::Receiver<bool>) -> Self {
+            let (ours, theirs) = tokio::io::duplex(1 << 16);
+            tokio::spawn(handle_connection(theirs, shared.clone(), stop.clone()));
+            let (r, w) = split(ours);
+            let mut client = Self {
+                lines: BufReader::new(r).lines(),
+                w,
+            };
+            assert_eq!(client.line().await, "OK MPD 0.23.0");
+            client
+        }
+
+        async fn line(&mut self) -> String {
+            let line = tokio::time::timeout(Duration::from_secs(5), self.lines.next_line());
+            line.await.unwrap().unwrap().unwrap()
+        }
+
+        async fn send(&mut self, lines: &str) {
+            self.w.write_all(lines.as_bytes()).await.unwrap();
+            self.w.write_all(b"\n").await.unwrap();
+        }
+
+        /// The reply lines up to and including `OK` or `ACK`.
+        async fn reply(&mut self) -> Vec<String> {
+            let mut reply = Vec::new();
+            loop {
+                let line = self.line().await;
+                let done = line == "OK" || line.starts_with("ACK ");
+                reply.push(line);
+                if done {
+                    return reply;
+                }
+            }
+        }
+
+        async fn cmd(&mut self, lines: &str) -> Vec<String> {
+            self.send(lines).await;
+            self.reply().await
+        }
+
+        /// The value of `key` in a successful reply.
+        async fn get(&mut self, command: &str, key: &str) -> Option<String> {
+            let reply = self.cmd(command).await;
+            assert_eq!(reply.last().map(String::as_str), Some("OK"), "{:?}", reply);
+            let prefix = format!("{}: ", key);
+            reply
+                .iter()
+                .find_map(|l| l.strip_prefix(&prefix))
+                .map(str::to_string)
+        }
+    }
+
+    #[tokio::test]
+    async fn mpd_clients_drive_the_player() {
+        let shared = Shared::for_test(Player::new(Box::new(StateAdapter::default())));
+        let (stop_tx, stop) = watch::channel(false);
+        let mut c = TestClient::connect(&shared, &stop).await;
+
+        assert_eq!(c.cmd("ping").await, vec!["OK"]);
+        let r = c.cmd("status").await;
+        assert!(r[0].starts_with("ACK [4@0] {status}"), "{:?}", r);
+        let r = c.cmd("password nope").await;
+        assert!(r[0].starts_with("ACK [3@0] {password}"), "{:?}", r);
+        assert_eq!(c.cmd("password secret").await, vec!["OK"]);
+
+        assert_eq!(c.cmd("add a.mp3").await, vec!["OK"]);
+        assert_eq!(c.cmd(r#"add "b c.mp3""#).await, vec!["OK"]);
+        let files: Vec<_> = c
+            .cmd("playlistinfo")
+            .await
+            .into_iter()
+            .filter(|l| l.starts_with("file: ") || l.starts_with("Pos: "))
+            .collect();
+        assert_eq!(files, ["file: a.mp3", "Pos: 0", "file: b c.mp3", "Pos: 1"]);
+        assert_eq!(c.get("status", "state").await.as_deref(), Some("stop"));
+
+        // the playing track is position 0, the rest of the queue follows
+        assert_eq!(c.cmd("play").await, vec!["OK"]);
+        assert_eq!(c.get("status", "state").await.as_deref(), Some("play"));
+        assert_eq!(c.get("status", "song").await.as_deref(), Some("0"));
+        assert_eq!(
+            c.get("status", "playlistlength").await.as_deref(),
+            Some("2")
+        );
+        assert_eq!(c.get("currentsong", "file").await.as_deref(), Some("a.mp3"));
+        let r = c.cmd("delete 0").await;
+        assert!(r[0].starts_with("ACK [2@0] {delete}"), "{:?}", r);
+
+        assert_eq!(c.cmd("setvol 30").await, vec!["OK"]);
+        assert_eq!(c.get("status", "volume").await.as_deref(), Some("30"));
+        assert_eq!(c.cmd("seekcur 42").await, vec!["OK"]);
+        assert_eq!(c.get("status", "elapsed").await.as_deref(), Some("42.000"));
+        assert_eq!(c.cmd("pause 1").await, vec!["OK"]);
+        assert_eq!(c.cmd("pause 1").await, vec!["OK"]);
+        assert_eq!(c.get("status", "state").await.as_deref(), Some("pause"));
+        assert_eq!(c.cmd("pause").await, vec!["OK"]);
+        assert_eq!(c.get("status", "state").await.as_deref(), Some("play"));
+
+        assert_eq!(
+            c.cmd("command_list_ok_begin\nrepeat 1\nsingle 1\ncommand_list_end")
+                .await,
+            vec!["list_OK", "list_OK", "OK"]
+        );
+        assert_eq!(c.get("status", "repeat").await.as_deref(), Some("1"));
+        assert_eq!(c.get("status", "single").await.as_deref(), Some("1"));
+        assert_eq!(
+            c.cmd("command_list_begin\nping\nbogus\ncommand_list_end")
+                .await,
+            vec![r#"ACK [5@1] {bogus} unknown command "bogus""#]
+        );
+
+        // idle reports what other clients changed, and noidle ends it
+        let mut other = TestClient::connect(&shared, &stop).await;
+        assert_eq!(other.cmd("password secret").await, vec!["OK"]);
+        // everything this client did so far is still pending
+        let pending = c.cmd("idle").await;
+        assert!(
+            pending.contains(&"changed: playlist".to_string()),
+            "{:?}",
+            pending
+        );
+        c.send("idle playlist").await;
+        assert_eq!(other.cmd("add d.mp3").await, vec!["OK"]);
+        assert_eq!(c.reply().await, vec!["changed: playlist", "OK"]);
+        c.send("idle").await;
+        c.send("noidle").await;
+        assert_eq!(c.reply().await, vec!["OK"]);
+        assert_eq!(c.cmd("next").await, vec!["OK"]);
+        assert_eq!(
+            c.get("currentsong", "file").await.as_deref(),
+            Some("b c.mp3")
+        );
+
+        stop_tx.send_replace(true);
+    }
+}
@@ -0,0 +1,638 @@
+// MPRIS2 on the D-Bus session bus, so desktop media keys, GNOME/KDE widgets and `playerctl`
+// can drive the daemon.
+//
+// `org.mpris.MediaPlayer2` and `org.mpris.MediaPlayer2.Player` are served at
+// /org/mpris/MediaPlayer2 under the name org.mpris.MediaPlayer2.apple (with an `.instance<pid>`
+// suffix when another daemon holds it). Methods go through the daemon's `dispatch` like any
+// other client; property changes are announced from the same change notifications and state
+// diffs that feed `subscribe`. Adapters have no separate stop, so `Stop` pauses.
+
+use crate::daemon::{changed_events, dispatch, stopped, Session, Shared};
+use crate::events::{Event, LiveState};
+use crate::playback::{PlaybackState, PlaybackStatus};
+use crate::player::RepeatMode;
+use crate::protocol::{Request, Response};
+use crate::track::Track;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use zbus::fdo;
+use zbus::object_server::{InterfaceRef, SignalEmitter};
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{interface, Connection};
+
+pub const BUS_NAME: &str = "org.mpris.MediaPlayer2.apple";
+pub const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// How often the state is checked for changes made behind the daemon's back (volume set in
+/// mpv, the position moving).
+const POLL: Duration = Duration::from_secs(1);
+/// A position this many seconds away from where playback should be counts as a seek.
+const SEEK_SLACK: u64 = 2;
+
+/// Connect to the session bus. The objects are only exported, and the name requested, by
+/// `serve`.
+pub async fn connect() -> zbus::Result<Connection> {
+    Connection::session().await
+}
+
+/// Export the MPRIS objects on `conn`, take a bus name and announce changes until `stop` is
+/// set.
+pub(crate) async fn serve(
+    conn: Connection,
+    shared: Shared,
+    mut stop: watch::Receiver<bool>,
+) -> zbus::Result<()> {
+    let server = conn.object_server();
+    server.at(OBJECT_PATH, Root).await?;
+    server
+        .at(
+            OBJECT_PATH,
+            Controls {
+                shared: shared.clone(),
+            },
+        )
+        .await?;
+    let name = match conn.request_name(BUS_NAME).await {
+        Ok(()) => BUS_NAME.to_string(),
+        Err(_) => {
+            let name = format!("{}.instance{}", BUS_NAME, std::process::id());
+            conn.request_name(name.as_str()).await?;
+            name
+        }
+    };
+    println!("daemon: MPRIS as {}", name);
+    let controls = server.interface::<_, Controls>(OBJECT_PATH).await?;
+
+    let mut changes = shared.changes.subscribe();
+    let mut seen: Option<LiveState> = None;
+    // last known position and when it was read, to tell seeks from playback
+    let mut last: Option<(u64, Instant)> = None;
+    loop {
+        let first = seen.is_none();
+        let events = changed_events(&shared, &mut seen).await;
+        if !first {
+            announce(&controls, &events).await?;
+        }
+        let now = seen.as_ref().map(|s| &s.status);
+        let position = now.and_then(|s| s.position);
+        let playing = now.is_some_and(|s| s.state == PlaybackState::Playing);
+        let new_track = events.iter().any(|e| matches!(e, Event::Track { .. }));
+        if let (Some(pos), Some((before, at)), false) = (position, last, new_track) {
+            let expected = before + if playing { at.elapsed().as_secs() } else { 0 };
+            if pos.abs_diff(expected) > SEEK_SLACK {
+                Controls::seeked(controls.signal_emitter(), micros(pos)).await?;
+            }
+        }
+        last = position.map(|p| (p, Instant::now()));
+
+        tokio::select! {
+            _ = stopped(&mut stop) => break,
+            r = changes.changed() => if r.is_err() { break },
+            _ = tokio::time::sleep(POLL) => {}
+        }
+    }
+    conn.release_name(name.as_str()).await?;
+    Ok(())
+}
+
+// PropertiesChanged for whatever the events touched.
+async fn announce(controls: &InterfaceRef<Controls>, events: &[Event]) -> zbus::Result<()> {
+    let iface = controls.get().await;
+    let emitter = controls.signal_emitter();
+    for event in events {
+        match event {
+            Event::Track { .. } => iface.metadata_changed(emitter).await?,
+            Event::State { .. } => iface.playback_status_changed(emitter).await?,
+            Event::Volume { .. } => iface.volume_changed(emitter).await?,
+            Event::Modes { .. } => {
+                iface.loop_status_changed(emitter).await?;
+                iface.shuffle_changed(emitter).await?;
+            }
+            Event::Queue { .. } => iface.can_go_next_changed(emitter).await?,
+            Event::History { .. } | Event::Playlists { .. } | Event::Position { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// `org.mpris.MediaPlayer2`: identity only; there is no window to raise.
+struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn identity(&self) -> &str {
+        "apple"
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".into(), "https".into()]
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/flac",
+            "audio/ogg",
+            "audio/mp4",
+            "audio/wav",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`, bridged to the daemon's player.
+struct Controls {
+    shared: Shared,
+}
+
+impl Controls {
+    async fn run(&self, request: Request) -> fdo::Result<Response> {
+        // the session bus belongs to the user the daemon runs as, so no token is asked for
+        let token = self.shared.token.as_deref();
+        let resp = dispatch(Ok(request), token, &self.shared, &mut Session::default()).await;
+        if resp.ok {
+            Ok(resp)
+        } else {
+            Err(fdo::Error::Failed(resp.msg))
+        }
+    }
+
+    async fn status(&self) -> PlaybackStatus {
+        let mut pl = self.shared.player.lock().await;
+        pl.status().await.unwrap_or_default()
+    }
+
+    // `pause` toggles, so look before pressing it
+    async fn set_paused(&self, paused: bool) -> fdo::Result<()> {
+        match (self.status().await.state, paused) {
+            (PlaybackState::Playing, true) | (PlaybackState::Paused, false) => {
+                self.run(Request::Pause).await?;
+            }
+            (PlaybackState::Stopped | PlaybackState::Idle, false) => {
+                self.run(Request::Next).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Controls {
+    async fn next(&self) -> fdo::Result<()> {
+        self.run(Request::Next).await.map(drop)
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.run(Request::Prev).await.map(drop)
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.set_paused(true).await
+    }
+
+    async fn play(&self) -> fdo::Result<()> {
+        self.set_paused(false).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        let playing = self.status().await.state == PlaybackState::Playing;
+        self.set_paused(playing).await
+    }
+
+    async fn stop(&self) -> fdo::Result<()> {
+        self.set_paused(true).await
+    }
+
+    /// Move by `offset` microseconds
+    async fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let seconds = (offset.unsigned_abs() as f64 / 1e6).round() as u64;
+        let request = if offset < 0 {
+            Request::SeekBackward { seconds }
+        } else {
+            Request::SeekForward { seconds }
+        };
+        self.run(request).await.map(drop)
+    }
+
+    /// Jump to `position` microseconds, if `track_id` is still the current track
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let st = self.status().await;
+        if track_id.as_str() != track_path(st.track.as_ref()).as_str() || position < 0 {
+            return Ok(());
+        }
+        let seconds = (position as f64 / 1e6).round() as u64;
+        if st.duration.is_some_and(|d| seconds > d) {
+            return Ok(());
+        }
+        self.run(Request::SeekTo { seconds }).await.map(drop)
+    }
+
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        self.run(Request::Play { uri }).await.map(drop)
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    async fn playback_status(&self) -> String {
+        match self.status().await.state {
+            PlaybackState::Playing => "Playing",
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped | PlaybackState::Idle => "Stopped",
+        }
+        .into()
+    }
+
+    #[zbus(property)]
+    async fn loop_status(&self) -> String {
+        loop_status(self.shared.player.lock().await.repeat()).into()
+    }
+
+    #[zbus(property)]
+    async fn set_loop_status(&mut self, status: String) -> fdo::Result<()> {
+        let Some(mode) = repeat_mode(&status) else {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "unknown loop status '{}'",
+                status
+            )));
+        };
+        self.run(Request::Repeat { mode }).await.map(drop)
+    }
+
+    #[zbus(property)]
+    async fn shuffle(&self) -> bool {
+        self.shared.player.lock().await.shuffle()
+    }
+
+    #[zbus(property)]
+    async fn set_shuffle(&mut self, on: bool) -> fdo::Result<()> {
+        self.run(Request::Shuffle { on, seed: None })
+            .await
+            .map(drop)
+    }
+
+    #[zbus(property)]
+    async fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let st = self.status().await;
+        metadata(st.track.as_ref(), st.duration)
+    }
+
+    /// 0.0 to 1.0
+    #[zbus(property)]
+    async fn volume(&self) -> f64 {
+        self.status()
+            .await
+            .volume
+            .map_or(1.0, |v| f64::from(v) / 100.0)
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        let volume = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        self.run(Request::SetVolume { volume }).await.map(drop)
+    }
+
+    /// Microseconds into the current track
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn position(&self) -> i64 {
+        micros(self.status().await.position.unwrap_or(0))
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    async fn can_go_next(&self) -> bool {
+        !self.shared.player.lock().await.list().is_empty()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+fn micros(seconds: u64) -> i64 {
+    i64::try_from(seconds.saturating_mul(1_000_000)).unwrap_or(i64::MAX)
+}
+
+fn loop_status(mode: RepeatMode) -> &'static str {
+    match mode {
+        RepeatMode::Off => "None",
+        RepeatMode::One => "Track",
+        RepeatMode::All => "Playlist",
+    }
+}
+
+fn repeat_mode(status: &str) -> Option<RepeatMode> {
+    match status {
+        "None" => Some(RepeatMode::Off),
+        "Track" => Some(RepeatMode::One),
+        "Playlist" => Some(RepeatMode::All),
+        _ => None,
+    }
+}
+
+/// The `mpris:trackid` of a track: an object path derived from its id.
+fn track_path(track: Option<&Track>) -> ObjectPath<'static> {
+    let path = match track {
+        Some(t) => {
+            let id: String =
+                t.id.chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+            format!("/org/mpris/MediaPlayer2/track/{}", id)
+        }
+        None => NO_TRACK.to_string(),
+    };
+    ObjectPath::try_from(path).expect("only [A-Za-z0-9_/] in the path")
+}
+
+fn owned<'a>(v: impl Into<Value<'a>>) -> OwnedValue {
+    v.into()
+        .try_into_owned()
+        .expect("metadata holds no file descriptors")
+}
+
+/// The MPRIS metadata map for `track`.
+fn metadata(track: Option<&Track>, duration: Option<u64>) -> HashMap<String, OwnedValue> {
+    let mut m = HashMap::new();
+    m.insert("mpris:trackid".to_string(), owned(track_path(track)));
+    let Some(t) = track else {
+        return m;
+    };
+    if let Some(d) = duration.or(t.duration) {
+        m.insert("mpris:length".into(), owned(micros(d)));
+    }
+    let title = t.title.clone().unwrap_or_else(|| {
+        std::path::Path::new(&t.uri)
+            .file_name()
+            .map_or(t.uri.clone(), |n| n.to_string_lossy().into_owned())
+    });
+    m.insert("xesam:title".into(), owned(title));
+    if let Some(artist) = &t.artist {
+        m.insert("xesam:artist".into(), owned(vec![artist.clone()]));
+    }
+    if let Some(album) = &t.album {
+        m.insert("xesam:album".into(), owned(album.clone()));
+    }
+    let url = if t.uri.contains("://") {
+        Some(t.uri.clone())
+    } else {
+        std::path::absolute(&t.uri)
+            .ok()
+            .and_then(|p| url::Url::from_file_path(p).ok())
+            .map(String::from)
+    };
+    if let Some(url) = url {
+        m.insert("xesam:url".into(), owned(url));
+    }
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback::PlaybackAdapter;
+    use crate::player::Player;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn metadata_describes_the_track() {
+        let m = metadata(None, None);
+        assert_eq!(m.len(), 1);
+        let id: ObjectPath = m["mpris:trackid"].clone().try_into().unwrap();
+        assert_eq!(id.as_str(), NO_TRACK);
+
+        let mut t = Track::from_uri("/music/a song.flac", "mpv");
+        t.artist = Some("Band".into());
+        let m = metadata(Some(&t), Some(90));
+        let id: ObjectPath = m["mpris:trackid"].clone().try_into().unwrap();
+        assert_eq!(
+            id.as_str(),
+            format!("/org/mpris/MediaPlayer2/track/{}", t.id)
+        );
+        assert_eq!(i64::try_from(&m["mpris:length"]).unwrap(), 90_000_000);
+        assert_eq!(<&str>::try_from(&m["xesam:title"]).unwrap(), "a song.flac");
+        assert_eq!(
+            <&str>::try_from(&m["xesam:url"]).unwrap(),
+            "file:///music/a%20song.flac"
+        );
+        let artists: Vec<String> = m["xesam:artist"].clone().try_into().unwrap();
+        assert_eq!(artists, vec!["Band"]);
+
+        for mode in [RepeatMode::Off, RepeatMode::One, RepeatMode::All] {
+            assert_eq!(repeat_mode(loop_status(mode)), Some(mode));
+        }
+    }
+
+    // Adapter that keeps the state it is told to be in.
+    #[derive(Clone, Default)]
+    struct StateAdapter(Arc<Mutex<PlaybackStatus>>);
+
+    #[async_trait::async_trait]
+    impl PlaybackAdapter for StateAdapter {
+        async fn search(&mut self, _query: &str) -> anyhow::Result<String> {
+            Ok(String::new())
+        }
+        async fn play(&mut self, track_id: Option<&str>) -> anyhow::Result<()> {
+            let mut st = self.0.lock().unwrap();
+            st.state = PlaybackState::Playing;
+            st.track = track_id.map(|uri| Track::from_uri(uri, "test"));
+            st.position = Some(0);
+            st.duration = Some(200);
+            Ok(())
+        }
+        async fn pause(&mut self) -> anyhow::Result<()> {
+            let mut st = self.0.lock().unwrap();
+            st.state = match st.state {
+                PlaybackState::Playing => PlaybackState::Paused,
+                _ => PlaybackState::Playing,
+            };
+            Ok(())
+        }
+        async fn next(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn prev(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn status(&mut self) -> anyhow::Result<PlaybackStatus> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+        async fn set_volume(&mut self, volume: u8) -> anyhow::Result<()> {
+            self.0.lock().unwrap().volume = Some(volume);
+            Ok(())
+        }
+        async fn seek_to(&mut self, seconds: u64) -> anyhow::Result<()> {
+            self.0.lock().unwrap().position = Some(seconds);
+            Ok(())
+        }
+    }
+
+    // Runs a private dbus-daemon, so it needs dbus installed; CI runs it on every pull request
+    // with `cargo test --lib mpris -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn media_keys_drive_the_player_over_a_private_bus() {
+        use futures_util::StreamExt;
+        use std::io::BufRead;
+
+        assert!(
+            which::which("dbus-daemon").is_ok(),
+            "dbus-daemon not found in PATH; install dbus to run this test"
+        );
+        let mut bus = std::process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .expect("start dbus-daemon");
+        let mut address = String::new();
+        std::io::BufReader::new(bus.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        let address = address.trim().to_string();
+
+        let mut player = Player::new(Box::new(StateAdapter::default()));
+        player.enqueue(player.track_for("a.mp3"));
+        let shared = Shared::for_test(player);
+        let conn = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let (stop_tx, stop) = watch::channel(false);
+        let server = tokio::spawn(serve(conn, shared.clone(), stop));
+
+        let client = zbus::connection::Builder::address(address.as_str())
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let dbus = fdo::DBusProxy::new(&client).await.unwrap();
+        for _ in 0..100 {
+            if dbus
+                .name_has_owner(BUS_NAME.try_into().unwrap())
+                .await
+                .unwrap()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let proxy: zbus::Proxy = zbus::proxy::Builder::new(&client)
+            .destination(BUS_NAME)
+            .unwrap()
+            .path(OBJECT_PATH)
+            .unwrap()
+            .interface("org.mpris.MediaPlayer2.Player")
+            .unwrap()
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+            .unwrap();
+        let status = async || {
+            proxy
+                .get_property::<String>("PlaybackStatus")
+                .await
+                .unwrap()
+        };
+
+        // nothing playing: PlayPause starts the queue
+        assert_eq!(status().await, "Stopped");
+        proxy.call_method("PlayPause", &()).await.unwrap();
+        assert_eq!(status().await, "Playing");
+        let meta: HashMap<String, OwnedValue> = proxy.get_property("Metadata").await.unwrap();
+        let url = <&str>::try_from(&meta["xesam:url"]).unwrap();
+        assert!(url.ends_with("/a.mp3"), "{}", url);
+        let track_id: ObjectPath = meta["mpris:trackid"].clone().try_into().unwrap();
+        proxy.call_method("PlayPause", &()).await.unwrap();
+        assert_eq!(status().await, "Paused");
+        proxy.call_method("Pause", &()).await.unwrap();
+        assert_eq!(status().await, "Paused");
+
+        proxy.set_property("Volume", 0.5f64).await.unwrap();
+        assert_eq!(proxy.get_property::<f64>("Volume").await.unwrap(), 0.5);
+        proxy.set_property("LoopStatus", "Playlist").await.unwrap();
+        assert_eq!(shared.player.lock().await.repeat(), RepeatMode::All);
+
+        let mut seeked = proxy.receive_signal("Seeked").await.unwrap();
+        proxy
+            .call_method("SetPosition", &(track_id, 30_000_000i64))
+            .await
+            .unwrap();
+        assert_eq!(
+            proxy.get_property::<i64>("Position").await.unwrap(),
+            30_000_000
+        );
+        let signal = tokio::time::timeout(Duration::from_secs(5), seeked.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(signal.body().deserialize::<i64>().unwrap(), 30_000_000);
+
+        stop_tx.send_replace(true);
+        server.await.unwrap().unwrap();
+        let _ = bus.kill();
+        let _ = bus.wait();
+    }
+}
@@ -3,7 +3,8 @@
 // without needing an Apple Music developer account. Later this can be extended to
 // perform OAuth and call the Apple Music API.
 
-use crate::playback::PlaybackAdapter;
+use crate::playback::{PlaybackAdapter, PlaybackState, PlaybackStatus};
+use crate::track::Track;
 use anyhow::{Context, Result};
 
 pub struct AppleMusicAdapter {
@@ -89,6 +90,10 @@ impl Default for AppleMusicAdapter {
 
 #[async_trait::async_trait]
 impl PlaybackAdapter for AppleMusicAdapter {
+    fn name(&self) -> &'static str {
+        "applemusic"
+    }
+
     async fn search(&mut self, query: &str) -> Result<String> {
         if !self.enabled || self.client.is_none() || self.dev_token.is_none() {
             return Ok(format!(
@@ -167,13 +172,18 @@ impl PlaybackAdapter for AppleMusicAdapter {
         Ok(())
     }
 
-    async fn status(&mut self) -> Result<String> {
-        Ok(format!(
-            "apple-music enabled={} playing={} last_item={}",
-            self.enabled,
-            self.playing,
-            self.last_item.clone().unwrap_or_default()
-        ))
+    async fn status(&mut self) -> Result<PlaybackStatus> {
+        let state = match (self.playing, &self.last_item) {
+            (true, _) => PlaybackState::Playing,
+            (false, Some(_)) => PlaybackState::Paused,
+            (false, None) => PlaybackState::Idle,
+        };
+        let mut st = PlaybackStatus::with_state(state);
+        st.track = self
+            .last_item
+            .as_deref()
+            .map(|id| Track::from_uri(id, self.name()));
+        Ok(st)
     }
 
     async fn artist_info(&mut self, artist_id: &str) -> Result<String> {
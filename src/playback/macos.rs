@@ -1,4 +1,5 @@
-use crate::playback::PlaybackAdapter;
+use crate::playback::{PlaybackAdapter, PlaybackState, PlaybackStatus};
+use crate::track::Track;
 use anyhow::{Context, Result};
 use std::process::Stdio;
 use tokio::process::Command;
@@ -32,6 +33,10 @@ impl MacOsAdapter {
 
 #[async_trait::async_trait]
 impl PlaybackAdapter for MacOsAdapter {
+    fn name(&self) -> &'static str {
+        "macos"
+    }
+
     async fn search(&mut self, query: &str) -> Result<String> {
         // Use Music app search via AppleScript: search library playlist for track
         let script = format!(
@@ -70,15 +75,36 @@ impl PlaybackAdapter for MacOsAdapter {
         Self::run_applescript(script).await.map(|_| ())
     }
 
-    async fn status(&mut self) -> Result<String> {
-        let script = r#"tell application \"Music\"
+    async fn status(&mut self) -> Result<PlaybackStatus> {
+        // One value per line: state, volume, then track fields if something is loaded
+        let script = r#"tell application "Music"
+set out to (player state as string) & linefeed & (sound volume as string)
+if player state is not stopped then
 set t to current track
-set name to name of t
-set artist to artist of t
-set playingState to player state
-return name & " - " & artist & " (" & playingState & ")"
+set out to out & linefeed & (persistent ID of t) & linefeed & (name of t) & linefeed & (artist of t) & linefeed & (album of t) & linefeed & ((player position as integer) as string) & linefeed & ((duration of t as integer) as string)
+end if
+return out
 end tell"#;
         let res = Self::run_applescript(script).await?;
-        Ok(res)
+        let lines: Vec<&str> = res.lines().collect();
+        let state = match lines.first().copied() {
+            Some("playing") => PlaybackState::Playing,
+            Some("paused") => PlaybackState::Paused,
+            Some("stopped") => PlaybackState::Stopped,
+            _ => PlaybackState::Idle,
+        };
+        let mut st = PlaybackStatus::with_state(state);
+        st.volume = lines.get(1).and_then(|v| v.parse().ok());
+        if let [_, _, id, name, artist, album, pos, dur, ..] = lines.as_slice() {
+            let mut t = Track::from_uri(id, self.name());
+            t.title = Some(name.to_string());
+            t.artist = Some(artist.to_string());
+            t.album = Some(album.to_string());
+            t.duration = dur.parse().ok();
+            st.duration = t.duration;
+            st.position = pos.parse().ok();
+            st.track = Some(t);
+        }
+        Ok(st)
     }
 }
@@ -1,14 +1,51 @@
+use crate::track::Track;
 use anyhow::Result;
 use async_trait::async_trait;
+use std::collections::BTreeMap;
+use tokio::sync::broadcast;
+
+/// Why the adapter stopped playing the current item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndReason {
+    /// Reached the end of the file/stream
+    Eof,
+    /// Stopped or replaced by another item (e.g. a new `play`)
+    Stop,
+    /// Player is shutting down
+    Quit,
+    /// Playback failed
+    Error(String),
+    /// Item redirected to another one (playlists, some streams)
+    Redirect,
+    Unknown,
+}
+
+/// Asynchronous notifications emitted by an adapter while it plays.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackEvent {
+    /// A new item was loaded and is starting to play
+    Started,
+    /// The current item stopped playing
+    Ended(EndReason),
+    /// Pause state changed (true = paused)
+    Paused(bool),
+    /// Metadata of the current item changed (title, artist, icy-title, ...)
+    Metadata(BTreeMap<String, String>),
+}
 
 #[async_trait]
 pub trait PlaybackAdapter {
+    // Short identifier recorded as the source of tracks played through this adapter.
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
     async fn search(&mut self, query: &str) -> Result<String>;
     async fn play(&mut self, track_id: Option<&str>) -> Result<()>;
     async fn pause(&mut self) -> Result<()>;
     async fn next(&mut self) -> Result<()>;
     async fn prev(&mut self) -> Result<()>;
-    async fn status(&mut self) -> Result<String>;
+    async fn status(&mut self) -> Result<PlaybackStatus>;
 
     // Volume control (0-100). Default: not supported.
     async fn volume_up(&mut self) -> Result<()> {
@@ -64,6 +101,20 @@ pub trait PlaybackAdapter {
         Err(anyhow::anyhow!("duration not supported by this adapter"))
     }
 
+    // Optional: search returning playable tracks (best match first), which callers can queue
+    // by `Track::id`. Default: not supported.
+    async fn search_tracks(&mut self, _query: &str) -> Result<Vec<Track>> {
+        Err(anyhow::anyhow!(
+            "structured search not supported by this adapter"
+        ))
+    }
+
+    // Optional: subscribe to playback events (track ended, pause changes, ...).
+    // Default: not supported.
+    fn subscribe(&self) -> Result<broadcast::Receiver<PlaybackEvent>> {
+        Err(anyhow::anyhow!("events not supported by this adapter"))
+    }
+
     // Optional: fetch artist general info (name, genre, url, etc.). Default: not supported.
     async fn artist_info(&mut self, _artist_id: &str) -> Result<String> {
         Ok("artist info not supported by this adapter".into())
@@ -82,7 +133,10 @@ mod applemusic;
 mod applemusic_oauth;
 #[cfg(unix)]
 mod mpv;
+#[cfg(unix)]
+mod mpv_ipc;
 mod noop;
+mod status;
 mod system;
 
 #[cfg(target_os = "macos")]
@@ -92,6 +146,7 @@ pub use applemusic::AppleMusicAdapter;
 #[cfg(unix)]
 pub use mpv::MpvAdapter;
 pub use noop::NoopAdapter;
+pub use status::{PlaybackState, PlaybackStatus};
 pub use system::SystemAdapter;
 
 pub async fn get_adapter() -> Result<Box<dyn PlaybackAdapter + Send>> {
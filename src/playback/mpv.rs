@@ -1,4 +1,6 @@
-use crate::playback::PlaybackAdapter;
+use crate::playback::mpv_ipc::MpvIpc;
+use crate::playback::{PlaybackAdapter, PlaybackEvent, PlaybackState, PlaybackStatus};
+use crate::track::Track;
 use anyhow::{Context, Result};
 #[cfg(unix)]
 use nix::sys::signal::kill as nix_kill;
@@ -9,12 +11,12 @@ use nix::unistd::Pid as NixPid;
 use serde_json::json;
 use std::path::PathBuf;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
 use tokio::net::UnixStream;
 use tokio::process::{Child, Command};
+use tokio::sync::broadcast;
 
 pub struct MpvAdapter {
-    ipc_path: PathBuf,
+    ipc: MpvIpc,
     _child: Option<Child>,
 }
 
@@ -60,10 +62,7 @@ impl MpvAdapter {
             .context("failed to create mpv err file")?;
         // Let mpv write its --log-file; capture stderr to err_file for immediate diagnostics. Keep stdout null.
         // Build the full command string and run it via sh -c to ensure options like --input-ipc-server= are passed exactly.
-        let log_arg_val = ipc_path
-            .with_extension("log")
-            .to_string_lossy()
-            .to_string();
+        let log_arg_val = ipc_path.with_extension("log").to_string_lossy().to_string();
         let ipc_arg_val = ipc_path.to_string_lossy().to_string();
         let primary_cmd = format!(
             "mpv --no-config --no-video --idle --ao=null --msg-level=all=debug \
@@ -146,8 +145,9 @@ impl MpvAdapter {
                 .await
                 .unwrap_or(false);
             if connected2 {
+                let ipc = MpvIpc::connect(&ipc_path).await?;
                 return Ok(Self {
-                    ipc_path,
+                    ipc,
                     _child: Some(child2),
                 });
             } else {
@@ -168,131 +168,172 @@ impl MpvAdapter {
             }
         }
 
+        let ipc = MpvIpc::connect(&ipc_path).await?;
         Ok(Self {
-            ipc_path,
+            ipc,
             _child: Some(child),
         })
     }
 
     async fn send_command(&self, cmd: serde_json::Value) -> Result<()> {
-        let mut stream = UnixStream::connect(&self.ipc_path)
-            .await
-            .context("failed to connect to mpv ipc")?;
-        let s = cmd.to_string() + "\n";
-        stream
-            .write_all(s.as_bytes())
-            .await
-            .context("failed to write to mpv ipc")?;
+        self.ipc.command(cmd).await?;
         Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl PlaybackAdapter for MpvAdapter {
-    async fn search(&mut self, _query: &str) -> Result<String> {
-        Ok("mpv: search not implemented".to_string())
+    fn name(&self) -> &'static str {
+        "mpv"
+    }
+
+    async fn search(&mut self, query: &str) -> Result<String> {
+        let tracks = self.search_tracks(query).await?;
+        Ok(crate::library::format_results(query, &tracks))
+    }
+
+    async fn search_tracks(&mut self, query: &str) -> Result<Vec<Track>> {
+        crate::library::search_tracks(query)
     }
 
     async fn play(&mut self, track_id: Option<&str>) -> Result<()> {
         if let Some(id) = track_id {
-            let cmd = json!({"command": ["loadfile", id, "replace"]});
+            let cmd = json!(["loadfile", id, "replace"]);
             self.send_command(cmd).await?;
         }
         Ok(())
     }
 
     async fn pause(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["cycle", "pause"]});
+        let cmd = json!(["cycle", "pause"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn next(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["playlist-next", "weak"]});
+        let cmd = json!(["playlist-next", "weak"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn prev(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["playlist-prev", "weak"]});
+        let cmd = json!(["playlist-prev", "weak"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
-    async fn status(&mut self) -> Result<String> {
-        Ok("mpv: status not implemented".to_string())
+    async fn status(&mut self) -> Result<PlaybackStatus> {
+        let idle = self
+            .ipc
+            .get_property("idle-active")
+            .await?
+            .as_bool()
+            .unwrap_or(true);
+        let mut st = PlaybackStatus {
+            volume: self.get_volume().await.ok(),
+            muted: self.ipc.get_property("mute").await?.as_bool(),
+            ..PlaybackStatus::default()
+        };
+        if idle {
+            return Ok(st);
+        }
+
+        let paused = self.ipc.get_property("pause").await?.as_bool();
+        st.state = if paused == Some(true) {
+            PlaybackState::Paused
+        } else {
+            PlaybackState::Playing
+        };
+        // Position/duration are unavailable for some streams; that is not an error here.
+        st.position = self.get_position().await.ok();
+        st.duration = self.get_duration().await.ok();
+
+        if let Some(path) = self.ipc.get_property("path").await?.as_str() {
+            let mut t = Track::from_uri(path, self.name());
+            let meta = self.ipc.get_property("metadata").await.unwrap_or_default();
+            // tag keys differ in case between containers (TITLE vs title)
+            let tag = |key: &str| {
+                meta.as_object().and_then(|m| {
+                    m.iter()
+                        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                        .and_then(|(_, v)| v.as_str())
+                        .map(str::to_string)
+                })
+            };
+            t.title = tag("title").or_else(|| tag("icy-title"));
+            t.artist = tag("artist");
+            t.album = tag("album");
+            t.duration = st.duration;
+            st.track = Some(t);
+        }
+        Ok(st)
     }
 
     async fn volume_up(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["add", "volume", 10]});
+        let cmd = json!(["add", "volume", 10]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn volume_down(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["add", "volume", -10]});
+        let cmd = json!(["add", "volume", -10]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn set_volume(&mut self, volume: u8) -> Result<()> {
-        let cmd = json!({"command": ["set", "volume", volume]});
+        let cmd = json!(["set", "volume", volume]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn get_volume(&mut self) -> Result<u8> {
-        let cmd = json!({"command": ["get_property", "volume"]});
-        self.send_command(cmd).await?;
-        // Note: In a real implementation, we'd need to read the response
-        // For now, return a reasonable default
-        Ok(50)
+        let vol = self.ipc.get_f64("volume").await?;
+        Ok(vol.round().clamp(0.0, 100.0) as u8)
     }
 
     async fn mute(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["set", "mute", true]});
+        let cmd = json!(["set", "mute", true]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn unmute(&mut self) -> Result<()> {
-        let cmd = json!({"command": ["set", "mute", false]});
+        let cmd = json!(["set", "mute", false]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn seek_forward(&mut self, seconds: u64) -> Result<()> {
-        let cmd = json!({"command": ["seek", seconds, "relative"]});
+        let cmd = json!(["seek", seconds, "relative"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn seek_backward(&mut self, seconds: u64) -> Result<()> {
-        let cmd = json!({"command": ["seek", -(seconds as i64), "relative"]});
+        let cmd = json!(["seek", -(seconds as i64), "relative"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn seek_to(&mut self, seconds: u64) -> Result<()> {
-        let cmd = json!({"command": ["seek", seconds, "absolute"]});
+        let cmd = json!(["seek", seconds, "absolute"]);
         self.send_command(cmd).await?;
         Ok(())
     }
 
     async fn get_position(&mut self) -> Result<u64> {
-        let cmd = json!({"command": ["get_property", "time-pos"]});
-        self.send_command(cmd).await?;
-        // Note: In a real implementation, we'd need to read the response
-        // For now, return a reasonable default
-        Ok(0)
+        let pos = self.ipc.get_f64("time-pos").await?;
+        Ok(pos.max(0.0) as u64)
     }
 
     async fn get_duration(&mut self) -> Result<u64> {
-        let cmd = json!({"command": ["get_property", "duration"]});
-        self.send_command(cmd).await?;
-        // Note: In a real implementation, we'd need to read the response
-        // For now, return a reasonable default
-        Ok(0)
+        let dur = self.ipc.get_f64("duration").await?;
+        Ok(dur.max(0.0) as u64)
+    }
+
+    fn subscribe(&self) -> Result<broadcast::Receiver<PlaybackEvent>> {
+        Ok(self.ipc.subscribe())
     }
 }
 
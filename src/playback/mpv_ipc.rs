@@ -0,0 +1,319 @@
+// Persistent client for mpv's JSON IPC protocol.
+// A single connection is kept open for the lifetime of the adapter. Every command is tagged
+// with a `request_id` and a background reader task routes mpv's replies back to the caller
+// that issued the command, so property reads return real values. Messages without a
+// `request_id` are async events; they are decoded and published on a broadcast channel.
+
+use crate::playback::{EndReason, PlaybackEvent};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>>;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const EVENT_CAPACITY: usize = 64;
+
+// Properties observed right after connecting; changes arrive as `property-change` events.
+const OBSERVED: [&str; 2] = ["pause", "metadata"];
+
+pub struct MpvIpc {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Pending,
+    next_id: AtomicU64,
+    events: broadcast::Sender<PlaybackEvent>,
+    reader: JoinHandle<()>,
+}
+
+impl MpvIpc {
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .context("failed to connect to mpv ipc")?;
+        let (r, w) = stream.into_split();
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+
+        let reader_pending = pending.clone();
+        let reader_events = events.clone();
+        let reader = tokio::spawn(async move {
+            let mut lines = BufReader::new(r).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let Ok(msg) = serde_json::from_str::<Value>(&line) else {
+                    continue;
+                };
+                let Some(id) = msg.get("request_id").and_then(|v| v.as_u64()) else {
+                    if let Some(ev) = decode_event(&msg) {
+                        // no subscribers is fine
+                        let _ = reader_events.send(ev);
+                    }
+                    continue;
+                };
+                let tx = reader_pending.lock().unwrap().remove(&id);
+                if let Some(tx) = tx {
+                    let _ = tx.send(decode_reply(msg));
+                }
+            }
+            // connection closed: fail everyone still waiting for a reply
+            for (_, tx) in reader_pending.lock().unwrap().drain() {
+                let _ = tx.send(Err(anyhow::anyhow!("mpv ipc connection closed")));
+            }
+        });
+
+        let ipc = Self {
+            writer: tokio::sync::Mutex::new(w),
+            pending,
+            next_id: AtomicU64::new(1),
+            events,
+            reader,
+        };
+        for (i, name) in OBSERVED.iter().enumerate() {
+            ipc.command(json!(["observe_property", i + 1, name]))
+                .await
+                .with_context(|| format!("failed to observe mpv property {}", name))?;
+        }
+        Ok(ipc)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlaybackEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send a command (the array that goes into mpv's `command` field) and wait for its reply.
+    /// Returns the reply's `data` field, or `Value::Null` when mpv did not include one.
+    pub async fn command(&self, args: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        let line = json!({"command": args, "request_id": id}).to_string() + "\n";
+        let written = {
+            let mut w = self.writer.lock().await;
+            w.write_all(line.as_bytes()).await
+        };
+        if let Err(e) = written {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e).context("failed to write to mpv ipc");
+        }
+
+        match tokio::time::timeout(REPLY_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => reply.with_context(|| format!("mpv command {} failed", args)),
+            Ok(Err(_)) => Err(anyhow::anyhow!("mpv ipc connection closed")),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(anyhow::anyhow!(
+                    "timed out waiting for mpv reply to {}",
+                    args
+                ))
+            }
+        }
+    }
+
+    pub async fn get_property(&self, name: &str) -> Result<Value> {
+        self.command(json!(["get_property", name])).await
+    }
+
+    pub async fn get_f64(&self, name: &str) -> Result<f64> {
+        let v = self.get_property(name).await?;
+        v.as_f64()
+            .ok_or_else(|| anyhow::anyhow!("mpv property {} is not a number: {}", name, v))
+    }
+}
+
+impl Drop for MpvIpc {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+fn decode_reply(msg: Value) -> Result<Value> {
+    match msg.get("error").and_then(|e| e.as_str()) {
+        Some("success") | None => Ok(msg.get("data").cloned().unwrap_or(Value::Null)),
+        Some(err) => Err(anyhow::anyhow!("mpv error: {}", err)),
+    }
+}
+
+fn decode_event(msg: &Value) -> Option<PlaybackEvent> {
+    match msg.get("event")?.as_str()? {
+        "file-loaded" => Some(PlaybackEvent::Started),
+        "end-file" => {
+            let reason = match msg.get("reason").and_then(|r| r.as_str()) {
+                Some("eof") => EndReason::Eof,
+                Some("stop") => EndReason::Stop,
+                Some("quit") => EndReason::Quit,
+                Some("redirect") => EndReason::Redirect,
+                Some("error") => EndReason::Error(
+                    msg.get("file_error")
+                        .and_then(|e| e.as_str())
+                        .unwrap_or("unknown error")
+                        .to_string(),
+                ),
+                _ => EndReason::Unknown,
+            };
+            Some(PlaybackEvent::Ended(reason))
+        }
+        "property-change" => match msg.get("name")?.as_str()? {
+            "pause" => Some(PlaybackEvent::Paused(msg.get("data")?.as_bool()?)),
+            "metadata" => {
+                // data is null while nothing is loaded
+                let tags = msg
+                    .get("data")
+                    .and_then(|d| d.as_object())
+                    .map(|obj| {
+                        obj.iter()
+                            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                            .collect::<BTreeMap<_, _>>()
+                    })
+                    .unwrap_or_default();
+                Some(PlaybackEvent::Metadata(tags))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::Lines;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::UnixListener;
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "apple-mpv-ipc-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    async fn send_line(w: &mut OwnedWriteHalf, v: Value) {
+        w.write_all((v.to_string() + "\n").as_bytes())
+            .await
+            .unwrap();
+    }
+
+    // Accept the client and acknowledge the observe_property commands sent on connect.
+    async fn accept(listener: UnixListener) -> (Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (r, mut w) = stream.into_split();
+        let mut lines = BufReader::new(r).lines();
+        for _ in OBSERVED {
+            let req: Value =
+                serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            assert_eq!(req["command"][0], "observe_property");
+            send_line(
+                &mut w,
+                json!({"error": "success", "request_id": req["request_id"]}),
+            )
+            .await;
+        }
+        (lines, w)
+    }
+
+    // Minimal stand-in for mpv: answers get_property for "volume" and rejects everything else
+    // the way mpv does for an unavailable property. Replies are sent in reverse order with an
+    // unrelated event in between to make sure they are matched by request_id.
+    async fn fake_mpv(listener: UnixListener) {
+        let (mut lines, mut w) = accept(listener).await;
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            let line = lines.next_line().await.unwrap().unwrap();
+            let req: Value = serde_json::from_str(&line).unwrap();
+            let id = req["request_id"].as_u64().unwrap();
+            let reply = if req["command"] == json!(["get_property", "volume"]) {
+                json!({"data": 42.0, "error": "success", "request_id": id})
+            } else {
+                json!({"error": "property unavailable", "request_id": id})
+            };
+            replies.push(reply);
+        }
+        send_line(&mut w, json!({"event": "idle"})).await;
+        for reply in replies.into_iter().rev() {
+            send_line(&mut w, reply).await;
+        }
+        // keep the socket open until the client hangs up
+        let _ = lines.next_line().await;
+    }
+
+    #[tokio::test]
+    async fn replies_are_matched_by_request_id() {
+        let path = socket_path("replies");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(fake_mpv(listener));
+
+        let ipc = MpvIpc::connect(&path).await.unwrap();
+        let (vol, pos) = tokio::join!(ipc.get_f64("volume"), ipc.get_f64("time-pos"));
+        assert_eq!(vol.unwrap(), 42.0);
+        let err = pos.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("property unavailable"),
+            "unexpected error: {:#}",
+            err
+        );
+
+        drop(ipc);
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn events_are_decoded_and_broadcast() {
+        let path = socket_path("events");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (ready_tx, ready_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            let (mut lines, mut w) = accept(listener).await;
+            let _ = ready_rx.await;
+            for ev in [
+                json!({"event": "file-loaded"}),
+                json!({"event": "property-change", "id": 2, "name": "metadata",
+                       "data": {"title": "Song", "track": 3}}),
+                json!({"event": "property-change", "id": 1, "name": "pause", "data": true}),
+                json!({"event": "seek"}),
+                json!({"event": "end-file", "reason": "error", "file_error": "no such file"}),
+                json!({"event": "end-file", "reason": "eof"}),
+            ] {
+                send_line(&mut w, ev).await;
+            }
+            let _ = lines.next_line().await;
+        });
+
+        let ipc = MpvIpc::connect(&path).await.unwrap();
+        let mut rx = ipc.subscribe();
+        ready_tx.send(()).unwrap();
+
+        let mut meta = BTreeMap::new();
+        meta.insert("title".to_string(), "Song".to_string());
+        let expected = [
+            PlaybackEvent::Started,
+            PlaybackEvent::Metadata(meta),
+            PlaybackEvent::Paused(true),
+            PlaybackEvent::Ended(EndReason::Error("no such file".into())),
+            PlaybackEvent::Ended(EndReason::Eof),
+        ];
+        for want in expected {
+            let got = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+                .await
+                .expect("timed out waiting for event")
+                .unwrap();
+            assert_eq!(got, want);
+        }
+
+        drop(ipc);
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::playback::PlaybackAdapter;
+use crate::playback::{PlaybackAdapter, PlaybackStatus};
 use anyhow::Result;
 
 pub struct NoopAdapter {}
@@ -17,6 +17,10 @@ impl Default for NoopAdapter {
 
 #[async_trait::async_trait]
 impl PlaybackAdapter for NoopAdapter {
+    fn name(&self) -> &'static str {
+        "noop"
+    }
+
     async fn search(&mut self, query: &str) -> Result<String> {
         Ok(format!(
             "noop: search '{}': no results (not on macOS)",
@@ -44,7 +48,7 @@ impl PlaybackAdapter for NoopAdapter {
         Ok(())
     }
 
-    async fn status(&mut self) -> Result<String> {
-        Ok("noop: no status".to_string())
+    async fn status(&mut self) -> Result<PlaybackStatus> {
+        Ok(PlaybackStatus::default())
     }
 }
@@ -0,0 +1,123 @@
+use crate::player::RepeatMode;
+use crate::track::Track;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackState {
+    Playing,
+    Paused,
+    Stopped,
+    /// Nothing loaded (or the adapter cannot tell)
+    #[default]
+    Idle,
+}
+
+impl fmt::Display for PlaybackState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            PlaybackState::Playing => "playing",
+            PlaybackState::Paused => "paused",
+            PlaybackState::Stopped => "stopped",
+            PlaybackState::Idle => "idle",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Snapshot of what an adapter is doing. Fields the adapter cannot report are left as None.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PlaybackStatus {
+    pub state: PlaybackState,
+    pub track: Option<Track>,
+    /// Seconds into the current track
+    pub position: Option<u64>,
+    /// Length of the current track in seconds
+    pub duration: Option<u64>,
+    /// 0-100
+    pub volume: Option<u8>,
+    pub muted: Option<bool>,
+    /// Items waiting in the local queue; filled in by `Player::status`
+    #[serde(default)]
+    pub queue_len: usize,
+    /// Queue modes; filled in by `Player::status`
+    #[serde(default)]
+    pub repeat: RepeatMode,
+    #[serde(default)]
+    pub shuffle: bool,
+}
+
+impl PlaybackStatus {
+    pub fn with_state(state: PlaybackState) -> Self {
+        Self {
+            state,
+            ..Self::default()
+        }
+    }
+}
+
+fn fmt_time(seconds: u64) -> String {
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+impl fmt::Display for PlaybackStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.state)?;
+        if let Some(t) = &self.track {
+            write!(f, ": {}", t)?;
+        }
+        match (self.position, self.duration) {
+            (Some(p), Some(d)) => write!(f, " ({}/{})", fmt_time(p), fmt_time(d))?,
+            (Some(p), None) => write!(f, " ({})", fmt_time(p))?,
+            _ => {}
+        }
+        if let Some(v) = self.volume {
+            write!(f, " volume={}", v)?;
+        }
+        if self.muted == Some(true) {
+            write!(f, " (muted)")?;
+        }
+        write!(f, " queue={}", self.queue_len)?;
+        if self.repeat != RepeatMode::Off {
+            write!(f, " repeat={}", self.repeat)?;
+        }
+        if self.shuffle {
+            write!(f, " shuffle")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_is_one_line_summary() {
+        let mut st = PlaybackStatus::with_state(PlaybackState::Playing);
+        assert_eq!(st.to_string(), "playing queue=0");
+
+        let mut t = Track::from_uri("a.mp3", "mpv");
+        t.title = Some("Song".into());
+        st.track = Some(t);
+        st.position = Some(62);
+        st.duration = Some(225);
+        st.volume = Some(50);
+        st.muted = Some(true);
+        st.queue_len = 3;
+        assert_eq!(
+            st.to_string(),
+            "playing: Song (01:02/03:45) volume=50 (muted) queue=3"
+        );
+    }
+
+    #[test]
+    fn serializes_state_lowercase() {
+        let st = PlaybackStatus::with_state(PlaybackState::Paused);
+        let v = serde_json::to_value(&st).unwrap();
+        assert_eq!(v["state"], "paused");
+        let back: PlaybackStatus = serde_json::from_value(v).unwrap();
+        assert_eq!(back, st);
+    }
+}
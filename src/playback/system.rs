@@ -1,4 +1,5 @@
-use crate::playback::PlaybackAdapter;
+use crate::playback::{PlaybackAdapter, PlaybackStatus};
+use crate::track::Track;
 use anyhow::{Context, Result};
 use std::process::Stdio;
 use tokio::process::Command;
@@ -46,11 +47,17 @@ impl SystemAdapter {
 
 #[async_trait::async_trait]
 impl PlaybackAdapter for SystemAdapter {
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
     async fn search(&mut self, query: &str) -> Result<String> {
-        Ok(format!(
-            "system: search '{}' (no remote search implemented)",
-            query
-        ))
+        let tracks = self.search_tracks(query).await?;
+        Ok(crate::library::format_results(query, &tracks))
+    }
+
+    async fn search_tracks(&mut self, query: &str) -> Result<Vec<Track>> {
+        crate::library::search_tracks(query)
     }
 
     async fn play(&mut self, track_id: Option<&str>) -> Result<()> {
@@ -87,7 +94,8 @@ impl PlaybackAdapter for SystemAdapter {
         Ok(())
     }
 
-    async fn status(&mut self) -> Result<String> {
-        Ok("system: no status".to_string())
+    async fn status(&mut self) -> Result<PlaybackStatus> {
+        // spawned players are fire-and-forget; there is nothing to query
+        Ok(PlaybackStatus::default())
     }
 }
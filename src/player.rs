@@ -1,9 +1,87 @@
-use crate::playback::PlaybackAdapter;
-use anyhow::Result;
+use crate::playback::{PlaybackAdapter, PlaybackState, PlaybackStatus};
+use crate::state::PlayerState;
+use crate::track::Track;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+/// How many previously played tracks are remembered for `previous`/`history`.
+pub const HISTORY_LIMIT: usize = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    /// Replay the current track when it ends
+    One,
+    /// Start over with the played tracks once the queue runs out
+    All,
+}
+
+impl RepeatMode {
+    /// Off -> All -> One -> Off, for toggle keys.
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+/// Modes given on the command line. Unset ones leave the player's modes, which may have been
+/// restored from saved state, as they are.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ModeOverrides {
+    pub repeat: Option<RepeatMode>,
+    pub shuffle: bool,
+    pub shuffle_seed: Option<u64>,
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::All => "all",
+        })
+    }
+}
+
+impl FromStr for RepeatMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(RepeatMode::Off),
+            "one" | "track" => Ok(RepeatMode::One),
+            "all" | "queue" => Ok(RepeatMode::All),
+            _ => Err(anyhow::anyhow!(
+                "invalid repeat mode '{}' (expected off, one or all)",
+                s
+            )),
+        }
+    }
+}
 
 pub struct Player {
-    queue: VecDeque<String>,
+    queue: VecDeque<Track>,
+    current: Option<Track>,
+    // oldest first; bounded by HISTORY_LIMIT
+    history: VecDeque<Track>,
+    // tracks played since repeat-all last refilled the queue, oldest first; unbounded so that
+    // the whole queue comes round again
+    cycle: Vec<Track>,
+    // the current track was put back in the queue by a refill, so it is not recorded again
+    // when it is replaced
+    current_requeued: bool,
+    repeat: RepeatMode,
+    shuffle: bool,
+    // splitmix64 state for shuffle; seeded explicitly in tests for reproducible order
+    rng: u64,
     adapter: Box<dyn PlaybackAdapter + Send>,
 }
 
@@ -11,27 +89,280 @@ impl Player {
     pub fn new(adapter: Box<dyn PlaybackAdapter + Send>) -> Self {
         Self {
             queue: VecDeque::new(),
+            current: None,
+            history: VecDeque::new(),
+            cycle: Vec::new(),
+            current_requeued: false,
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            rng: 0,
             adapter,
         }
     }
 
-    pub fn enqueue(&mut self, item: String) {
-        self.queue.push_back(item);
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, mode: RepeatMode) {
+        self.repeat = mode;
+    }
+
+    /// Apply modes given on the command line on top of the current ones. Plain `--shuffle`
+    /// keeps a shuffle order that is already running.
+    pub fn apply_overrides(&mut self, modes: ModeOverrides) {
+        if let Some(mode) = modes.repeat {
+            self.set_repeat(mode);
+        }
+        if modes.shuffle && (modes.shuffle_seed.is_some() || !self.shuffle) {
+            self.set_shuffle(true, modes.shuffle_seed);
+        }
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
     }
 
-    pub fn list(&self) -> Vec<String> {
+    /// Turn shuffle on or off. With a seed the shuffle order is reproducible; without one the
+    /// generator is seeded from the clock.
+    pub fn set_shuffle(&mut self, on: bool, seed: Option<u64>) {
+        self.shuffle = on;
+        if on {
+            self.rng = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0)
+            });
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Source recorded on tracks created for this player's adapter.
+    pub fn source(&self) -> &'static str {
+        self.adapter.name()
+    }
+
+    /// Build a track for `uri` attributed to the current adapter.
+    pub fn track_for(&self, uri: &str) -> Track {
+        Track::from_uri(uri, self.source())
+    }
+
+    pub fn enqueue(&mut self, track: Track) {
+        self.queue.push_back(track);
+    }
+
+    pub fn list(&self) -> Vec<Track> {
         self.queue.iter().cloned().collect()
     }
 
-    pub fn next_item(&mut self) -> Option<String> {
+    /// Take the next item to play after a skip. Honors shuffle, and with repeat-all refills
+    /// an exhausted queue with the tracks played since the last refill, in the order they
+    /// played, followed by the current track.
+    pub fn next_item(&mut self) -> Option<Track> {
+        if self.queue.is_empty() && self.repeat == RepeatMode::All {
+            self.queue.extend(self.cycle.drain(..));
+            if let Some(cur) = &self.current {
+                self.queue.push_back(cur.clone());
+                self.current_requeued = true;
+            }
+        }
+        if self.shuffle && self.queue.len() > 1 {
+            let idx = (self.next_random() % self.queue.len() as u64) as usize;
+            return self.queue.remove(idx);
+        }
+        self.queue.pop_front()
+    }
+
+    /// Carry on after the current track finished on its own: repeat-one replays it, anything
+    /// else plays `next_item`. Returns what is playing now, or None when the queue ran out.
+    pub async fn play_after_end(&mut self) -> Result<Option<Track>> {
+        if self.repeat == RepeatMode::One {
+            if let Some(cur) = self.current.clone() {
+                self.replay().await?;
+                return Ok(Some(cur));
+            }
+        }
+        let Some(next) = self.next_item() else {
+            return Ok(None);
+        };
+        self.play_track(next.clone())
+            .await
+            .with_context(|| format!("playing queued item {}", next))?;
+        Ok(Some(next))
+    }
+
+    /// Restart the current track from the top. It is still the same play, so the history and
+    /// the repeat-all cycle are left alone.
+    pub async fn replay(&mut self) -> Result<()> {
+        match &self.current {
+            Some(cur) => self.adapter.play(Some(&cur.uri)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Put a track at the front of the queue so it plays next.
+    pub fn insert_next(&mut self, track: Track) {
+        self.queue.push_front(track);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Track> {
+        self.queue.remove(index)
+    }
+
+    /// Move the item at `from` so it ends up at position `to`. Returns false if either index
+    /// is out of range.
+    pub fn move_item(&mut self, from: usize, to: usize) -> bool {
+        if from >= self.queue.len() || to >= self.queue.len() {
+            return false;
+        }
+        if let Some(t) = self.queue.remove(from) {
+            self.queue.insert(to, t);
+        }
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    /// Skip ahead to `index`: drops the items before it and returns the item itself, which
+    /// the caller is expected to play.
+    pub fn jump_to(&mut self, index: usize) -> Option<Track> {
+        if index >= self.queue.len() {
+            return None;
+        }
+        self.queue.drain(..index);
         self.queue.pop_front()
     }
 
     pub async fn play_item(&mut self, item: &str) -> Result<()> {
-        self.adapter.play(Some(item)).await?;
+        let track = self.track_for(item);
+        self.play_track(track).await
+    }
+
+    pub async fn play_track(&mut self, track: Track) -> Result<()> {
+        self.adapter.play(Some(&track.uri)).await?;
+        if let Some(prev) = self.current.replace(track) {
+            if !std::mem::take(&mut self.current_requeued) {
+                self.cycle.push(prev.clone());
+            }
+            self.push_history(prev);
+        }
         Ok(())
     }
 
+    /// Play the first of `tracks` now and queue the rest in front of whatever was queued.
+    pub async fn play_tracks(&mut self, tracks: Vec<Track>) -> Result<()> {
+        let mut tracks = tracks.into_iter();
+        let Some(first) = tracks.next() else {
+            return Ok(());
+        };
+        for t in tracks.rev() {
+            self.insert_next(t);
+        }
+        self.play_track(first).await
+    }
+
+    /// Replay the most recently played track. The track that was playing goes back to the
+    /// front of the queue so `next` returns to it. Returns None when there is no history.
+    pub async fn previous(&mut self) -> Result<Option<Track>> {
+        let Some(prev) = self.history.pop_back() else {
+            return Ok(None);
+        };
+        if let Err(e) = self.adapter.play(Some(&prev.uri)).await {
+            self.history.push_back(prev);
+            return Err(e);
+        }
+        // it comes round once per cycle, and the track it replaces is queued again
+        if self.cycle.last().is_some_and(|t| t.id == prev.id) {
+            self.cycle.pop();
+        }
+        if let Some(cur) = self.current.replace(prev.clone()) {
+            self.queue.push_front(cur);
+        }
+        self.current_requeued = false;
+        Ok(Some(prev))
+    }
+
+    /// Previously played tracks, most recent first.
+    pub fn history(&self) -> Vec<Track> {
+        self.history.iter().rev().cloned().collect()
+    }
+
+    fn push_history(&mut self, track: Track) {
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(track);
+    }
+
+    pub fn current(&self) -> Option<&Track> {
+        self.current.as_ref()
+    }
+
+    /// Queue, history and modes for persisting. Position and volume live in the adapter and
+    /// are left for the caller to fill in.
+    pub fn snapshot(&self) -> PlayerState {
+        PlayerState {
+            queue: self.list(),
+            history: self.history.iter().cloned().collect(),
+            current: self.current.clone(),
+            position: None,
+            volume: None,
+            repeat: self.repeat,
+            shuffle: self.shuffle,
+            shuffle_seed: self.shuffle.then_some(self.rng),
+        }
+    }
+
+    /// Replace queue, history and modes with a saved snapshot. The saved current track is not
+    /// started; it is returned so the caller can resume it or queue it.
+    pub fn restore(&mut self, state: PlayerState) -> Option<Track> {
+        self.queue = state.queue.into();
+        let skip = state.history.len().saturating_sub(HISTORY_LIMIT);
+        self.history = state.history.into_iter().skip(skip).collect();
+        // the saved history is the best record of the cycle that was playing
+        self.cycle = self.history.iter().cloned().collect();
+        self.current_requeued = false;
+        self.repeat = state.repeat;
+        self.set_shuffle(state.shuffle, state.shuffle_seed);
+        state.current
+    }
+
+    /// Adapter status completed with what only the player knows: the queue length and the
+    /// metadata of the track it started (adapters often only see a bare uri).
+    pub async fn status(&mut self) -> Result<PlaybackStatus> {
+        let mut st = self.adapter.status().await?;
+        st.queue_len = self.queue.len();
+        st.repeat = self.repeat;
+        st.shuffle = self.shuffle;
+        if matches!(st.state, PlaybackState::Playing | PlaybackState::Paused) {
+            if let Some(cur) = &self.current {
+                match st.track.take() {
+                    Some(t) if t.uri == cur.uri => {
+                        let mut merged = cur.clone();
+                        merged.title = merged.title.or(t.title);
+                        merged.artist = merged.artist.or(t.artist);
+                        merged.album = merged.album.or(t.album);
+                        merged.duration = merged.duration.or(t.duration);
+                        st.track = Some(merged);
+                    }
+                    None => st.track = Some(cur.clone()),
+                    other => st.track = other,
+                }
+            }
+        }
+        Ok(st)
+    }
+
     pub fn adapter_mut(&mut self) -> &mut (dyn PlaybackAdapter + Send) {
         &mut *self.adapter
     }
@@ -60,18 +391,269 @@ mod tests {
         async fn prev(&mut self) -> anyhow::Result<()> {
             Ok(())
         }
-        async fn status(&mut self) -> anyhow::Result<String> {
-            Ok("".into())
+        async fn status(&mut self) -> anyhow::Result<PlaybackStatus> {
+            Ok(PlaybackStatus::with_state(PlaybackState::Playing))
         }
     }
 
+    fn uris(tracks: &[Track]) -> Vec<&str> {
+        tracks.iter().map(|t| t.uri.as_str()).collect()
+    }
+
     #[tokio::test]
     async fn queue_basic() {
         let mock = Box::new(MockAdapter);
         let mut player = Player::new(mock);
-        player.enqueue("one".into());
-        player.enqueue("two".into());
-        assert_eq!(player.list(), vec!["one".to_string(), "two".to_string()]);
-        assert_eq!(player.next_item(), Some("one".to_string()));
+        player.enqueue(player.track_for("one"));
+        player.enqueue(player.track_for("two"));
+        assert_eq!(uris(&player.list()), vec!["one", "two"]);
+        let next = player.next_item().unwrap();
+        assert_eq!(next.uri, "one");
+        assert_eq!(next.source, "unknown");
+    }
+
+    #[tokio::test]
+    async fn status_reports_queue_and_current_track() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        let mut t = player.track_for("song.mp3");
+        t.title = Some("Song".into());
+        player.play_track(t.clone()).await.unwrap();
+        player.enqueue(player.track_for("next.mp3"));
+
+        let st = player.status().await.unwrap();
+        assert_eq!(st.queue_len, 1);
+        assert_eq!(st.track, Some(t));
+    }
+
+    #[tokio::test]
+    async fn queue_editing() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        for uri in ["a", "b", "c", "d"] {
+            player.enqueue(player.track_for(uri));
+        }
+
+        assert!(player.move_item(0, 2));
+        assert_eq!(uris(&player.list()), vec!["b", "c", "a", "d"]);
+        assert!(!player.move_item(0, 4));
+
+        assert_eq!(player.remove(1).unwrap().uri, "c");
+        assert!(player.remove(9).is_none());
+
+        player.insert_next(player.track_for("x"));
+        assert_eq!(uris(&player.list()), vec!["x", "b", "a", "d"]);
+
+        assert_eq!(player.jump_to(2).unwrap().uri, "a");
+        assert_eq!(uris(&player.list()), vec!["d"]);
+        assert!(player.jump_to(1).is_none());
+
+        player.clear();
+        assert!(player.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn previous_replays_history() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        assert!(player.previous().await.unwrap().is_none());
+
+        for uri in ["a", "b", "c"] {
+            let t = player.track_for(uri);
+            player.play_track(t).await.unwrap();
+        }
+        assert_eq!(uris(&player.history()), vec!["b", "a"]);
+
+        assert_eq!(player.previous().await.unwrap().unwrap().uri, "b");
+        assert_eq!(player.current().unwrap().uri, "b");
+        assert_eq!(uris(&player.list()), vec!["c"]);
+        assert_eq!(uris(&player.history()), vec!["a"]);
+    }
+
+    #[tokio::test]
+    async fn play_tracks_puts_the_rest_in_front_of_the_queue() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        player.enqueue(player.track_for("later"));
+        let album = ["1", "2", "3"].map(|u| player.track_for(u)).to_vec();
+        player.play_tracks(album).await.unwrap();
+        assert_eq!(player.current().unwrap().uri, "1");
+        assert_eq!(uris(&player.list()), vec!["2", "3", "later"]);
+    }
+
+    #[tokio::test]
+    async fn history_is_bounded() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        for i in 0..HISTORY_LIMIT + 5 {
+            let t = player.track_for(&i.to_string());
+            player.play_track(t).await.unwrap();
+        }
+        let history = player.history();
+        assert_eq!(history.len(), HISTORY_LIMIT);
+        assert_eq!(history[0].uri, (HISTORY_LIMIT + 3).to_string());
+    }
+
+    #[tokio::test]
+    async fn repeat_one_replays_current_on_end() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        player.set_repeat(RepeatMode::One);
+        let t = player.track_for("a");
+        player.play_track(t).await.unwrap();
+        player.enqueue(player.track_for("b"));
+
+        assert_eq!(player.play_after_end().await.unwrap().unwrap().uri, "a");
+        assert_eq!(uris(&player.list()), vec!["b"]);
+        // an explicit skip still moves on
+        assert_eq!(player.next_item().unwrap().uri, "b");
+    }
+
+    #[tokio::test]
+    async fn looping_one_track_leaves_history_and_cycle_alone() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        for uri in ["a", "b"] {
+            let t = player.track_for(uri);
+            player.play_track(t).await.unwrap();
+        }
+        player.set_repeat(RepeatMode::One);
+        for _ in 0..5 {
+            assert_eq!(player.play_after_end().await.unwrap().unwrap().uri, "b");
+        }
+        assert_eq!(uris(&player.history()), vec!["a"]);
+
+        // repeat-all then comes round to each track once
+        player.set_repeat(RepeatMode::All);
+        assert_eq!(player.play_after_end().await.unwrap().unwrap().uri, "a");
+        assert_eq!(uris(&player.list()), vec!["b"]);
+        assert_eq!(uris(&player.history()), vec!["b", "a"]);
+    }
+
+    // Skip `n` times, playing each next item; returns what played.
+    async fn play_on(player: &mut Player, n: usize) -> Vec<String> {
+        let mut played = Vec::new();
+        for _ in 0..n {
+            let t = player.next_item().unwrap();
+            played.push(t.uri.clone());
+            player.play_track(t).await.unwrap();
+        }
+        played
+    }
+
+    #[tokio::test]
+    async fn repeat_all_refeeds_history() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        player.set_repeat(RepeatMode::All);
+        for uri in ["a", "b", "c"] {
+            let t = player.track_for(uri);
+            player.play_track(t).await.unwrap();
+        }
+        assert_eq!(player.next_item().unwrap().uri, "a");
+        assert_eq!(uris(&player.list()), vec!["b", "c"]);
+
+        // every cycle plays each track once and the queue does not grow
+        let mut player = Player::new(Box::new(MockAdapter));
+        player.set_repeat(RepeatMode::All);
+        for uri in ["a", "b", "c"] {
+            player.enqueue(player.track_for(uri));
+        }
+        assert_eq!(play_on(&mut player, 9).await, ["a", "b", "c"].repeat(3));
+        assert_eq!(uris(&player.list()), Vec::<&str>::new());
+
+        // longer than the history
+        let mut player = Player::new(Box::new(MockAdapter));
+        player.set_repeat(RepeatMode::All);
+        let all: Vec<String> = (0..HISTORY_LIMIT + 10).map(|i| i.to_string()).collect();
+        for uri in &all {
+            player.enqueue(player.track_for(uri));
+        }
+        let played = play_on(&mut player, all.len() * 2).await;
+        assert_eq!(played, [all.clone(), all].concat());
+
+        player.set_repeat(RepeatMode::Off);
+        player.clear();
+        assert!(player.next_item().is_none());
+    }
+
+    #[tokio::test]
+    async fn shuffle_is_reproducible_with_seed() {
+        let order = |seed| {
+            let mut player = Player::new(Box::new(MockAdapter));
+            for i in 0..10 {
+                player.enqueue(player.track_for(&i.to_string()));
+            }
+            player.set_shuffle(true, Some(seed));
+            std::iter::from_fn(|| player.next_item())
+                .map(|t| t.uri)
+                .collect::<Vec<_>>()
+        };
+        let a = order(42);
+        assert_eq!(a, order(42));
+        assert_ne!(a, order(7));
+
+        let mut sorted = a.clone();
+        sorted.sort_by_key(|s| s.parse::<u32>().unwrap());
+        let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn repeat_mode_parses() {
+        assert_eq!("ALL".parse::<RepeatMode>().unwrap(), RepeatMode::All);
+        assert_eq!(RepeatMode::Off.cycle().cycle(), RepeatMode::One);
+        assert!("sometimes".parse::<RepeatMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshot_restore_roundtrip() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        for uri in ["a", "b"] {
+            let t = player.track_for(uri);
+            player.play_track(t).await.unwrap();
+        }
+        player.enqueue(player.track_for("c"));
+        player.set_repeat(RepeatMode::One);
+        let snap = player.snapshot();
+
+        let mut restored = Player::new(Box::new(MockAdapter));
+        let current = restored.restore(snap);
+        assert_eq!(current.unwrap().uri, "b");
+        assert_eq!(uris(&restored.list()), vec!["c"]);
+        assert_eq!(uris(&restored.history()), vec!["a"]);
+        assert_eq!(restored.repeat(), RepeatMode::One);
+        assert!(restored.current().is_none());
+    }
+
+    #[test]
+    fn restore_keeps_the_shuffle_order_and_overrides_apply_on_top() {
+        let mut player = Player::new(Box::new(MockAdapter));
+        for i in 0..10 {
+            player.enqueue(player.track_for(&i.to_string()));
+        }
+        player.set_shuffle(true, Some(7));
+        player.next_item();
+        let order = |pl: &mut Player| -> Vec<String> {
+            std::iter::from_fn(|| pl.next_item().map(|t| t.uri)).collect()
+        };
+
+        let snap = player.snapshot();
+        let mut restored = Player::new(Box::new(MockAdapter));
+        restored.restore(snap.clone());
+        // plain --shuffle keeps the restored order going
+        restored.apply_overrides(ModeOverrides {
+            repeat: Some(RepeatMode::All),
+            shuffle: true,
+            shuffle_seed: None,
+        });
+        assert_eq!(restored.repeat(), RepeatMode::All);
+        let rest = order(&mut player);
+        assert_eq!(rest.len(), 9);
+        assert_eq!(order(&mut restored), rest);
+
+        // a seed starts a new order; nothing given leaves the modes alone
+        let mut reseeded = Player::new(Box::new(MockAdapter));
+        reseeded.restore(snap);
+        reseeded.apply_overrides(ModeOverrides {
+            shuffle: true,
+            shuffle_seed: Some(8),
+            ..ModeOverrides::default()
+        });
+        reseeded.apply_overrides(ModeOverrides::default());
+        assert!(reseeded.shuffle());
+        assert_eq!(reseeded.rng, 8);
     }
 }
@@ -0,0 +1,467 @@
+// Playlist files: M3U/M3U8 (with #EXTINF), PLS and XSPF.
+// Entries become `Track`s; relative paths are resolved against the playlist's directory.
+
+use crate::track::Track;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Extended M3U; also used for .m3u8 (we always read and write UTF-8)
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    /// Guess the format from the file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "m3u" | "m3u8" => Some(PlaylistFormat::M3u),
+            "pls" => Some(PlaylistFormat::Pls),
+            "xspf" => Some(PlaylistFormat::Xspf),
+            _ => None,
+        }
+    }
+
+    /// Guess the format from the file contents, for files without a known extension.
+    fn sniff(text: &str) -> Self {
+        let head = text.trim_start_matches('\u{feff}').trim_start();
+        if head.starts_with('<') {
+            PlaylistFormat::Xspf
+        } else if head
+            .get(..10)
+            .is_some_and(|h| h.eq_ignore_ascii_case("[playlist]"))
+        {
+            PlaylistFormat::Pls
+        } else {
+            PlaylistFormat::M3u
+        }
+    }
+}
+
+/// Read a playlist file. Tracks get `source` (see `PlaybackAdapter::name`).
+pub fn load_playlist(path: &Path, source: &str) -> Result<Vec<Track>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("reading playlist {}", path.display()))?;
+    let format = PlaylistFormat::from_path(path).unwrap_or_else(|| PlaylistFormat::sniff(&text));
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let base = path.parent().unwrap_or(Path::new("/"));
+    parse(&text, format, base, source).with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Write `tracks` to `path` in the format given by its extension.
+pub fn save_playlist(path: &Path, tracks: &[Track]) -> Result<()> {
+    let format = PlaylistFormat::from_path(path).ok_or_else(|| {
+        anyhow!(
+            "unknown playlist format for {}; use .m3u, .m3u8, .pls or .xspf",
+            path.display()
+        )
+    })?;
+    fs::write(path, render(tracks, format))
+        .with_context(|| format!("writing playlist {}", path.display()))
+}
+
+pub fn parse(text: &str, format: PlaylistFormat, base: &Path, source: &str) -> Result<Vec<Track>> {
+    let text = text.trim_start_matches('\u{feff}');
+    match format {
+        PlaylistFormat::M3u => Ok(parse_m3u(text, base, source)),
+        PlaylistFormat::Pls => Ok(parse_pls(text, base, source)),
+        PlaylistFormat::Xspf => parse_xspf(text, base, source),
+    }
+}
+
+pub fn render(tracks: &[Track], format: PlaylistFormat) -> String {
+    match format {
+        PlaylistFormat::M3u => render_m3u(tracks),
+        PlaylistFormat::Pls => render_pls(tracks),
+        PlaylistFormat::Xspf => render_xspf(tracks),
+    }
+}
+
+fn is_url(entry: &str) -> bool {
+    match entry.split_once("://") {
+        Some((scheme, _)) => {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+        }
+        None => false,
+    }
+}
+
+// Turn a playlist entry into something the adapter can play: URLs are kept, file:// URLs
+// become paths and relative paths are joined onto the playlist's directory.
+fn resolve(entry: &str, base: &Path) -> String {
+    if entry.starts_with("file://") {
+        if let Some(p) = Url::parse(entry).ok().and_then(|u| u.to_file_path().ok()) {
+            return p.to_string_lossy().into_owned();
+        }
+    }
+    if is_url(entry) {
+        return entry.to_string();
+    }
+    let p = Path::new(entry);
+    if p.is_absolute() {
+        entry.to_string()
+    } else {
+        base.join(p).to_string_lossy().into_owned()
+    }
+}
+
+// "Artist - Title" as used by #EXTINF and PLS titles.
+fn split_display(s: &str) -> (Option<String>, Option<String>) {
+    let s = s.trim();
+    if s.is_empty() {
+        return (None, None);
+    }
+    match s.split_once(" - ") {
+        Some((artist, title)) => (Some(artist.trim().into()), Some(title.trim().into())),
+        None => (None, Some(s.into())),
+    }
+}
+
+fn join_display(t: &Track) -> Option<String> {
+    match (&t.artist, &t.title) {
+        (Some(a), Some(title)) => Some(format!("{} - {}", a, title)),
+        (None, Some(title)) => Some(title.clone()),
+        _ => None,
+    }
+}
+
+// Negative lengths mean "unknown" (streams) in both M3U and PLS.
+fn parse_length(s: &str) -> Option<u64> {
+    s.trim()
+        .parse::<i64>()
+        .ok()
+        .and_then(|n| u64::try_from(n).ok())
+}
+
+fn parse_m3u(text: &str, base: &Path, source: &str) -> Vec<Track> {
+    let mut tracks = Vec::new();
+    let mut pending: Option<(Option<u64>, Option<String>, Option<String>)> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>[ key="value" ...],<Artist - Title>
+            let (head, name) = info.split_once(',').unwrap_or((info, ""));
+            let secs = head.split_whitespace().next().and_then(parse_length);
+            let (artist, title) = split_display(name);
+            pending = Some((secs, artist, title));
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut t = Track::from_uri(&resolve(line, base), source);
+        if let Some((secs, artist, title)) = pending.take() {
+            t.duration = secs;
+            t.artist = artist;
+            t.title = title;
+        }
+        tracks.push(t);
+    }
+    tracks
+}
+
+fn render_m3u(tracks: &[Track]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for t in tracks {
+        let name = join_display(t);
+        if name.is_some() || t.duration.is_some() {
+            let secs = t.duration.map(|d| d as i64).unwrap_or(-1);
+            let _ = writeln!(out, "#EXTINF:{},{}", secs, name.unwrap_or_default());
+        }
+        let _ = writeln!(out, "{}", t.uri);
+    }
+    out
+}
+
+#[derive(Default)]
+struct PlsEntry {
+    file: Option<String>,
+    title: Option<String>,
+    length: Option<u64>,
+}
+
+fn parse_pls(text: &str, base: &Path, source: &str) -> Vec<Track> {
+    // FileN / TitleN / LengthN, ordered by N
+    let mut entries: BTreeMap<u32, PlsEntry> = BTreeMap::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+        let (field, n) = match key.find(|c: char| c.is_ascii_digit()) {
+            Some(i) => (&key[..i], &key[i..]),
+            None => continue,
+        };
+        let Ok(n) = n.parse::<u32>() else {
+            continue;
+        };
+        let e = entries.entry(n).or_default();
+        match field {
+            "file" => e.file = Some(value.to_string()),
+            "title" => e.title = Some(value.to_string()),
+            "length" => e.length = parse_length(value),
+            _ => {}
+        }
+    }
+    entries
+        .into_values()
+        .filter_map(|e| {
+            let mut t = Track::from_uri(&resolve(&e.file?, base), source);
+            if let Some(title) = e.title {
+                (t.artist, t.title) = split_display(&title);
+            }
+            t.duration = e.length;
+            Some(t)
+        })
+        .collect()
+}
+
+fn render_pls(tracks: &[Track]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (i, t) in tracks.iter().enumerate() {
+        let n = i + 1;
+        let _ = writeln!(out, "File{}={}", n, t.uri);
+        if let Some(name) = join_display(t) {
+            let _ = writeln!(out, "Title{}={}", n, name);
+        }
+        let secs = t.duration.map(|d| d as i64).unwrap_or(-1);
+        let _ = writeln!(out, "Length{}={}", n, secs);
+    }
+    let _ = writeln!(out, "NumberOfEntries={}", tracks.len());
+    out.push_str("Version=2\n");
+    out
+}
+
+fn parse_xspf(text: &str, base: &Path, source: &str) -> Result<Vec<Track>> {
+    let doc = roxmltree::Document::parse(text).context("invalid XSPF")?;
+    let root = doc.root_element();
+    if root.tag_name().name() != "playlist" {
+        bail!(
+            "not an XSPF playlist (root element is <{}>)",
+            root.tag_name().name()
+        );
+    }
+    let child = |n: roxmltree::Node, name: &str| {
+        n.children()
+            .find(|c| c.tag_name().name() == name)
+            .and_then(|c| c.text())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    // XSPF locations are URIs, so relative ones are resolved (and percent-decoded) as URLs
+    let base_url = Url::from_directory_path(base).ok();
+    let mut tracks = Vec::new();
+    let list = root
+        .children()
+        .filter(|c| c.tag_name().name() == "trackList");
+    for node in list.flat_map(|l| l.children()) {
+        if node.tag_name().name() != "track" {
+            continue;
+        }
+        let Some(location) = child(node, "location") else {
+            continue;
+        };
+        let uri = match &base_url {
+            Some(b) if !is_url(&location) => match b.join(&location) {
+                Ok(u) => resolve(u.as_str(), base),
+                Err(_) => resolve(&location, base),
+            },
+            _ => resolve(&location, base),
+        };
+        let mut t = Track::from_uri(&uri, source);
+        t.title = child(node, "title");
+        t.artist = child(node, "creator");
+        t.album = child(node, "album");
+        // milliseconds in XSPF
+        t.duration = child(node, "duration")
+            .and_then(|d| d.parse::<u64>().ok())
+            .map(|ms| ms / 1000);
+        tracks.push(t);
+    }
+    Ok(tracks)
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_xspf(tracks: &[Track]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for t in tracks {
+        // local paths have to be written as file:// URIs
+        let location = if is_url(&t.uri) {
+            t.uri.clone()
+        } else {
+            Url::from_file_path(PathBuf::from(&t.uri))
+                .map(|u| u.to_string())
+                .unwrap_or_else(|_| t.uri.clone())
+        };
+        out.push_str("    <track>\n");
+        let _ = writeln!(out, "      <location>{}</location>", xml_escape(&location));
+        for (tag, value) in [
+            ("title", &t.title),
+            ("creator", &t.artist),
+            ("album", &t.album),
+        ] {
+            if let Some(v) = value {
+                let _ = writeln!(out, "      <{tag}>{}</{tag}>", xml_escape(v));
+            }
+        }
+        if let Some(d) = t.duration {
+            let _ = writeln!(out, "      <duration>{}</duration>", d * 1000);
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uris(tracks: &[Track]) -> Vec<&str> {
+        tracks.iter().map(|t| t.uri.as_str()).collect()
+    }
+
+    #[test]
+    fn m3u_reads_extinf_and_resolves_relative_paths() {
+        let text = "\u{feff}#EXTM3U\n\
+                    #EXTINF:225,Band - Song\n\
+                    music/song.mp3\n\
+                    \n\
+                    # a comment\n\
+                    #EXTINF:-1,Radio\n\
+                    https://radio.example/stream\n\
+                    /abs/other.flac\n";
+        let tracks = parse(text, PlaylistFormat::M3u, Path::new("/lists"), "mpv").unwrap();
+        assert_eq!(
+            uris(&tracks),
+            vec![
+                "/lists/music/song.mp3",
+                "https://radio.example/stream",
+                "/abs/other.flac"
+            ]
+        );
+        assert_eq!(tracks[0].artist.as_deref(), Some("Band"));
+        assert_eq!(tracks[0].title.as_deref(), Some("Song"));
+        assert_eq!(tracks[0].duration, Some(225));
+        assert_eq!(tracks[1].title.as_deref(), Some("Radio"));
+        assert_eq!(tracks[1].duration, None);
+        assert_eq!(tracks[2].title, None);
+    }
+
+    #[test]
+    fn pls_orders_by_entry_number() {
+        let text = "[playlist]\n\
+                    File2=https://b.example/stream\n\
+                    Title2=Second\n\
+                    Length2=-1\n\
+                    File1=a.mp3\n\
+                    Title1=Band - First\n\
+                    Length1=60\n\
+                    NumberOfEntries=2\n\
+                    Version=2\n";
+        let tracks = parse(text, PlaylistFormat::Pls, Path::new("/lists"), "mpv").unwrap();
+        assert_eq!(
+            uris(&tracks),
+            vec!["/lists/a.mp3", "https://b.example/stream"]
+        );
+        assert_eq!(tracks[0].artist.as_deref(), Some("Band"));
+        assert_eq!(tracks[0].duration, Some(60));
+        assert_eq!(tracks[1].title.as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn xspf_reads_locations_and_metadata() {
+        let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <trackList>
+    <track>
+      <location>file:///music/My%20Song.ogg</location>
+      <title>My Song</title>
+      <creator>Band</creator>
+      <album>Record</album>
+      <duration>61500</duration>
+    </track>
+    <track><location>sub/a%20b.mp3</location></track>
+    <track><title>no location</title></track>
+  </trackList>
+</playlist>"#;
+        let tracks = parse(text, PlaylistFormat::Xspf, Path::new("/lists"), "mpv").unwrap();
+        assert_eq!(
+            uris(&tracks),
+            vec!["/music/My Song.ogg", "/lists/sub/a b.mp3"]
+        );
+        assert_eq!(tracks[0].artist.as_deref(), Some("Band"));
+        assert_eq!(tracks[0].album.as_deref(), Some("Record"));
+        assert_eq!(tracks[0].duration, Some(61));
+
+        assert!(parse("<html/>", PlaylistFormat::Xspf, Path::new("/"), "mpv").is_err());
+    }
+
+    #[test]
+    fn every_format_roundtrips() {
+        let mut a = Track::from_uri("/music/a & b.mp3", "mpv");
+        a.artist = Some("Band".into());
+        a.title = Some("Song".into());
+        a.duration = Some(200);
+        let b = Track::from_uri("https://radio.example/stream", "mpv");
+        let tracks = vec![a, b];
+        for format in [
+            PlaylistFormat::M3u,
+            PlaylistFormat::Pls,
+            PlaylistFormat::Xspf,
+        ] {
+            let text = render(&tracks, format);
+            let back = parse(&text, format, Path::new("/elsewhere"), "mpv").unwrap();
+            assert_eq!(back, tracks, "{:?}:\n{}", format, text);
+        }
+    }
+
+    #[test]
+    fn files_are_detected_by_extension_or_content() {
+        let dir = std::env::temp_dir().join(format!("apple-playlist-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let tracks = vec![Track::from_uri("https://radio.example/stream", "mpv")];
+
+        let path = dir.join("list.pls");
+        save_playlist(&path, &tracks).unwrap();
+        assert_eq!(load_playlist(&path, "mpv").unwrap(), tracks);
+        assert!(save_playlist(&dir.join("list.txt"), &tracks).is_err());
+
+        // no extension: the [playlist] header gives it away
+        let bare = dir.join("stations");
+        fs::copy(&path, &bare).unwrap();
+        assert_eq!(load_playlist(&bare, "mpv").unwrap(), tracks);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,250 @@
+// Named playlists kept under the config dir (`<config>/playlists/<name>.json`).
+
+use crate::track::Track;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SavedPlaylist {
+    #[serde(default)]
+    tracks: Vec<Track>,
+}
+
+pub struct PlaylistStore {
+    dir: PathBuf,
+}
+
+impl PlaylistStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The store under the default config dir.
+    pub fn open_default() -> Self {
+        Self::new(crate::config::default_config_dir().join("playlists"))
+    }
+
+    /// Playlist names, sorted.
+    pub fn names(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("reading playlist dir"),
+        };
+        let mut names: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_str()?.strip_suffix(".json")?.to_string();
+                check_name(&name).ok().map(|_| name)
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        check_name(name).is_ok() && self.path(name).exists()
+    }
+
+    pub fn tracks(&self, name: &str) -> Result<Vec<Track>> {
+        check_name(name)?;
+        let s = match fs::read_to_string(self.path(name)) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                bail!("no playlist named '{}'", name)
+            }
+            Err(e) => return Err(e).context("reading playlist"),
+        };
+        let pl: SavedPlaylist =
+            serde_json::from_str(&s).with_context(|| format!("parsing playlist '{}'", name))?;
+        Ok(pl.tracks)
+    }
+
+    /// Create an empty playlist; fails if one with that name exists.
+    pub fn create(&self, name: &str) -> Result<()> {
+        check_name(name)?;
+        if self.path(name).exists() {
+            bail!("playlist '{}' already exists", name);
+        }
+        self.write(name, &[])
+    }
+
+    /// Create or overwrite `name` with `tracks`.
+    pub fn save(&self, name: &str, tracks: &[Track]) -> Result<()> {
+        check_name(name)?;
+        self.write(name, tracks)
+    }
+
+    /// Append to an existing playlist; returns its new length.
+    pub fn append(&self, name: &str, tracks: &[Track]) -> Result<usize> {
+        let mut all = self.tracks(name)?;
+        all.extend_from_slice(tracks);
+        self.write(name, &all)?;
+        Ok(all.len())
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        check_name(to)?;
+        if !self.exists(from) {
+            bail!("no playlist named '{}'", from);
+        }
+        if self.path(to).exists() {
+            bail!("playlist '{}' already exists", to);
+        }
+        fs::rename(self.path(from), self.path(to)).context("renaming playlist")
+    }
+
+    pub fn delete(&self, name: &str) -> Result<()> {
+        if !self.exists(name) {
+            bail!("no playlist named '{}'", name);
+        }
+        fs::remove_file(self.path(name)).context("deleting playlist")
+    }
+
+    /// A playlist file given relative to the store dir, e.g. `road-trip.m3u` or
+    /// `mixes/chill.pls`. Absolute paths and `..` are refused so the file stays inside it, and
+    /// so are symlinks that lead out of it.
+    pub fn file_path(&self, relative: &str) -> Result<PathBuf> {
+        let rel = Path::new(relative);
+        if relative.is_empty()
+            || !rel
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            bail!(
+                "playlist file '{}' must be a path inside the playlist dir, without '..'",
+                relative
+            );
+        }
+        let path = self.dir.join(rel);
+        let Ok(root) = self.dir.canonicalize() else {
+            // nothing exists yet, so there is no link to follow
+            return Ok(path);
+        };
+        // The deepest part that exists decides where the path really goes; whatever is
+        // created below it stays there.
+        let existing = path
+            .ancestors()
+            .find(|p| p.symlink_metadata().is_ok())
+            .unwrap_or(&self.dir);
+        let resolved = existing
+            .canonicalize()
+            .with_context(|| format!("resolving playlist file '{}'", relative))?;
+        if !resolved.starts_with(&root) {
+            bail!(
+                "playlist file '{}' leads outside the playlist dir through a symlink",
+                relative
+            );
+        }
+        Ok(path)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    fn write(&self, name: &str, tracks: &[Track]) -> Result<()> {
+        fs::create_dir_all(&self.dir).context("creating playlist dir")?;
+        let pl = SavedPlaylist {
+            tracks: tracks.to_vec(),
+        };
+        let s = serde_json::to_string_pretty(&pl).context("serialize playlist")?;
+        // same temp-file-and-rename dance as the state file
+        let tmp = self.dir.join(format!(".{}.json.tmp", name));
+        fs::write(&tmp, s).context("write playlist")?;
+        fs::rename(&tmp, self.path(name)).context("replace playlist file")
+    }
+}
+
+// Names become file names and are passed as the first word of daemon arguments, so keep them
+// to a single path-safe word.
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.len() > 100
+        || name.starts_with('.')
+        || name
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '/' | '\\'))
+    {
+        bail!(
+            "invalid playlist name '{}' (use a single word without slashes)",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_append_rename_delete() {
+        let dir = std::env::temp_dir().join(format!("apple-playlists-test-{}", std::process::id()));
+        let store = PlaylistStore::new(dir.clone());
+        assert!(store.names().unwrap().is_empty());
+
+        store.create("road-trip").unwrap();
+        assert!(store.create("road-trip").is_err());
+        assert!(store.create("../escape").is_err());
+        assert!(store.create("two words").is_err());
+
+        let a = Track::from_uri("a.mp3", "mpv");
+        let b = Track::from_uri("b.mp3", "mpv");
+        assert_eq!(
+            store.append("road-trip", std::slice::from_ref(&a)).unwrap(),
+            1
+        );
+        assert_eq!(
+            store.append("road-trip", std::slice::from_ref(&b)).unwrap(),
+            2
+        );
+        assert!(store.append("missing", std::slice::from_ref(&a)).is_err());
+
+        store.save("chill", std::slice::from_ref(&b)).unwrap();
+        store.rename("road-trip", "drive").unwrap();
+        assert!(store.rename("drive", "chill").is_err());
+        assert_eq!(store.names().unwrap(), vec!["chill", "drive"]);
+        assert_eq!(store.tracks("drive").unwrap(), vec![a, b]);
+
+        store.delete("drive").unwrap();
+        assert!(store.tracks("drive").is_err());
+        assert_eq!(store.names().unwrap(), vec!["chill"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn files_stay_inside_the_dir() {
+        let store = PlaylistStore::new(PathBuf::from("/music/playlists"));
+        assert_eq!(
+            store.file_path("mixes/chill.m3u").unwrap(),
+            Path::new("/music/playlists/mixes/chill.m3u")
+        );
+        for bad in ["", "/etc/passwd", "../state.json", "mixes/../../x.m3u"] {
+            assert!(store.file_path(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn symlinks_out_of_the_dir_are_refused() {
+        let base = std::env::temp_dir().join(format!("apple-store-links-{}", std::process::id()));
+        let dir = base.join("playlists");
+        let outside = base.join("outside");
+        fs::create_dir_all(dir.join("mixes")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+        std::os::unix::fs::symlink(outside.join("x.m3u"), dir.join("dangling.m3u")).unwrap();
+        std::os::unix::fs::symlink(dir.join("mixes"), dir.join("inner")).unwrap();
+        let store = PlaylistStore::new(dir.clone());
+
+        for bad in ["out", "out/x.m3u", "out/new/x.m3u", "dangling.m3u"] {
+            assert!(store.file_path(bad).is_err(), "{}", bad);
+        }
+        for good in ["a.m3u", "mixes/new/b.pls", "inner/c.xspf"] {
+            assert_eq!(store.file_path(good).unwrap(), dir.join(good));
+        }
+        let _ = fs::remove_dir_all(&base);
+    }
+}
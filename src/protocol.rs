@@ -0,0 +1,855 @@
+// Wire protocol between the daemon and its clients (applectl, the TUI).
+//
+// One JSON object per line in each direction. Version 1 requests carry a version, an optional
+// request id and token, the command and its structured parameters:
+//   {"v":1,"id":7,"token":"secret","cmd":"move","params":{"from":2,"to":0}}
+// Responses echo the id; failures add a machine-readable `code` next to the `msg`:
+//   {"v":1,"id":7,"ok":false,"msg":"index out of range","code":"not_found","items":null}
+// Clients may start with `hello` to learn the daemon's protocol version and capabilities.
+// After `subscribe` the daemon also pushes state changes on that connection, one per line:
+//   {"v":1,"event":"queue","tracks":[...]}
+// starting with the full state; see `crate::events`.
+//
+// Lines without "v" use the original format, `{"cmd":"move","arg":"2 0","token":"..."}`, with
+// all parameters packed into one string. `Request::from_legacy` translates those so older
+// clients and scripts keep working.
+
+use crate::events::{Event, DEFAULT_TICK_MS};
+use crate::playback::PlaybackStatus;
+use crate::player::RepeatMode;
+use crate::track::Track;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Default step for `seek_forward` / `seek_backward`.
+pub const DEFAULT_SEEK_SECONDS: u64 = 10;
+
+/// Commands the daemon understands, as sent in `cmd`.
+pub const COMMANDS: &[&str] = &[
+    "hello",
+    "play",
+    "pause",
+    "enqueue",
+    "insert_next",
+    "next",
+    "prev",
+    "clear",
+    "list",
+    "history",
+    "status",
+    "position",
+    "duration",
+    "remove",
+    "move",
+    "play_index",
+    "repeat",
+    "shuffle",
+    "queue_load",
+    "queue_save",
+    "enqueue_tracks",
+    "search",
+    "enqueue_id",
+    "play_ids",
+    "playlist_list",
+    "playlist_show",
+    "playlist_create",
+    "playlist_rename",
+    "playlist_delete",
+    "playlist_add",
+    "playlist_save",
+    "playlist_load",
+    "artist_info",
+    "artist_discography",
+    "volume_up",
+    "volume_down",
+    "set_volume",
+    "mute",
+    "unmute",
+    "seek_forward",
+    "seek_backward",
+    "seek_to",
+    "subscribe",
+    "unsubscribe",
+];
+
+/// A daemon command with its parameters. Queue indices are zero-based.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "cmd", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    /// Handshake: the client's protocol version and name
+    Hello {
+        #[serde(default)]
+        version: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<String>,
+    },
+    Play {
+        uri: String,
+    },
+    Pause,
+    Enqueue {
+        uri: String,
+    },
+    InsertNext {
+        uri: String,
+    },
+    Next,
+    Prev,
+    Clear,
+    List,
+    History,
+    Status,
+    /// Playback position in seconds
+    Position,
+    /// Duration of the current track in seconds
+    Duration,
+    Remove {
+        index: usize,
+    },
+    Move {
+        from: usize,
+        to: usize,
+    },
+    PlayIndex {
+        index: usize,
+    },
+    Repeat {
+        mode: RepeatMode,
+    },
+    Shuffle {
+        on: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seed: Option<u64>,
+    },
+    /// Append a playlist file, relative to the daemon's playlist dir, to the queue
+    QueueLoad {
+        path: String,
+    },
+    /// Write the queue to a playlist file relative to the playlist dir; the format follows
+    /// the extension
+    QueueSave {
+        path: String,
+    },
+    /// Append tracks with their metadata, e.g. a playlist file the client read itself
+    EnqueueTracks {
+        tracks: Vec<Track>,
+    },
+    Search {
+        query: String,
+    },
+    /// Queue library tracks by id
+    EnqueueId {
+        ids: Vec<String>,
+    },
+    /// Play the first library track now and queue the rest next
+    PlayIds {
+        ids: Vec<String>,
+    },
+    PlaylistList,
+    PlaylistShow {
+        name: String,
+    },
+    PlaylistCreate {
+        name: String,
+    },
+    PlaylistRename {
+        from: String,
+        to: String,
+    },
+    PlaylistDelete {
+        name: String,
+    },
+    /// Append `uri`, or the current track, to a playlist
+    PlaylistAdd {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uri: Option<String>,
+    },
+    /// Replace a playlist with the queue
+    PlaylistSave {
+        name: String,
+    },
+    /// Append a playlist to the queue
+    PlaylistLoad {
+        name: String,
+    },
+    ArtistInfo {
+        artist_id: String,
+    },
+    ArtistDiscography {
+        artist_id: String,
+    },
+    VolumeUp,
+    VolumeDown,
+    SetVolume {
+        volume: u8,
+    },
+    Mute,
+    Unmute,
+    SeekForward {
+        #[serde(default = "default_seek")]
+        seconds: u64,
+    },
+    SeekBackward {
+        #[serde(default = "default_seek")]
+        seconds: u64,
+    },
+    SeekTo {
+        seconds: u64,
+    },
+    /// Stream state changes on this connection, with a `position` event every `tick_ms`
+    /// (0 for none)
+    Subscribe {
+        #[serde(default = "default_tick")]
+        tick_ms: u64,
+    },
+    Unsubscribe,
+}
+
+fn default_seek() -> u64 {
+    DEFAULT_SEEK_SECONDS
+}
+
+fn default_tick() -> u64 {
+    DEFAULT_TICK_MS
+}
+
+impl Request {
+    /// Whether running the request may change what subscribers see.
+    pub fn changes_state(&self) -> bool {
+        !matches!(
+            self,
+            Request::Hello { .. }
+                | Request::List
+                | Request::History
+                | Request::Status
+                | Request::Position
+                | Request::Duration
+                | Request::Search { .. }
+                | Request::PlaylistList
+                | Request::PlaylistShow { .. }
+                | Request::ArtistInfo { .. }
+                | Request::ArtistDiscography { .. }
+                | Request::Subscribe { .. }
+                | Request::Unsubscribe
+        )
+    }
+
+    /// Whether running the request may add, rename or remove named playlists.
+    pub fn changes_playlists(&self) -> bool {
+        matches!(
+            self,
+            Request::PlaylistCreate { .. }
+                | Request::PlaylistRename { .. }
+                | Request::PlaylistDelete { .. }
+                | Request::PlaylistSave { .. }
+        )
+    }
+}
+
+/// Machine-readable reason a request failed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The line is not a JSON object
+    ParseError,
+    /// `v` is not a version this daemon speaks
+    UnsupportedVersion,
+    /// Missing or wrong token
+    Unauthorized,
+    UnknownCommand,
+    /// Missing or malformed parameters
+    InvalidParams,
+    /// No such queue index, playlist, library track, ...
+    NotFound,
+    /// Refused by policy, e.g. a plain http URL
+    Forbidden,
+    /// A valid request that could not be carried out
+    Failed,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // same spelling as on the wire
+        let s = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        f.write_str(s.as_str().unwrap_or_default())
+    }
+}
+
+/// Why a request failed: the `code` and `msg` of the response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub code: ErrorCode,
+    pub msg: String,
+}
+
+impl Failure {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    fn invalid(msg: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParams, msg)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.msg, self.code)
+    }
+}
+
+/// What the daemon answers to `hello`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    /// Highest protocol version the daemon speaks
+    pub version: u32,
+    /// Daemon version
+    pub server: String,
+    /// Whether requests other than `hello` need the token
+    pub auth_required: bool,
+    pub commands: Vec<String>,
+    /// Optional features, e.g. "legacy" (the unversioned format), "jsonrpc" (JSON-RPC 2.0
+    /// framing, see `crate::jsonrpc`), "events" (the queue
+    /// advances by itself), "library" (a local index is available)
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+    /// The request's id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub ok: bool,
+    pub msg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    // plain uris alongside `tracks` for older clients; always present in the original format
+    #[serde(default)]
+    pub items: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracks: Option<Vec<Track>>,
+    // structured status; `msg` carries its one-line rendering
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<PlaybackStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hello: Option<Hello>,
+}
+
+impl Response {
+    pub fn ok(msg: impl Into<String>) -> Self {
+        Response {
+            ok: true,
+            msg: msg.into(),
+            ..Response::default()
+        }
+    }
+
+    pub fn fail(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Failure::new(code, msg).into()
+    }
+
+    /// A `failed` response carrying the error chain.
+    pub fn error(e: anyhow::Error) -> Self {
+        Response::fail(ErrorCode::Failed, format!("{:#}", e))
+    }
+
+    /// Attach `tracks`, with their uris as `items`.
+    pub fn with_tracks(mut self, tracks: Vec<Track>) -> Self {
+        self.items = Some(tracks.iter().map(|t| t.uri.clone()).collect());
+        self.tracks = Some(tracks);
+        self
+    }
+
+    pub fn with_items(mut self, items: Vec<String>) -> Self {
+        self.items = Some(items);
+        self
+    }
+
+    /// The response if it succeeded, else its message as an error.
+    pub fn into_result(self) -> anyhow::Result<Self> {
+        if self.ok {
+            Ok(self)
+        } else {
+            Err(anyhow::anyhow!(self.msg))
+        }
+    }
+}
+
+impl From<Failure> for Response {
+    fn from(f: Failure) -> Self {
+        Response {
+            ok: false,
+            msg: f.msg,
+            code: Some(f.code),
+            ..Response::default()
+        }
+    }
+}
+
+/// A request line in the current format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// A pushed event as sent to subscribers of the line protocol.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventLine {
+    pub v: u32,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// A request line as received, in either format.
+#[derive(Debug)]
+pub struct Incoming {
+    pub id: Option<u64>,
+    pub token: Option<String>,
+    pub request: Result<Request, Failure>,
+}
+
+/// Parse one request line. Fails only if the line is not a JSON object at all; problems with
+/// the command itself end up in `Incoming::request` so the reply can still echo the id.
+pub fn parse_request(line: &str) -> Result<Incoming, Failure> {
+    let value: Value = serde_json::from_str(line)
+        .map_err(|e| Failure::new(ErrorCode::ParseError, format!("parse error: {}", e)))?;
+    let Some(obj) = value.as_object() else {
+        return Err(Failure::new(
+            ErrorCode::ParseError,
+            "expected a JSON object",
+        ));
+    };
+    let id = obj.get("id").and_then(Value::as_u64);
+    let token = obj.get("token").and_then(Value::as_str).map(str::to_string);
+    let request = parse_command(&value);
+    Ok(Incoming { id, token, request })
+}
+
+fn parse_command(value: &Value) -> Result<Request, Failure> {
+    let Some(cmd) = value.get("cmd").and_then(Value::as_str) else {
+        return Err(Failure::invalid("missing cmd"));
+    };
+    if !COMMANDS.contains(&cmd) {
+        return Err(Failure::new(
+            ErrorCode::UnknownCommand,
+            format!("unknown cmd '{}'", cmd),
+        ));
+    }
+    match value.get("v") {
+        None => Request::from_legacy(cmd, value.get("arg").and_then(Value::as_str)),
+        Some(v) if v.as_u64() == Some(PROTOCOL_VERSION as u64) => {
+            serde_json::from_value::<Envelope>(value.clone())
+                .map(|e| e.request)
+                .map_err(|e| Failure::invalid(format!("invalid params for {}: {}", cmd, e)))
+        }
+        Some(v) => Err(Failure::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "unsupported protocol version {} (daemon speaks {})",
+                v, PROTOCOL_VERSION
+            ),
+        )),
+    }
+}
+
+/// "on"/"off" (also true/false, 1/0).
+pub fn parse_switch(s: &str) -> Option<bool> {
+    match s.to_ascii_lowercase().as_str() {
+        "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+impl Request {
+    /// Translate a request in the original `{cmd, arg}` format.
+    pub fn from_legacy(cmd: &str, arg: Option<&str>) -> Result<Request, Failure> {
+        let arg = arg.map(str::trim).filter(|a| !a.is_empty());
+        let required = || {
+            arg.map(str::to_string)
+                .ok_or(Failure::invalid("missing arg"))
+        };
+        let index = || {
+            arg.and_then(|a| a.parse::<usize>().ok())
+                .ok_or(Failure::invalid("missing or invalid index"))
+        };
+        // "<name>" or "<name> <rest>"
+        let (name, rest) = match arg.map(|a| a.split_once(char::is_whitespace)) {
+            Some(Some((name, rest))) => (Some(name.to_string()), Some(rest.trim().to_string())),
+            Some(None) => (arg.map(str::to_string), None),
+            None => (None, None),
+        };
+        let name = || name.clone().ok_or(Failure::invalid("missing arg"));
+        let ids = || {
+            let ids: Vec<String> = arg
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect();
+            if ids.is_empty() {
+                Err(Failure::invalid("missing arg"))
+            } else {
+                Ok(ids)
+            }
+        };
+        let seek = || {
+            arg.and_then(|a| a.parse().ok())
+                .unwrap_or(DEFAULT_SEEK_SECONDS)
+        };
+
+        Ok(match cmd {
+            "hello" => Request::Hello {
+                version: arg.and_then(|a| a.parse().ok()).unwrap_or_default(),
+                client: None,
+            },
+            "play" => Request::Play { uri: required()? },
+            "pause" => Request::Pause,
+            "enqueue" => Request::Enqueue { uri: required()? },
+            "insert_next" => Request::InsertNext { uri: required()? },
+            "next" => Request::Next,
+            "prev" => Request::Prev,
+            "clear" => Request::Clear,
+            "list" => Request::List,
+            "history" => Request::History,
+            "status" => Request::Status,
+            "position" => Request::Position,
+            "duration" => Request::Duration,
+            "remove" => Request::Remove { index: index()? },
+            "play_index" => Request::PlayIndex { index: index()? },
+            "move" => {
+                let idx: Vec<usize> = arg
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .filter_map(|s| s.parse().ok())
+                    .collect();
+                match idx.as_slice() {
+                    [from, to] => Request::Move {
+                        from: *from,
+                        to: *to,
+                    },
+                    _ => return Err(Failure::invalid("expected arg \"<from> <to>\"")),
+                }
+            }
+            "repeat" => Request::Repeat {
+                mode: required()?
+                    .parse()
+                    .map_err(|e: anyhow::Error| Failure::invalid(e.to_string()))?,
+            },
+            "shuffle" => {
+                // "on", "off" or "on <seed>"
+                let mut parts = arg.unwrap_or_default().split_whitespace();
+                let Some(on) = parts.next().and_then(parse_switch) else {
+                    return Err(Failure::invalid(
+                        "expected arg \"on\", \"off\" or \"on <seed>\"",
+                    ));
+                };
+                let seed = parts.next().and_then(|s| s.parse().ok());
+                Request::Shuffle { on, seed }
+            }
+            "queue_load" => Request::QueueLoad { path: required()? },
+            "queue_save" => Request::QueueSave { path: required()? },
+            "enqueue_tracks" => Request::EnqueueTracks {
+                tracks: serde_json::from_str(&required()?)
+                    .map_err(|e| Failure::invalid(format!("expected a JSON track list: {}", e)))?,
+            },
+            "search" => Request::Search { query: required()? },
+            "enqueue_id" => Request::EnqueueId { ids: ids()? },
+            "play_ids" => Request::PlayIds { ids: ids()? },
+            "playlist_list" => Request::PlaylistList,
+            "playlist_show" => Request::PlaylistShow { name: name()? },
+            "playlist_create" => Request::PlaylistCreate { name: name()? },
+            "playlist_rename" => match rest {
+                Some(to) => Request::PlaylistRename { from: name()?, to },
+                None => return Err(Failure::invalid("usage: playlist_rename <old> <new>")),
+            },
+            "playlist_delete" => Request::PlaylistDelete { name: name()? },
+            "playlist_add" => Request::PlaylistAdd {
+                name: name()?,
+                uri: rest,
+            },
+            "playlist_save" => Request::PlaylistSave { name: name()? },
+            "playlist_load" => Request::PlaylistLoad { name: name()? },
+            "artist_info" => Request::ArtistInfo {
+                artist_id: required()?,
+            },
+            "artist_discography" => Request::ArtistDiscography {
+                artist_id: required()?,
+            },
+            "volume_up" => Request::VolumeUp,
+            "volume_down" => Request::VolumeDown,
+            "set_volume" => match arg.map(str::parse::<u8>) {
+                Some(Ok(volume)) => Request::SetVolume { volume },
+                Some(Err(_)) => return Err(Failure::invalid("invalid volume")),
+                None => return Err(Failure::invalid("missing volume")),
+            },
+            "mute" => Request::Mute,
+            "unmute" => Request::Unmute,
+            "seek_forward" => Request::SeekForward { seconds: seek() },
+            "seek_backward" => Request::SeekBackward { seconds: seek() },
+            "seek_to" => match arg.map(str::parse::<u64>) {
+                Some(Ok(seconds)) => Request::SeekTo { seconds },
+                Some(Err(_)) => return Err(Failure::invalid("invalid seconds")),
+                None => return Err(Failure::invalid("missing seconds")),
+            },
+            "subscribe" => match arg.map(str::parse::<u64>) {
+                Some(Ok(tick_ms)) => Request::Subscribe { tick_ms },
+                Some(Err(_)) => return Err(Failure::invalid("invalid tick interval")),
+                None => Request::Subscribe {
+                    tick_ms: DEFAULT_TICK_MS,
+                },
+            },
+            "unsubscribe" => Request::Unsubscribe,
+            _ => {
+                return Err(Failure::new(
+                    ErrorCode::UnknownCommand,
+                    format!("unknown cmd '{}'", cmd),
+                ))
+            }
+        })
+    }
+}
+
+impl Request {
+    /// Build a request from a command name and its named parameters, as JSON-RPC and the HTTP
+    /// API receive them; goes through the same representation as `{"v":1,"cmd":..,"params":..}`.
+    pub fn from_params(cmd: &str, params: Map<String, Value>) -> Result<Request, Failure> {
+        if !COMMANDS.contains(&cmd) {
+            return Err(Failure::new(
+                ErrorCode::UnknownCommand,
+                format!("unknown cmd '{}'", cmd),
+            ));
+        }
+        let mut value = Map::new();
+        value.insert("cmd".into(), cmd.into());
+        // unit commands (pause, next, ...) only parse without "params", so add it only when needed
+        if !params.is_empty()
+            || serde_json::from_value::<Request>(Value::Object(value.clone())).is_err()
+        {
+            value.insert("params".into(), Value::Object(params));
+        }
+        serde_json::from_value(Value::Object(value))
+            .map_err(|e| Failure::invalid(format!("invalid params for {}: {}", cmd, e)))
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The daemon's unix socket when APPLE_DAEMON_SOCKET is not set: `apple-daemon.sock` in
+/// $XDG_RUNTIME_DIR, or `apple-daemon-<uid>.sock` in the temp dir without one. The name is the
+/// same for every daemon of a user, so a second one finds the first one listening.
+#[cfg(unix)]
+pub fn default_socket_path() -> std::path::PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        Some(dir) => std::path::PathBuf::from(dir).join("apple-daemon.sock"),
+        None => std::env::temp_dir().join(format!("apple-daemon-{}.sock", nix::unistd::getuid())),
+    }
+}
+
+/// Where clients reach the daemon: APPLE_DAEMON_SOCKET, or the default unix socket. None
+/// without unix sockets, where the daemon's TCP port has to be given.
+pub fn daemon_socket() -> Option<String> {
+    let socket = std::env::var("APPLE_DAEMON_SOCKET")
+        .ok()
+        .filter(|s| !s.is_empty());
+    #[cfg(unix)]
+    let socket = socket.or_else(|| Some(default_socket_path().to_string_lossy().into_owned()));
+    socket
+}
+
+/// Send `request` to the daemon at `socket` (a unix socket path, or host:port where unix
+/// sockets are unavailable) and wait for the response. Failed requests are still `Ok`; check
+/// `Response::ok` or use `Response::into_result`.
+pub async fn call(socket: &str, token: Option<&str>, request: Request) -> anyhow::Result<Response> {
+    let (resp, _) = open(socket, token, request).await?;
+    Ok(resp)
+}
+
+/// Subscribe to the daemon's state changes. The receiver gets the full state first, then
+/// every change, and closes when the connection drops.
+pub async fn subscribe(
+    socket: &str,
+    token: Option<&str>,
+    tick_ms: u64,
+) -> anyhow::Result<tokio::sync::mpsc::UnboundedReceiver<Event>> {
+    use tokio::io::AsyncBufReadExt;
+    let (resp, reader) = open(socket, token, Request::Subscribe { tick_ms }).await?;
+    resp.into_result()?;
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = reader.lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            // replies to other requests on this connection are not events
+            let Ok(ev) = serde_json::from_str::<EventLine>(&line) else {
+                continue;
+            };
+            if tx.send(ev.event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(unix)]
+type Stream = tokio::net::UnixStream;
+#[cfg(not(unix))]
+type Stream = tokio::net::TcpStream;
+
+type Reader = tokio::io::BufReader<tokio::io::ReadHalf<Stream>>;
+
+// Connect, send `request` and read its response; the rest of the connection is returned for
+// what the daemon sends afterwards.
+async fn open(
+    socket: &str,
+    token: Option<&str>,
+    request: Request,
+) -> anyhow::Result<(Response, Reader)> {
+    use tokio::io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader};
+    let stream = Stream::connect(socket)
+        .await
+        .with_context(|| format!("connecting to daemon at {}", socket))?;
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let envelope = Envelope {
+        v: PROTOCOL_VERSION,
+        id: Some(id),
+        token: token.map(str::to_string),
+        request,
+    };
+    let (r, mut w) = split(stream);
+    let line = serde_json::to_string(&envelope)? + "\n";
+    w.write_all(line.as_bytes()).await?;
+    let mut reader = BufReader::new(r);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    if line.is_empty() {
+        bail!("daemon closed the connection");
+    }
+    let resp: Response = serde_json::from_str(&line).context("parsing daemon response")?;
+    if resp.id.is_some_and(|r| r != id) {
+        bail!("response id {:?} does not match request {}", resp.id, id);
+    }
+    Ok((resp, reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Request, Failure> {
+        parse_request(line).unwrap().request
+    }
+
+    #[test]
+    fn typed_requests_round_trip() {
+        let requests = [
+            Request::Move { from: 2, to: 0 },
+            Request::Pause,
+            Request::Shuffle {
+                on: true,
+                seed: Some(7),
+            },
+            Request::Repeat {
+                mode: RepeatMode::All,
+            },
+            Request::PlaylistAdd {
+                name: "mix".into(),
+                uri: None,
+            },
+        ];
+        for request in requests {
+            let line = serde_json::to_string(&Envelope {
+                v: PROTOCOL_VERSION,
+                id: Some(3),
+                token: None,
+                request: request.clone(),
+            })
+            .unwrap();
+            let incoming = parse_request(&line).unwrap();
+            assert_eq!(incoming.id, Some(3));
+            assert_eq!(incoming.request, Ok(request), "{}", line);
+        }
+        assert_eq!(
+            parse(r#"{"v":1,"cmd":"seek_forward","params":{}}"#),
+            Ok(Request::SeekForward { seconds: 10 })
+        );
+    }
+
+    #[test]
+    fn legacy_lines_are_translated() {
+        assert_eq!(
+            parse(r#"{"cmd":"move","arg":"2 0","token":null}"#),
+            Ok(Request::Move { from: 2, to: 0 })
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"playlist_add","arg":"mix https://x/y.mp3"}"#),
+            Ok(Request::PlaylistAdd {
+                name: "mix".into(),
+                uri: Some("https://x/y.mp3".into())
+            })
+        );
+        assert_eq!(
+            parse(r#"{"cmd":"shuffle","arg":"on 42"}"#),
+            Ok(Request::Shuffle {
+                on: true,
+                seed: Some(42)
+            })
+        );
+        assert_eq!(parse(r#"{"cmd":"list"}"#), Ok(Request::List));
+        // every command is reachable through the old format
+        for cmd in COMMANDS {
+            let r = Request::from_legacy(cmd, Some("1 2"));
+            assert!(
+                !matches!(&r, Err(f) if f.code == ErrorCode::UnknownCommand),
+                "{}",
+                cmd
+            );
+        }
+    }
+
+    #[test]
+    fn failures_carry_codes() {
+        let code = |line: &str| parse(line).unwrap_err().code;
+        assert_eq!(code(r#"{"cmd":"dance"}"#), ErrorCode::UnknownCommand);
+        assert_eq!(
+            code(r#"{"cmd":"remove","arg":"x"}"#),
+            ErrorCode::InvalidParams
+        );
+        assert_eq!(
+            code(r#"{"v":1,"cmd":"remove","params":{"index":"x"}}"#),
+            ErrorCode::InvalidParams
+        );
+        assert_eq!(
+            code(r#"{"v":9,"cmd":"status"}"#),
+            ErrorCode::UnsupportedVersion
+        );
+        assert_eq!(
+            parse_request("not json").unwrap_err().code,
+            ErrorCode::ParseError
+        );
+
+        let resp = Response::from(Failure::new(ErrorCode::NotFound, "gone"));
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(json["code"], "not_found");
+        assert_eq!(json["ok"], false);
+        // older clients expect `items` to be present
+        assert!(json.as_object().unwrap().contains_key("items"));
+    }
+}
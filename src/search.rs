@@ -0,0 +1,302 @@
+// Offline search over the library index.
+//
+// A query is a list of free words plus `field:value` filters, e.g.
+//   radiohead creep            words must all match title, artist, album or file name
+//   artist:radiohead year:>2000
+//   album:"ok computer" year:1995..1999
+// Words match exactly, by prefix, as a substring or with a small typo; matching ignores case
+// and diacritics. Filters narrow the result set without affecting the ranking.
+
+use crate::library::{Library, LibraryEntry};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    Title(String),
+    Artist(String),
+    Album(String),
+    Genre(String),
+    /// Inclusive year range
+    Year(u32, u32),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Query {
+    words: Vec<String>,
+    filters: Vec<Filter>,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> Self {
+        let mut q = Query::default();
+        for token in tokenize(input) {
+            match token.split_once(':').and_then(|(k, v)| parse_filter(k, v)) {
+                Some(f) => q.filters.push(f),
+                None => q.words.extend(words_of(&token)),
+            }
+        }
+        q
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.filters.is_empty()
+    }
+
+    fn accepts(&self, e: &LibraryEntry) -> bool {
+        let has = |field: Option<&str>, v: &str| field.is_some_and(|f| normalize(f).contains(v));
+        self.filters.iter().all(|f| match f {
+            Filter::Title(v) => has(e.track.title.as_deref(), v),
+            Filter::Artist(v) => {
+                has(e.track.artist.as_deref(), v) || has(e.album_artist.as_deref(), v)
+            }
+            Filter::Album(v) => has(e.track.album.as_deref(), v),
+            Filter::Genre(v) => has(e.genre.as_deref(), v),
+            Filter::Year(lo, hi) => e.year.is_some_and(|y| (*lo..=*hi).contains(&y)),
+        })
+    }
+
+    // Sum of the best score of every word, or None if some word matches nothing.
+    fn score(&self, e: &LibraryEntry) -> Option<f32> {
+        let file_name = e.path().file_stem().and_then(|n| n.to_str());
+        let fields: Vec<(Vec<String>, f32)> = [
+            (e.track.title.as_deref(), 3.0),
+            (e.track.artist.as_deref(), 2.0),
+            (e.album_artist.as_deref(), 2.0),
+            (e.track.album.as_deref(), 1.5),
+            (file_name, 0.5),
+        ]
+        .into_iter()
+        .filter_map(|(f, weight)| Some((words_of(f?), weight)))
+        .collect();
+        let mut total = 0.0;
+        for w in &self.words {
+            let best = fields
+                .iter()
+                .flat_map(|(words, weight)| words.iter().map(move |fw| word_score(w, fw) * weight))
+                .fold(0.0, f32::max);
+            if best == 0.0 {
+                return None;
+            }
+            total += best;
+        }
+        Some(total)
+    }
+}
+
+/// Entries matching `query`, best first; ties keep index order.
+pub fn rank<'a>(lib: &'a Library, query: &Query) -> Vec<&'a LibraryEntry> {
+    let mut hits: Vec<(f32, usize)> = lib
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| query.accepts(e))
+        .filter_map(|(i, e)| query.score(e).map(|s| (s, i)))
+        .collect();
+    hits.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+    hits.into_iter().map(|(_, i)| &lib.entries[i]).collect()
+}
+
+/// Lowercase and strip diacritics ("Björk" -> "bjork", "Æther" -> "aether").
+pub fn normalize(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.nfkd() {
+        if is_combining_mark(c) {
+            continue;
+        }
+        // letters that do not decompose into base + mark
+        match c {
+            'ß' => out.push_str("ss"),
+            'æ' | 'Æ' => out.push_str("ae"),
+            'œ' | 'Œ' => out.push_str("oe"),
+            'ø' | 'Ø' => out.push('o'),
+            'đ' | 'Đ' | 'ð' | 'Ð' => out.push('d'),
+            'ł' | 'Ł' => out.push('l'),
+            'þ' | 'Þ' => out.push_str("th"),
+            _ => out.extend(c.to_lowercase()),
+        }
+    }
+    out
+}
+
+fn words_of(s: &str) -> Vec<String> {
+    normalize(s)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn word_score(query: &str, word: &str) -> f32 {
+    if query == word {
+        1.0
+    } else if word.starts_with(query) {
+        0.8
+    } else if query.chars().count() >= 3 && word.contains(query) {
+        0.5
+    } else {
+        // allow one typo in short words, two in long ones
+        let n = query.chars().count();
+        let max = match n {
+            0..=3 => return 0.0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        match edit_distance(query, word, max) {
+            Some(d) => 0.4 - 0.1 * d as f32,
+            None => 0.0,
+        }
+    }
+}
+
+// Levenshtein distance if it is at most `max`.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        if cur.iter().min().is_some_and(|m| *m > max) {
+            return None;
+        }
+        prev = cur;
+    }
+    Some(prev[b.len()]).filter(|d| *d <= max)
+}
+
+// Whitespace-separated tokens; double quotes group words (`album:"ok computer"`).
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !cur.is_empty() {
+                    tokens.push(std::mem::take(&mut cur));
+                }
+            }
+            c => cur.push(c),
+        }
+    }
+    if !cur.is_empty() {
+        tokens.push(cur);
+    }
+    tokens
+}
+
+fn parse_filter(key: &str, value: &str) -> Option<Filter> {
+    let text = || Some(normalize(value.trim())).filter(|v| !v.is_empty());
+    match key.to_ascii_lowercase().as_str() {
+        "title" | "track" => text().map(Filter::Title),
+        "artist" | "by" => text().map(Filter::Artist),
+        "album" => text().map(Filter::Album),
+        "genre" => text().map(Filter::Genre),
+        "year" => parse_years(value.trim()),
+        _ => None,
+    }
+}
+
+// 1999, >2000, >=2000, <1990, <=1990, 1990..1999
+fn parse_years(v: &str) -> Option<Filter> {
+    let num = |s: &str| s.trim().parse::<u32>().ok();
+    let (lo, hi) = if let Some((a, b)) = v.split_once("..") {
+        (num(a)?, num(b)?)
+    } else if let Some(n) = v.strip_prefix(">=") {
+        (num(n)?, u32::MAX)
+    } else if let Some(n) = v.strip_prefix('>') {
+        (num(n)?.checked_add(1)?, u32::MAX)
+    } else if let Some(n) = v.strip_prefix("<=") {
+        (0, num(n)?)
+    } else if let Some(n) = v.strip_prefix('<') {
+        (0, num(n)?.checked_sub(1)?)
+    } else {
+        let y = num(v)?;
+        (y, y)
+    };
+    Some(Filter::Year(lo, hi))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::Track;
+
+    fn entry(uri: &str, title: &str, artist: &str, album: &str, year: u32) -> LibraryEntry {
+        let mut track = Track::from_uri(uri, "library");
+        track.title = Some(title.into());
+        track.artist = Some(artist.into());
+        track.album = Some(album.into());
+        LibraryEntry {
+            track,
+            album_artist: None,
+            track_number: None,
+            disc_number: None,
+            year: Some(year),
+            genre: None,
+            mtime: 0,
+            size: 0,
+        }
+    }
+
+    fn library() -> Library {
+        Library {
+            roots: Vec::new(),
+            entries: vec![
+                entry("/m/1.flac", "Creep", "Radiohead", "Pablo Honey", 1993),
+                entry("/m/2.flac", "Reckoner", "Radiohead", "In Rainbows", 2007),
+                entry("/m/3.flac", "Jóga", "Björk", "Homogenic", 1997),
+                entry("/m/4.flac", "Radio Ga Ga", "Queen", "The Works", 1984),
+            ],
+        }
+    }
+
+    fn titles(lib: &Library, q: &str) -> Vec<String> {
+        rank(lib, &Query::parse(q))
+            .iter()
+            .map(|e| e.track.title.clone().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matching_ignores_case_and_diacritics() {
+        let lib = library();
+        assert_eq!(titles(&lib, "bjork joga"), vec!["Jóga"]);
+        assert_eq!(titles(&lib, "BJÖRK"), vec!["Jóga"]);
+        assert_eq!(normalize("Æther Straße"), "aether strasse");
+    }
+
+    #[test]
+    fn exact_title_ranks_above_prefix_and_typos_still_match() {
+        let lib = library();
+        // "radio" is a whole title word for Queen, only a prefix of "radiohead"
+        assert_eq!(
+            titles(&lib, "radio"),
+            vec!["Radio Ga Ga", "Creep", "Reckoner"]
+        );
+        assert_eq!(titles(&lib, "radiohaed creep"), vec!["Creep"]);
+        assert!(titles(&lib, "nothing").is_empty());
+    }
+
+    #[test]
+    fn field_filters_narrow_results() {
+        let lib = library();
+        assert_eq!(
+            titles(&lib, "artist:radiohead year:>2000"),
+            vec!["Reckoner"]
+        );
+        assert_eq!(titles(&lib, "year:1990..1999"), vec!["Creep", "Jóga"]);
+        assert_eq!(titles(&lib, r#"album:"in rainbows""#), vec!["Reckoner"]);
+        assert_eq!(titles(&lib, "year:<=1984"), vec!["Radio Ga Ga"]);
+        // unknown fields are plain words
+        assert!(titles(&lib, "mood:happy").is_empty());
+    }
+}
@@ -0,0 +1,87 @@
+// Playback state persisted by the daemon so the queue survives restarts.
+// Stored as JSON next to the config file (see `config::config_path`).
+
+use crate::player::RepeatMode;
+use crate::track::Track;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PlayerState {
+    #[serde(default)]
+    pub queue: Vec<Track>,
+    /// Oldest first
+    #[serde(default)]
+    pub history: Vec<Track>,
+    #[serde(default)]
+    pub current: Option<Track>,
+    /// Seconds into `current` when the snapshot was taken
+    #[serde(default)]
+    pub position: Option<u64>,
+    #[serde(default)]
+    pub volume: Option<u8>,
+    #[serde(default)]
+    pub repeat: RepeatMode,
+    #[serde(default)]
+    pub shuffle: bool,
+    /// Shuffle generator state, so the random order carries on where it left off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shuffle_seed: Option<u64>,
+}
+
+pub fn state_path() -> PathBuf {
+    crate::config::default_config_dir().join("state.json")
+}
+
+/// Returns None if there is no state file or it cannot be parsed.
+pub fn load_state(path: &Path) -> Option<PlayerState> {
+    let s = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&s) {
+        Ok(st) => Some(st),
+        Err(e) => {
+            eprintln!("ignoring unreadable state file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn save_state(path: &Path, state: &PlayerState) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("creating state dir")?;
+    }
+    let s = serde_json::to_string_pretty(state).context("serialize state")?;
+    // write to a temp file first so a crash mid-write never leaves a truncated state file
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, s).context("write state")?;
+    fs::rename(&tmp, path).context("replace state file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("apple-state-test-{}", std::process::id()));
+        let path = dir.join("state.json");
+        assert!(load_state(&path).is_none());
+
+        let st = PlayerState {
+            queue: vec![Track::from_uri("a.mp3", "mpv")],
+            current: Some(Track::from_uri("b.mp3", "mpv")),
+            position: Some(42),
+            volume: Some(70),
+            repeat: RepeatMode::All,
+            ..PlayerState::default()
+        };
+        save_state(&path, &st).unwrap();
+        assert_eq!(load_state(&path), Some(st));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(load_state(&path).is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
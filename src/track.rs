@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A playable item with whatever metadata we know about it.
+/// Only `id`, `uri` and `source` are always present; the rest is filled in when an adapter,
+/// playlist or tag reader provides it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    /// Opaque identifier, stable for a given source + uri
+    pub id: String,
+    /// What gets handed to the adapter's `play` (path, URL, catalog id, ...)
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    /// Length in seconds, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    /// Name of the adapter the track belongs to (see `PlaybackAdapter::name`)
+    pub source: String,
+}
+
+impl Track {
+    pub fn from_uri(uri: &str, source: &str) -> Self {
+        Self {
+            id: track_id(source, uri),
+            uri: uri.to_string(),
+            title: None,
+            artist: None,
+            album: None,
+            duration: None,
+            source: source.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Track {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => write!(f, "{} - {}", artist, title)?,
+            (None, Some(title)) => write!(f, "{}", title)?,
+            _ => write!(f, "{}", self.uri)?,
+        }
+        if let Some(album) = &self.album {
+            write!(f, " [{}]", album)?;
+        }
+        Ok(())
+    }
+}
+
+// FNV-1a over source + uri; unlike std's DefaultHasher the result is stable across builds,
+// so ids can be stored and handed to clients.
+fn track_id(source: &str, uri: &str) -> String {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in source.bytes().chain([0u8]).chain(uri.bytes()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_stable_and_source_scoped() {
+        let a = Track::from_uri("song.mp3", "mpv");
+        assert_eq!(a.id, Track::from_uri("song.mp3", "mpv").id);
+        assert_ne!(a.id, Track::from_uri("song.mp3", "system").id);
+    }
+
+    #[test]
+    fn display_prefers_metadata() {
+        let mut t = Track::from_uri("https://example.com/a.mp3", "mpv");
+        assert_eq!(t.to_string(), "https://example.com/a.mp3");
+        t.title = Some("Song".into());
+        t.artist = Some("Band".into());
+        t.album = Some("Record".into());
+        assert_eq!(t.to_string(), "Band - Song [Record]");
+    }
+}
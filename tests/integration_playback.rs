@@ -153,8 +153,13 @@ async fn plays_silence_via_mpv() {
         .await
         .expect("mpv failed to play url");
 
+    // Property reads go through the same IPC connection and return real values
+    sleep(Duration::from_millis(300)).await;
+    let volume = adapter.get_volume().await.expect("failed to read volume");
+    assert!(volume <= 100, "volume out of range: {}", volume);
+
     // Let it play for a short while then pause
-    sleep(Duration::from_millis(1200)).await;
+    sleep(Duration::from_millis(900)).await;
 
     adapter.pause().await.expect("failed to pause");
 